@@ -1,30 +1,99 @@
 // src/cli/commands.rs
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use ipnetwork::{Ipv4Network, Ipv6Network};
+use log::LevelFilter;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
 use std::{env::current_dir, path::PathBuf};
 
+use crate::network::etc_files::ExtraHost;
+use crate::network::ports::PublishSpec;
+use crate::network::proxy::ProxyMode;
+use crate::network::tc::parse_rate;
+use crate::network::{NetworkDriver, NetworkMode};
+use crate::runtime::container::{ContainerFilter, VolumeMount};
+use crate::runtime::health::parse_duration;
+use crate::runtime::logs::LogDriverType;
+use crate::runtime::namespace::NamespaceMode;
+use crate::runtime::restart::RestartPolicy;
+use crate::runtime::signal::{DEFAULT_STOP_TIMEOUT, parse_signal};
+use crate::runtime::supervisor::WaitCondition;
+use crate::runtime::tty::{DEFAULT_DETACH_KEYS, DetachKeys};
+use crate::util::parse_size;
+use nix::sys::signal::Signal;
+
 // use crate::runtime::container::VolumeMount;
 fn get_current_dir() -> String {
-    let current_dir = current_dir().unwrap();
+    // Commands run from / (like the supervisor's health checks) have no
+    // directory name to default to.
+    let Ok(current_dir) = current_dir() else {
+        return String::new();
+    };
     let dir_name_os_str = current_dir.file_name();
-    let dir_name = dir_name_os_str
+    dir_name_os_str
         .and_then(|name| name.to_str())
         .map(|s| s.to_owned())
-        .unwrap();
-    return dir_name;
+        .unwrap_or_default()
 }
 
 #[derive(Parser)]
 #[command(name = "Nebulon")]
 #[command(about = "Nebulon Container Runtime", long_about = None)]
 pub struct Cli {
+    /// Runtime log level: off, error, warn, info, debug or trace. Defaults to
+    /// $NEBULON_LOG, or info
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<LevelFilter>,
+
+    /// Runtime log file, /var/lib/nebulon/logs/nb.log by default
+    #[arg(long, global = true, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
+    /// Also write runtime logs to stderr
+    #[arg(long, global = true)]
+    pub log_stderr: bool,
+
+    /// Forward published ports with a proxy in the container's supervisor:
+    /// auto (where NAT rules cannot be set up), always or never. Defaults to
+    /// $NEBULON_USERLAND_PROXY, or auto
+    #[arg(long, global = true, value_name = "MODE")]
+    pub userland_proxy: Option<ProxyMode>,
+
     #[command(subcommand)]
     pub command: Commands,
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
 pub enum Commands {
-    Run {
+    /// Start a created or stopped container
+    Start {
         container_id: String,
+
+        /// Allocate a pseudo-TTY for the container
+        #[arg(short, long)]
+        tty: bool,
+
+        /// Keep STDIN open and forward it to the container
+        #[arg(short, long)]
+        interactive: bool,
+
+        /// Key sequence for detaching from the container
+        #[arg(long, value_name = "KEYS")]
+        detach_keys: Option<DetachKeys>,
+    },
+
+    /// Attach the local terminal to a running container's TTY
+    Attach {
+        container_id: String,
+
+        /// Do not forward STDIN to the container
+        #[arg(long)]
+        no_stdin: bool,
+
+        /// Key sequence for detaching from the container
+        #[arg(long, value_name = "KEYS")]
+        detach_keys: Option<DetachKeys>,
     },
 
     Create {
@@ -38,34 +107,195 @@ pub enum Commands {
         #[arg(short, long, default_value = "/")]
         workdir: PathBuf,
 
-        #[arg(short, long)]
-        env: Vec<String>,
-
-        #[arg(long, default_value = "orca-container")]
-        hostname: String,
-
         #[arg(short, long, default_value = "0")]
         uid: u32,
 
         #[arg(short, long, default_value = "0")]
         gid: u32,
 
-        #[arg(short, long, default_value = "[]")]
-        volumes: Vec<String>,
-
         #[arg(short, long, default_value = "")]
         storage_driver: String,
+
+        #[command(flatten)]
+        options: CreateOptions,
+    },
+
+    /// Create a container from an image, start it and attach to it
+    Run {
+        /// Image as NAME[:TAG], the tag defaults to latest
+        image: String,
+
+        /// Command to run instead of the image's default command
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+
+        /// Remove the container once it exits
+        #[arg(long)]
+        rm: bool,
+
+        /// Run the container in the background and print its id
+        #[arg(short, long)]
+        detach: bool,
+
+        /// Overwrite the image's entrypoint
+        #[arg(long, value_name = "CMD")]
+        entrypoint: Option<String>,
+
+        /// Working directory inside the container, the image's by default
+        #[arg(short, long)]
+        workdir: Option<PathBuf>,
+
+        /// User to run as, user[:group] by name or id, the image's user by default
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Storage driver for the container's rootfs: overlayfs or aufs
+        #[arg(short, long, default_value = "overlayfs")]
+        storage_driver: String,
+
+        #[command(flatten)]
+        options: CreateOptions,
     },
 
     Ps,
 
+    /// List the published ports of a container
+    Port {
+        container_id: String,
+
+        /// Only show the mapping of PORT[/tcp|udp]
+        port: Option<String>,
+    },
+
+    /// Give a container a new name
+    Rename {
+        container_id: String,
+
+        name: String,
+    },
+
+    /// Show the stored state of a container as JSON
+    Inspect {
+        container_id: String,
+    },
+
+    /// Show the output of a container
+    Logs {
+        container_id: String,
+
+        /// Keep printing output as the container writes it
+        #[arg(short, long)]
+        follow: bool,
+
+        /// Only show the last N lines
+        #[arg(long, value_name = "N")]
+        tail: Option<usize>,
+
+        /// Only show output since a timestamp or a relative time such as 10m
+        #[arg(long, value_name = "TIME")]
+        since: Option<String>,
+
+        /// Only show output until a timestamp or a relative time such as 10m
+        #[arg(long, value_name = "TIME")]
+        until: Option<String>,
+
+        /// Prefix every line with its timestamp
+        #[arg(short, long)]
+        timestamps: bool,
+    },
+
+    /// Run a command inside a running container
+    Exec {
+        container_id: String,
+
+        /// Keep STDIN open
+        #[arg(short, long)]
+        interactive: bool,
+
+        /// Allocate a pseudo-TTY
+        #[arg(short, long)]
+        tty: bool,
+
+        /// User to run the command as, user[:group] by name or id
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Environment variables to set, as KEY=VALUE
+        #[arg(short, long)]
+        env: Vec<String>,
+
+        /// Working directory inside the container
+        #[arg(short, long)]
+        workdir: Option<PathBuf>,
+
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+
     Stop {
         #[arg(short, long, default_value_t= get_current_dir())]
         container_id: String,
+
+        /// Seconds to wait for the container to exit before killing it
+        #[arg(short, long, value_name = "SECONDS", default_value_t = DEFAULT_STOP_TIMEOUT)]
+        time: u64,
+    },
+
+    /// Block until containers stop and print their exit codes
+    Wait {
+        #[arg(required = true)]
+        container_ids: Vec<String>,
+
+        /// What to wait for: not-running, next-exit or removed
+        #[arg(long, value_name = "CONDITION", default_value = "not-running")]
+        condition: WaitCondition,
+    },
+
+    /// Remove containers
+    Rm {
+        #[arg(required = true)]
+        container_ids: Vec<String>,
+
+        /// Kill and remove running containers
+        #[arg(short, long)]
+        force: bool,
+
+        /// Also remove the anonymous volumes of the containers
+        #[arg(short, long)]
+        volumes: bool,
+    },
+
+    Container {
+        #[command(subcommand)]
+        command: ContainerCommands,
+    },
+
+    /// Send a signal to a running container
+    Kill {
+        container_id: String,
+
+        /// Signal to send, by name or number
+        #[arg(short, long, default_value = "SIGKILL", value_parser = parse_signal)]
+        signal: Signal,
     },
 
     Init,
 
+    /// Set up the workload of a container, run by its supervisor
+    #[command(hide = true)]
+    InitWorkload {
+        /// Descriptors of the pipes to the supervisor
+        #[arg(long)]
+        spec: i32,
+        #[arg(long)]
+        pid: i32,
+        #[arg(long)]
+        resume: i32,
+        /// Socket the console of a container with a TTY is sent over
+        #[arg(long)]
+        console: Option<i32>,
+    },
+
     Volume {
         #[command(subcommand)]
         command: VolumeCommands,
@@ -75,6 +305,177 @@ pub enum Commands {
         #[command(subcommand)]
         command: ImageCommands,
     },
+
+    Network {
+        #[command(subcommand)]
+        command: NetworkCommands,
+    },
+}
+
+/// Options shared by `nb create` and `nb run`.
+#[derive(Args)]
+pub struct CreateOptions {
+    /// Name to refer to the container by
+    #[arg(long)]
+    pub name: Option<String>,
+
+    #[arg(short, long)]
+    pub env: Vec<String>,
+
+    #[arg(long, default_value = "orca-container")]
+    pub hostname: String,
+
+    /// Mount a volume: /host/path:/target, name:/target or /target, with :ro
+    /// for read only
+    #[arg(short, long, alias = "volume", value_name = "[SRC:]DST[:ro]")]
+    pub volumes: Vec<VolumeMount>,
+
+    #[arg(long = "sysctl", value_name = "KEY=VALUE")]
+    pub sysctls: Vec<String>,
+
+    /// UTS namespace: private, host, container:<id> or a namespace path
+    #[arg(long, value_name = "MODE")]
+    pub uts: Option<NamespaceMode>,
+
+    /// IPC namespace: private, host, container:<id> or a namespace path
+    #[arg(long, value_name = "MODE")]
+    pub ipc: Option<NamespaceMode>,
+
+    /// PID namespace: private, host, container:<id> or a namespace path
+    #[arg(long, value_name = "MODE")]
+    pub pid: Option<NamespaceMode>,
+
+    /// Network to connect to as NAME[:IP], repeat for several networks, a
+    /// network namespace: private, host, container:<id> or a namespace path,
+    /// or a rootless network stack: pasta, slirp4netns or rootless
+    #[arg(long, alias = "net", value_name = "NETWORK")]
+    pub network: Vec<NetworkMode>,
+
+    /// Name the container is also found by on the networks given with
+    /// --network
+    #[arg(long = "network-alias", value_name = "ALIAS")]
+    pub network_aliases: Vec<String>,
+
+    /// Publish a container port as [HOSTIP:]HOSTPORT:PORT[/tcp|udp], ports
+    /// may be ranges like 8000-8009:9000-9009 and IPv6 host addresses are
+    /// bracketed like [::1]:8080:80
+    #[arg(short, long, value_name = "MAPPING")]
+    pub publish: Vec<PublishSpec>,
+
+    /// Limit traffic to the container to RATE on its bridge network, like
+    /// 10mbit or 1gbit, or 1mbps in bytes per second
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    pub network_ingress_rate: Option<u64>,
+
+    /// Traffic to the container that may pass at once above the ingress
+    /// rate, like 256k, 100ms worth of it by default
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "network_ingress_rate")]
+    pub network_ingress_burst: Option<u64>,
+
+    /// Limit traffic from the container to RATE on its bridge network,
+    /// traffic above it is dropped
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    pub network_egress_rate: Option<u64>,
+
+    /// Traffic from the container that may pass at once above the egress
+    /// rate, 100ms worth of it by default
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "network_egress_rate")]
+    pub network_egress_burst: Option<u64>,
+
+    /// DNS server for the container's resolv.conf, replacing the host's
+    #[arg(long, value_name = "IP")]
+    pub dns: Vec<IpAddr>,
+
+    /// DNS search domain for the container's resolv.conf
+    #[arg(long, value_name = "DOMAIN")]
+    pub dns_search: Vec<String>,
+
+    /// Option for the container's resolv.conf, e.g. ndots:2
+    #[arg(long = "dns-option", value_name = "OPTION")]
+    pub dns_options: Vec<String>,
+
+    /// Add a HOST:IP entry to the container's /etc/hosts
+    #[arg(long = "add-host", value_name = "HOST:IP")]
+    pub add_hosts: Vec<ExtraHost>,
+
+    /// User namespace: private, host, container:<id> or a namespace path
+    #[arg(long, value_name = "MODE")]
+    pub userns: Option<NamespaceMode>,
+
+    /// Cgroup namespace: private, host, container:<id> or a namespace path
+    #[arg(long, value_name = "MODE")]
+    pub cgroupns: Option<NamespaceMode>,
+
+    /// Time namespace: private, host, container:<id> or a namespace path
+    #[arg(long, value_name = "MODE")]
+    pub timens: Option<NamespaceMode>,
+
+    /// Allocate a pseudo-TTY for the container
+    #[arg(short, long)]
+    pub tty: bool,
+
+    /// Keep STDIN open and forward it to the container
+    #[arg(short, long)]
+    pub interactive: bool,
+
+    /// Key sequence for detaching from the container
+    #[arg(long, value_name = "KEYS", default_value = DEFAULT_DETACH_KEYS)]
+    pub detach_keys: DetachKeys,
+
+    /// Where container output goes: json-file, local, syslog or none
+    #[arg(long, value_name = "DRIVER", default_value = "json-file")]
+    pub log_driver: LogDriverType,
+
+    /// Log driver option, e.g. max-size=10m, max-file=3 or compress=true
+    #[arg(long = "log-opt", value_name = "KEY=VALUE")]
+    pub log_opts: Vec<String>,
+
+    /// Restart policy: no, on-failure[:max-retries], always or unless-stopped
+    #[arg(long = "restart", value_name = "POLICY", default_value = "no")]
+    pub restart_policy: RestartPolicy,
+
+    /// Command run with /bin/sh -c inside the container to check its health
+    #[arg(long, value_name = "CMD")]
+    pub health_cmd: Option<String>,
+
+    /// Time between health checks
+    #[arg(long, value_name = "DURATION", default_value = "30s", value_parser = parse_duration)]
+    pub health_interval: Duration,
+
+    /// Time after which a health check counts as failed
+    #[arg(long, value_name = "DURATION", default_value = "30s", value_parser = parse_duration)]
+    pub health_timeout: Duration,
+
+    /// Time after a start during which failed health checks are not counted
+    #[arg(long, value_name = "DURATION", default_value = "0s", value_parser = parse_duration)]
+    pub health_start_period: Duration,
+
+    /// Consecutive failed health checks after which the container is unhealthy
+    #[arg(long, value_name = "N", default_value = "3")]
+    pub health_retries: u32,
+
+    /// Restart the container when it becomes unhealthy
+    #[arg(long)]
+    pub health_restart: bool,
+
+    /// Signal sent by nb stop, SIGTERM by default
+    #[arg(long, value_name = "SIGNAL")]
+    pub stop_signal: Option<String>,
+
+    /// Metadata label, as KEY=VALUE
+    #[arg(short, long = "label", value_name = "KEY=VALUE")]
+    pub labels: Vec<String>,
+}
+
+#[derive(Subcommand)]
+pub enum ContainerCommands {
+    /// Remove all containers that are not running
+    Prune {
+        /// Only remove containers created before until=<time>, or with
+        /// label=<key>[=<value>]
+        #[arg(long = "filter", value_name = "FILTER")]
+        filters: Vec<ContainerFilter>,
+    },
 }
 
 #[derive(Subcommand)]
@@ -96,11 +497,99 @@ pub enum ImageCommands {
     Import {
         path: PathBuf,
         name: String,
+        #[arg(default_value = "latest")]
         tag: String,
     },
     Ls,
     Rm {
         name: String,
+        #[arg(default_value = "latest")]
         tag: String,
     },
 }
+
+#[derive(Subcommand)]
+pub enum NetworkCommands {
+    /// Create a network
+    Create {
+        name: String,
+
+        /// Network driver: bridge, macvlan, ipvlan or none
+        #[arg(short, long, default_value = "bridge")]
+        driver: NetworkDriver,
+
+        /// Subnet in CIDR notation, a free /24 of 10.89.0.0/16 for bridges
+        /// by default
+        #[arg(long)]
+        subnet: Option<Ipv4Network>,
+
+        /// Gateway address, the first address of the subnet by default
+        #[arg(long)]
+        gateway: Option<Ipv4Addr>,
+
+        /// Part of the subnet to allocate container addresses from
+        #[arg(long, value_name = "CIDR")]
+        ip_range: Option<Ipv4Network>,
+
+        /// Make the network dual-stack, with a free /64 of fd89::/48 for
+        /// bridges unless --subnet6 is given
+        #[arg(long)]
+        ipv6: bool,
+
+        /// IPv6 subnet in CIDR notation, implies --ipv6
+        #[arg(long, value_name = "CIDR")]
+        subnet6: Option<Ipv6Network>,
+
+        /// IPv6 gateway address, the first address of the IPv6 subnet by
+        /// default
+        #[arg(long)]
+        gateway6: Option<Ipv6Addr>,
+
+        /// Do not route containers beyond the network
+        #[arg(long)]
+        internal: bool,
+
+        /// Metadata label, as KEY=VALUE
+        #[arg(short, long = "label", value_name = "KEY=VALUE")]
+        labels: Vec<String>,
+
+        /// Driver option, as KEY=VALUE: parent=<if> for macvlan and ipvlan,
+        /// macvlan_mode=bridge|private|vepa, ipvlan_mode=l2|l3
+        #[arg(short, long = "opt", value_name = "KEY=VALUE")]
+        options: Vec<String>,
+    },
+    Ls,
+    /// Show a network as JSON
+    Inspect {
+        network: String,
+    },
+    /// Remove networks no container is connected to
+    Rm {
+        #[arg(required = true)]
+        networks: Vec<String>,
+    },
+    /// Connect a container to a network
+    Connect {
+        network: String,
+
+        container_id: String,
+
+        /// Static address on the network
+        #[arg(long)]
+        ip: Option<Ipv4Addr>,
+
+        /// Static IPv6 address on a dual-stack network
+        #[arg(long)]
+        ip6: Option<Ipv6Addr>,
+
+        /// Name the container is also found by on the network
+        #[arg(long = "alias", value_name = "ALIAS")]
+        aliases: Vec<String>,
+    },
+    /// Disconnect a container from a network
+    Disconnect {
+        network: String,
+
+        container_id: String,
+    },
+}
@@ -1,3 +1,3 @@
 // src/cli/mod.rs
 
-pub mod commands;
\ No newline at end of file
+pub mod commands;
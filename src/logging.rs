@@ -0,0 +1,87 @@
+// src/logging.rs
+use anyhow::{Result, anyhow};
+use log::LevelFilter;
+use log4rs::Config;
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::config::{Appender, Root};
+use log4rs::encode::json::JsonEncoder;
+use log4rs::encode::pattern::PatternEncoder;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+
+use crate::PROGRAM_ROOT;
+
+/// Environment variable holding the default log level.
+pub const LOG_LEVEL_ENV: &str = "NEBULON_LOG";
+
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+const MAX_LOG_FILES: u32 = 5;
+
+pub fn default_log_file() -> PathBuf {
+    PROGRAM_ROOT.join("logs").join("nb.log")
+}
+
+/// Level given with `--log-level`, otherwise `NEBULON_LOG`, otherwise info.
+pub fn log_level(flag: Option<LevelFilter>) -> LevelFilter {
+    if let Some(level) = flag {
+        return level;
+    }
+
+    match std::env::var(LOG_LEVEL_ENV) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            eprintln!("Ignoring invalid {}={:?}", LOG_LEVEL_ENV, value);
+            LevelFilter::Info
+        }),
+        Err(_) => LevelFilter::Info,
+    }
+}
+
+/// Writes runtime logs as JSON lines to `log_file`, rolled over every
+/// 10 MB keeping 5 old files, and to stderr as plain text if requested.
+pub fn setup_logging(log_file: &Path, level: LevelFilter, stderr: bool) -> Result<()> {
+    if let Some(dir) = log_file.parent() {
+        create_dir_all(dir)
+            .map_err(|e| anyhow!("Failed to create log directory {:?}: {}", dir, e))?;
+    }
+
+    let pattern = format!("{}.{{}}", log_file.display());
+    let roller = FixedWindowRoller::builder()
+        .build(&pattern, MAX_LOG_FILES)
+        .map_err(|e| anyhow!("Failed to set up log rotation: {}", e))?;
+    let policy = CompoundPolicy::new(Box::new(SizeTrigger::new(MAX_LOG_SIZE)), Box::new(roller));
+    let logfile = RollingFileAppender::builder()
+        .encoder(Box::new(JsonEncoder::new()))
+        .build(log_file, Box::new(policy))
+        .map_err(|e| anyhow!("Failed to open log file {:?}: {}", log_file, e))?;
+
+    let mut config =
+        Config::builder().appender(Appender::builder().build("logfile", Box::new(logfile)));
+    let mut root = Root::builder().appender("logfile");
+
+    if stderr {
+        let console = ConsoleAppender::builder()
+            .target(Target::Stderr)
+            .encoder(Box::new(PatternEncoder::new(
+                "{d(%Y-%m-%d %H:%M:%S)(utc)} {h({l})} {X(container_id)(-)}: {m}{n}",
+            )))
+            .build();
+        config = config.appender(Appender::builder().build("stderr", Box::new(console)));
+        root = root.appender("stderr");
+    }
+
+    let config = config
+        .build(root.build(level))
+        .map_err(|e| anyhow!("Invalid logging configuration: {}", e))?;
+    log4rs::init_config(config).map_err(|e| anyhow!("Failed to initialize logging: {}", e))?;
+    Ok(())
+}
+
+/// Tags log lines written by the current thread with a container id. Shows
+/// up in the `mdc` field of the JSON lines.
+pub fn set_container_id(container_id: &str) {
+    log_mdc::insert("container_id", container_id);
+}
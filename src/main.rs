@@ -1,27 +1,36 @@
 // src/main.rs
 mod cli;
+mod logging;
+mod network;
 mod runtime;
 pub mod storage;
+mod util;
 
 use std::fs::create_dir_all;
 use std::path::{Path, PathBuf};
 
-use crate::cli::commands::{Cli, Commands};
-use crate::runtime::container::ContainerConfig;
+use crate::cli::commands::{Cli, Commands, CreateOptions, NetworkCommands};
+use crate::network::ports::Protocol;
+use crate::network::tc::{BandwidthLimit, BandwidthLimits};
+use crate::network::{NetworkAttachment, NetworkConfig, NetworkMode};
+use crate::runtime::container::{Container, ContainerConfig, ContainerStatus};
+use crate::runtime::exec::{Exec, ExecConfig};
+use crate::runtime::health::HealthCheck;
+use crate::runtime::image::ImageOverrides;
+use crate::runtime::log_drivers::create_log_driver;
+use crate::runtime::logs::{LogConfig, LogOptions};
 use crate::runtime::main::Runtime;
+use crate::runtime::namespace::{NamespaceConfig, NamespaceMode};
+use crate::runtime::supervisor::{Supervisor, WaitCondition};
+use crate::runtime::sysctl::Sysctl;
+use crate::runtime::tty::DetachKeys;
+use crate::storage::StorageConfig;
+use crate::storage::images::ImageManager;
 use crate::storage::storage::{
-    add_container, get_container, get_container_ids, init,
+    add_container, find_container, get_containers, init, rename_container, update_container,
 };
-use anyhow::anyhow;
-use chrono::{DateTime, Datelike, Local};
 use clap::Parser;
-use log::{LevelFilter, info};
-use log4rs::Config;
-use log4rs::append::file::FileAppender;
-use log4rs::config::{Appender, Root};
-use log4rs::encode::pattern::PatternEncoder;
-use nix::sys::wait::{WaitPidFlag, waitpid};
-use nix::unistd::Pid;
+use log::info;
 
 #[macro_use]
 extern crate lazy_static;
@@ -29,32 +38,153 @@ extern crate lazy_static;
 lazy_static! {
     pub static ref PROGRAM_NAME: String = String::from("Nebulon").to_lowercase();
     pub static ref PROGRAM_CMD: String = String::from("nb");
-    pub static ref PROGRAM_START_TIME: DateTime<Local> = Local::now();
-    pub static ref LOG_FILE_PATH: String = format!(
-        "logs/{}_{}_{}_{}.log",
-        PROGRAM_CMD.as_str(),
-        PROGRAM_START_TIME.year(),
-        PROGRAM_START_TIME.month(),
-        PROGRAM_START_TIME.day()
-    );
     pub static ref PROGRAM_ROOT: PathBuf = PathBuf::from("/var/lib/nebulon/");
 }
 
-fn setup_logging() {
-    let file_name = LOG_FILE_PATH.as_str();
-    let logfile = FileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new(
-            "{h({d(%Y-%m-%d %H:%M:%S)(utc)} - {l}: {m}{n})}",
-        )))
-        .build(file_name)
-        .unwrap();
-
-    let config = Config::builder()
-        .appender(Appender::builder().build("logfile", Box::new(logfile)))
-        .build(Root::builder().appender("logfile").build(LevelFilter::Info))
-        .unwrap();
-
-    log4rs::init_config(config).unwrap();
+fn exit_on_error<T>(result: anyhow::Result<T>) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        log::error!("{}", err);
+        std::process::exit(1);
+    })
+}
+
+/// Returns whether the user detached from the container.
+fn attach(container: &Container, interactive: bool, detach_keys: DetachKeys) -> bool {
+    let container_id = &container.id;
+    let tty = container.config.tty;
+    let detached = exit_on_error(Supervisor::attach(
+        container_id,
+        tty,
+        interactive,
+        detach_keys,
+    ));
+    if detached {
+        eprintln!("\r\nDetached from container {}", container_id);
+    }
+    detached
+}
+
+/// Fills in the options `nb create` and `nb run` share, `base` carries the
+/// rest of the container's config.
+fn container_config(options: CreateOptions, base: ContainerConfig) -> ContainerConfig {
+    let CreateOptions {
+        name: _,
+        env,
+        hostname,
+        volumes,
+        sysctls,
+        uts,
+        ipc,
+        pid,
+        network,
+        network_aliases,
+        publish,
+        network_ingress_rate,
+        network_ingress_burst,
+        network_egress_rate,
+        network_egress_burst,
+        dns,
+        dns_search,
+        dns_options,
+        add_hosts,
+        userns,
+        cgroupns,
+        timens,
+        tty,
+        interactive,
+        detach_keys,
+        log_driver,
+        log_opts,
+        restart_policy,
+        health_cmd,
+        health_interval,
+        health_timeout,
+        health_start_period,
+        health_retries,
+        health_restart,
+        stop_signal,
+        labels,
+    } = options;
+
+    let sysctls = exit_on_error(Sysctl::parse(&sysctls));
+    let log_config = LogConfig {
+        driver: log_driver,
+        options: exit_on_error(LogConfig::parse_options(&log_opts)),
+    };
+    let health_check = health_cmd.map(|cmd| HealthCheck {
+        command: vec!["/bin/sh".to_string(), "-c".to_string(), cmd],
+        interval: health_interval,
+        timeout: health_timeout,
+        start_period: health_start_period,
+        retries: health_retries,
+        restart_on_unhealthy: health_restart,
+    });
+    // The last namespace mode given wins like for the other namespaces,
+    // networks add up.
+    let mut net = None;
+    let mut rootless_network = None;
+    let mut networks = Vec::new();
+    for mode in network {
+        match mode {
+            NetworkMode::Namespace(mode) => {
+                net = Some(mode);
+                rootless_network = None;
+            }
+            NetworkMode::Rootless(rootless) => {
+                net = Some(NamespaceMode::Private);
+                rootless_network = Some(rootless);
+            }
+            NetworkMode::Network(attachment) => networks.push(attachment),
+        }
+    }
+    if !network_aliases.is_empty() && networks.is_empty() {
+        exit_on_error::<()>(Err(anyhow::anyhow!(
+            "Network aliases need a --network to apply to"
+        )));
+    }
+    for attachment in networks.iter_mut() {
+        attachment.aliases = network_aliases.clone();
+    }
+    let bandwidth = BandwidthLimits {
+        ingress: network_ingress_rate.map(|rate| BandwidthLimit::new(rate, network_ingress_burst)),
+        egress: network_egress_rate.map(|rate| BandwidthLimit::new(rate, network_egress_burst)),
+    };
+    let defaults = NamespaceConfig::default();
+    let namespaces = NamespaceConfig {
+        uts: uts.unwrap_or(defaults.uts),
+        ipc: ipc.unwrap_or(defaults.ipc),
+        pid: pid.unwrap_or(defaults.pid),
+        net: net.unwrap_or(defaults.net),
+        user: userns.unwrap_or(defaults.user),
+        cgroup: cgroupns.unwrap_or(defaults.cgroup),
+        time: timens.unwrap_or(defaults.time),
+    };
+
+    ContainerConfig {
+        env_vars: env,
+        hostname,
+        volumes,
+        sysctls,
+        namespaces,
+        tty,
+        interactive,
+        detach_keys: detach_keys.to_string(),
+        log_config,
+        restart_policy,
+        health_check,
+        stop_signal,
+        labels: exit_on_error(ContainerConfig::parse_labels(&labels)),
+        networks,
+        ports: publish.into_iter().flat_map(|spec| spec.0).collect(),
+        rootless_network,
+        dns,
+        dns_search,
+        dns_options,
+        extra_hosts: add_hosts,
+        bandwidth,
+        ..base
+    }
 }
 
 fn main() {
@@ -62,14 +192,20 @@ fn main() {
 
     let cli = Cli::parse();
     let root = "/var/lib/nebulon/rootfs";
-    setup_logging();
+    let log_file = cli
+        .log_file
+        .clone()
+        .unwrap_or_else(logging::default_log_file);
+    if let Err(err) =
+        logging::setup_logging(&log_file, logging::log_level(cli.log_level), cli.log_stderr)
+    {
+        eprintln!("Warning: runtime logging is disabled: {}", err);
+    }
+    network::proxy::set_mode(cli.userland_proxy);
 
     let path = Path::new(root);
     match create_dir_all(path) {
-        Ok(_) => {
-            println!("created {:?}", root);
-            log::info!("created {:?}", root);
-        }
+        Ok(_) => log::debug!("created {:?}", root),
         Err(err) => println!("Error creating directory: {}\n", err),
     };
     init().unwrap();
@@ -78,61 +214,330 @@ fn main() {
         Commands::Init => {
             info!("Calling the init command");
         }
-        Commands::Run { container_id } => {
-            if get_container_ids().unwrap().contains(&container_id) {
-                let mut container = get_container(&container_id).unwrap();
-                Runtime::start_container(&mut container).unwrap();
+        Commands::InitWorkload {
+            spec,
+            pid,
+            resume,
+            console,
+        } => {
+            exit_on_error(Runtime::init_workload(spec, pid, resume, console));
+        }
+        Commands::Start {
+            container_id,
+            tty,
+            interactive,
+            detach_keys,
+        } => {
+            let mut container = exit_on_error(find_container(&container_id));
+            logging::set_container_id(&container.id);
+            container.config.tty |= tty;
+            container.config.interactive |= interactive;
+            if let Some(keys) = &detach_keys {
+                container.config.detach_keys = keys.to_string();
             }
+
+            exit_on_error(update_container(&container));
+            let attach_tty = container.config.tty;
+            exit_on_error(Runtime::start_container(&mut container, attach_tty));
+
+            if container.config.tty {
+                let keys = exit_on_error(container.config.detach_keys.parse());
+                attach(&container, container.config.interactive, keys);
+            }
+        }
+        Commands::Attach {
+            container_id,
+            no_stdin,
+            detach_keys,
+        } => {
+            let container = exit_on_error(find_container(&container_id));
+            logging::set_container_id(&container.id);
+            let keys =
+                detach_keys.unwrap_or_else(|| exit_on_error(container.config.detach_keys.parse()));
+            attach(&container, container.config.interactive && !no_stdin, keys);
         }
         Commands::Create {
             command,
             args,
             rootfs,
             workdir,
-            env,
-            hostname,
             uid,
             gid,
-            volumes,
             storage_driver,
+            options,
         } => {
-            let config = ContainerConfig {
-                command: vec![command],
-                args,
-                env_vars: env,
-                working_dirs: workdir,
-                hostname: hostname,
-                rootfs: rootfs.unwrap_or_else(|| root.into()),
-                uid,
-                gid,
-                volumes: vec![],
-                storage_driver: storage_driver,
+            let name = options.name.clone();
+            let config = container_config(
+                options,
+                ContainerConfig {
+                    command: vec![command],
+                    args,
+                    working_dirs: workdir,
+                    rootfs: rootfs.unwrap_or_else(|| root.into()),
+                    uid,
+                    gid,
+                    storage_driver,
+                    ..Default::default()
+                },
+            );
+
+            let container = exit_on_error(Runtime::create_container(name, config));
+            let container_id = container.id.clone();
+            logging::set_container_id(&container_id);
+            if let Err(e) = add_container(container.clone()) {
+                Runtime::discard_container(&container);
+                exit_on_error::<()>(Err(e));
+            }
+            println!("{}", container_id);
+        }
+        Commands::Run {
+            image,
+            command,
+            rm,
+            detach,
+            entrypoint,
+            workdir,
+            user,
+            storage_driver,
+            options,
+        } => {
+            let name = options.name.clone();
+            let config = container_config(
+                options,
+                ContainerConfig {
+                    storage_driver,
+                    auto_remove: rm,
+                    ..Default::default()
+                },
+            );
+            let overrides = ImageOverrides {
+                entrypoint,
+                command,
+                working_dir: workdir,
+                user,
             };
 
-            let container = Runtime::create_container(config).unwrap();
+            let mut container =
+                exit_on_error(Runtime::create_from_image(&image, name, config, &overrides));
+            let container_id = container.id.clone();
+            logging::set_container_id(&container_id);
+            if let Err(e) = add_container(container.clone()) {
+                Runtime::discard_container(&container);
+                exit_on_error::<()>(Err(e));
+            }
 
-            log::info!(
-                "Pid before container creation is {:?}",
-                Pid::from_raw(container.pid)
+            if let Err(err) = Runtime::start_container(&mut container, !detach) {
+                if rm && let Err(e) = Runtime::remove_container(&container, false, true) {
+                    log::warn!("Failed to remove container {}: {}", container_id, e);
+                }
+                exit_on_error(Err(err))
+            }
+            if detach {
+                println!("{}", container_id);
+                return;
+            }
+
+            // Asked for before attaching, as the container may exit as soon
+            // as its output has been passed on.
+            let waiter = exit_on_error(Supervisor::wait_in_background(
+                &container_id,
+                WaitCondition::NotRunning,
+            ));
+            let keys = exit_on_error(container.config.detach_keys.parse());
+            if attach(&container, container.config.interactive, keys) {
+                return;
+            }
+            let exit_code = exit_on_error(
+                waiter
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("Failed to wait for container"))),
             );
-            waitpid(Pid::from_raw(container.pid), Some(WaitPidFlag::empty()))
-                .map_err(|e| anyhow!("Failed to initiate container: {}", e))
-                .unwrap();
-            add_container(container).unwrap();
-            // unwrap();
+            std::process::exit(exit_code.unwrap_or(0));
         }
         Commands::Ps => {
             println!("Listing containers");
-            for (num, con) in get_container_ids().unwrap().iter().enumerate() {
-                println!("{}. {}", num + 1, con);
+            for (num, con) in exit_on_error(get_containers()).iter().enumerate() {
+                let mut line = format!("{}. {} {} {:?}", num + 1, con.id, con.name, con.status);
+                if let Some(health) = &con.health
+                    && con.status == ContainerStatus::Running
+                {
+                    line.push_str(&format!(" (health: {})", health.status));
+                }
+                if !con.ports.is_empty() {
+                    let ports: Vec<String> = con.ports.iter().map(|p| p.to_string()).collect();
+                    line.push_str(&format!(" {}", ports.join(", ")));
+                }
+                println!("{}", line);
+            }
+        }
+        Commands::Port { container_id, port } => {
+            let container = exit_on_error(find_container(&container_id));
+            logging::set_container_id(&container.id);
+            let filter = port.map(|port| {
+                let (port, protocol) = port.split_once('/').unwrap_or((&port, "tcp"));
+                (
+                    exit_on_error(
+                        port.parse::<u16>()
+                            .map_err(|e| anyhow::anyhow!("Invalid port {:?}: {}", port, e)),
+                    ),
+                    exit_on_error(protocol.parse::<Protocol>()),
+                )
+            });
+            for published in &container.ports {
+                if filter.is_none_or(|(port, protocol)| {
+                    published.container_port == port && published.protocol == protocol
+                }) {
+                    println!(
+                        "{}/{} -> {}",
+                        published.container_port,
+                        published.protocol,
+                        published.host_address()
+                    );
+                }
+            }
+        }
+        Commands::Rename { container_id, name } => {
+            let container = exit_on_error(find_container(&container_id));
+            logging::set_container_id(&container.id);
+            exit_on_error(Container::validate_name(&name));
+            exit_on_error(rename_container(&container.id, &name));
+        }
+        Commands::Inspect { container_id } => {
+            let container = exit_on_error(find_container(&container_id));
+            logging::set_container_id(&container.id);
+            println!(
+                "{}",
+                exit_on_error(serde_json::to_string_pretty(&container).map_err(Into::into))
+            );
+        }
+        Commands::Logs {
+            container_id,
+            follow,
+            tail,
+            since,
+            until,
+            timestamps,
+        } => {
+            let container = exit_on_error(find_container(&container_id));
+            logging::set_container_id(&container.id);
+            let options = LogOptions {
+                follow,
+                tail,
+                since: since.map(|since| exit_on_error(LogOptions::parse_time(&since))),
+                until: until.map(|until| exit_on_error(LogOptions::parse_time(&until))),
+                timestamps,
+            };
+            let driver = exit_on_error(create_log_driver(
+                &container.id,
+                &container.config.log_config,
+            ));
+            exit_on_error(driver.read(&container.id, &options));
+        }
+        Commands::Exec {
+            container_id,
+            interactive,
+            tty,
+            user,
+            env,
+            workdir,
+            command,
+        } => {
+            let container = exit_on_error(find_container(&container_id));
+            logging::set_container_id(&container.id);
+            let config = ExecConfig {
+                command,
+                env_vars: env,
+                working_dir: workdir,
+                user,
+                interactive,
+                tty,
+            };
+            let code = exit_on_error(Exec::run(&container, &config));
+            std::process::exit(code);
+        }
+        Commands::Stop { container_id, time } => {
+            let mut container = exit_on_error(find_container(&container_id));
+            logging::set_container_id(&container.id);
+            println!("Stoping container: {}", container.id);
+            exit_on_error(Runtime::stop_container(&mut container, time));
+        }
+        Commands::Wait {
+            container_ids,
+            condition,
+        } => {
+            let mut failed = false;
+            for container_id in container_ids {
+                let waited = find_container(&container_id).and_then(|container| {
+                    logging::set_container_id(&container.id);
+                    Runtime::wait_container(&container.id, condition)
+                });
+                match waited {
+                    // A container that never ran did not fail either.
+                    Ok(exit_code) => println!("{}", exit_code.unwrap_or(0)),
+                    Err(err) => {
+                        eprintln!("Error: {}", err);
+                        log::error!("{}", err);
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                std::process::exit(1);
+            }
+        }
+        Commands::Rm {
+            container_ids,
+            force,
+            volumes,
+        } => {
+            let mut failed = false;
+            for container_id in container_ids {
+                let removed = find_container(&container_id).and_then(|container| {
+                    logging::set_container_id(&container.id);
+                    Runtime::remove_container(&container, force, volumes)?;
+                    Ok(container.id)
+                });
+                match removed {
+                    Ok(id) => println!("{}", id),
+                    Err(err) => {
+                        eprintln!("Error: {}", err);
+                        log::error!("{}", err);
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                std::process::exit(1);
             }
         }
-        Commands::Stop { container_id } => {
-            println!("Stoping container: {}", container_id);
-            if get_container_ids().unwrap().contains(&container_id) {
-                let mut container = get_container(&container_id).unwrap();
-                Runtime::stop_container(&mut container).unwrap();
+        Commands::Container { command } => match command {
+            cli::commands::ContainerCommands::Prune { filters } => {
+                let containers = exit_on_error(get_containers());
+                let stopped = containers.iter().filter(|c| {
+                    !matches!(
+                        c.status,
+                        ContainerStatus::Running | ContainerStatus::Restarting
+                    )
+                });
+                for container in stopped.filter(|c| filters.iter().all(|f| f.matches(c))) {
+                    logging::set_container_id(&container.id);
+                    match Runtime::remove_container(container, false, false) {
+                        Ok(()) => println!("{}", container.id),
+                        Err(err) => {
+                            eprintln!("Error: {}", err);
+                            log::error!("{}", err);
+                        }
+                    }
+                }
             }
+        },
+        Commands::Kill {
+            container_id,
+            signal,
+        } => {
+            let container = exit_on_error(find_container(&container_id));
+            logging::set_container_id(&container.id);
+            exit_on_error(Supervisor::kill(&container.id, signal));
         }
         Commands::Volume { command } => match command {
             cli::commands::VolumeCommands::Create { name, driver } => {
@@ -145,6 +550,107 @@ fn main() {
                 log::info!("Removing the volume: {}", name)
             }
         },
+        Commands::Network { command } => match command {
+            NetworkCommands::Create {
+                name,
+                driver,
+                subnet,
+                gateway,
+                ip_range,
+                ipv6,
+                subnet6,
+                gateway6,
+                internal,
+                labels,
+                options,
+            } => {
+                log::info!("Creating network {} with driver {}", name, driver);
+                let config = NetworkConfig {
+                    name,
+                    driver,
+                    subnet,
+                    gateway,
+                    ip_range,
+                    ipv6,
+                    subnet6,
+                    gateway6,
+                    internal,
+                    labels: exit_on_error(ContainerConfig::parse_labels(&labels)),
+                    options: exit_on_error(ContainerConfig::parse_labels(&options)),
+                };
+                let network = exit_on_error(network::create(config));
+                println!("{}", network.id);
+            }
+            NetworkCommands::Ls => {
+                for network in exit_on_error(network::store::get_networks()) {
+                    let subnets: Vec<String> = network
+                        .subnet
+                        .map(|subnet| subnet.to_string())
+                        .into_iter()
+                        .chain(network.subnet6.map(|subnet| subnet.to_string()))
+                        .collect();
+                    println!(
+                        "{} {} {} {}",
+                        &network.id[..12],
+                        network.name,
+                        network.driver,
+                        if subnets.is_empty() {
+                            "-".to_string()
+                        } else {
+                            subnets.join(",")
+                        }
+                    );
+                }
+            }
+            NetworkCommands::Inspect { network: reference } => {
+                let network = exit_on_error(network::store::get_network(&reference));
+                println!(
+                    "{}",
+                    exit_on_error(serde_json::to_string_pretty(&network).map_err(Into::into))
+                );
+            }
+            NetworkCommands::Rm { networks } => {
+                let mut failed = false;
+                for reference in networks {
+                    match network::remove(&reference) {
+                        Ok(network) => println!("{}", network.name),
+                        Err(err) => {
+                            eprintln!("Error: {}", err);
+                            log::error!("{}", err);
+                            failed = true;
+                        }
+                    }
+                }
+                if failed {
+                    std::process::exit(1);
+                }
+            }
+            NetworkCommands::Connect {
+                network,
+                container_id,
+                ip,
+                ip6,
+                aliases,
+            } => {
+                let container = exit_on_error(find_container(&container_id));
+                logging::set_container_id(&container.id);
+                let attachment = NetworkAttachment {
+                    network,
+                    ip,
+                    ip6,
+                    aliases,
+                };
+                exit_on_error(Runtime::connect_network(&container, attachment));
+            }
+            NetworkCommands::Disconnect {
+                network,
+                container_id,
+            } => {
+                let container = exit_on_error(find_container(&container_id));
+                logging::set_container_id(&container.id);
+                exit_on_error(Runtime::disconnect_network(&container, &network));
+            }
+        },
         Commands::Image { command } => match command {
             cli::commands::ImageCommands::Import { path, name, tag } => {
                 log::info!(
@@ -152,13 +658,36 @@ fn main() {
                     name,
                     tag,
                     path
-                )
+                );
+                let mut images =
+                    exit_on_error(ImageManager::new(StorageConfig::default().images_dir));
+                let image = exit_on_error(images.import_from_tar(&path, &name, &tag));
+                println!("{}", image.id);
             }
             cli::commands::ImageCommands::Ls => {
-                log::info!("Listing out the images")
+                log::info!("Listing out the images");
+                let images = exit_on_error(ImageManager::new(StorageConfig::default().images_dir));
+                for image in images.list() {
+                    println!(
+                        "{}:{} {} {}",
+                        image.name,
+                        image.tag,
+                        &image.id[..12],
+                        image.size
+                    );
+                }
             }
             cli::commands::ImageCommands::Rm { name, tag } => {
-                log::info!("Removing image {} with tag {}", name, tag)
+                log::info!("Removing image {} with tag {}", name, tag);
+                let mut images =
+                    exit_on_error(ImageManager::new(StorageConfig::default().images_dir));
+                let image_id = exit_on_error(
+                    images
+                        .get(&name, &tag)
+                        .map(|image| image.id.clone())
+                        .ok_or_else(|| anyhow::anyhow!("Image {}:{} not found", name, tag)),
+                );
+                exit_on_error(images.remove(&image_id));
             }
         },
     }
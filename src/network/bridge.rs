@@ -0,0 +1,144 @@
+// src/network/bridge.rs
+use anyhow::{Result, anyhow};
+use futures::TryStreamExt;
+use rtnetlink::packet_route::address::AddressAttribute;
+use rtnetlink::{Handle, LinkBridge, LinkUnspec, LinkVeth};
+use std::fs::write;
+use std::net::IpAddr;
+
+use crate::network::netlink::{link_index, set_up};
+use crate::network::{Endpoint, Network};
+
+/// Creates the network's bridge with the gateway addresses unless it
+/// exists, and turns on forwarding so containers can reach beyond it.
+pub async fn ensure_bridge(handle: &Handle, network: &Network) -> Result<u32> {
+    let (Some(subnet), Some(gateway)) = (network.subnet, network.gateway) else {
+        return Err(anyhow!("Network {} has no subnet", network.name));
+    };
+    let index = match link_index(handle, &network.bridge).await? {
+        Some(index) => index,
+        None => {
+            log::info!("Creating bridge {} for {}", network.bridge, subnet);
+            handle
+                .link()
+                .add(LinkBridge::new(&network.bridge).build())
+                .execute()
+                .await
+                .map_err(|e| anyhow!("Failed to create bridge {}: {}", network.bridge, e))?;
+            link_index(handle, &network.bridge)
+                .await?
+                .ok_or_else(|| anyhow!("Bridge {} vanished", network.bridge))?
+        }
+    };
+
+    let mut gateways = vec![(IpAddr::V4(gateway), subnet.prefix())];
+    if let (Some(subnet6), Some(gateway6)) = (network.subnet6, network.gateway6) {
+        // The gateway would stay tentative until the bridge has a port up.
+        write(
+            format!("/proc/sys/net/ipv6/conf/{}/accept_dad", network.bridge),
+            "0",
+        )
+        .map_err(|e| anyhow!("Failed to configure {}: {}", network.bridge, e))?;
+        gateways.push((IpAddr::V6(gateway6), subnet6.prefix()));
+    }
+    for (gateway, prefix) in gateways {
+        if !has_address(handle, index, gateway).await? {
+            handle
+                .address()
+                .add(index, gateway, prefix)
+                .execute()
+                .await
+                .map_err(|e| {
+                    anyhow!("Failed to assign {} to {}: {}", gateway, network.bridge, e)
+                })?;
+        }
+    }
+    set_up(handle, index).await?;
+
+    write("/proc/sys/net/ipv4/ip_forward", "1")
+        .map_err(|e| anyhow!("Failed to enable IPv4 forwarding: {}", e))?;
+    if network.subnet6.is_some() {
+        write("/proc/sys/net/ipv6/conf/all/forwarding", "1")
+            .map_err(|e| anyhow!("Failed to enable IPv6 forwarding: {}", e))?;
+    }
+    Ok(index)
+}
+
+async fn has_address(handle: &Handle, index: u32, address: IpAddr) -> Result<bool> {
+    let mut addresses = handle
+        .address()
+        .get()
+        .set_link_index_filter(index)
+        .execute();
+    while let Some(message) = addresses
+        .try_next()
+        .await
+        .map_err(|e| anyhow!("Failed to list addresses: {}", e))?
+    {
+        let found = message
+            .attributes
+            .iter()
+            .any(|attribute| matches!(attribute, AddressAttribute::Address(a) if *a == address));
+        if found {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Creates the veth pair of `endpoint`, plugs the host end into the
+/// network's bridge and moves the other end into the network namespace of
+/// process `pid`.
+pub async fn attach(handle: Handle, network: Network, endpoint: Endpoint, pid: i32) -> Result<()> {
+    let bridge = ensure_bridge(&handle, &network).await?;
+
+    // Left behind when another process kept the previous namespace alive.
+    if let Some(stale) = link_index(&handle, &endpoint.host_interface).await? {
+        let _ = handle.link().del(stale).execute().await;
+    }
+
+    let peer = endpoint.peer_interface();
+    handle
+        .link()
+        .add(LinkVeth::new(&endpoint.host_interface, &peer).build())
+        .execute()
+        .await
+        .map_err(|e| anyhow!("Failed to create veth {}: {}", endpoint.host_interface, e))?;
+
+    let host = link_index(&handle, &endpoint.host_interface)
+        .await?
+        .ok_or_else(|| anyhow!("Interface {} vanished", endpoint.host_interface))?;
+    handle
+        .link()
+        .set(
+            LinkUnspec::new_with_index(host)
+                .controller(bridge)
+                .up()
+                .build(),
+        )
+        .execute()
+        .await
+        .map_err(|e| {
+            anyhow!(
+                "Failed to plug {} into {}: {}",
+                endpoint.host_interface,
+                network.bridge,
+                e
+            )
+        })?;
+
+    let peer_index = link_index(&handle, &peer)
+        .await?
+        .ok_or_else(|| anyhow!("Interface {} vanished", peer))?;
+    handle
+        .link()
+        .set(
+            LinkUnspec::new_with_index(peer_index)
+                .setns_by_pid(pid as u32)
+                .build(),
+        )
+        .execute()
+        .await
+        .map_err(|e| anyhow!("Failed to move {} into the container: {}", peer, e))?;
+    Ok(())
+}
@@ -0,0 +1,441 @@
+// src/network/dns.rs
+use anyhow::{Result, anyhow};
+use async_std::channel::{Sender, bounded};
+use async_std::io::timeout;
+use async_std::net::{TcpListener, TcpStream, UdpSocket};
+use async_std::task;
+use futures::future::{Either, join, select};
+use futures::{AsyncReadExt, AsyncWriteExt, pin_mut};
+use nix::sched::{CloneFlags, setns};
+use std::fs::File;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::network::{DEFAULT_NETWORK, NetworkDriver, etc_files, store};
+use crate::runtime::container::{Container, ContainerStatus};
+use crate::runtime::namespace::{NamespaceKind, NamespaceMode};
+use crate::storage::storage::{get_container, get_containers};
+
+/// Address the embedded DNS server listens on inside the container.
+pub const LISTEN_ADDRESS: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 11);
+/// TTL of the answers for containers, which come and go.
+const TTL: u32 = 600;
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RCODE_SERVFAIL: u8 = 2;
+
+/// Whether the container gets an embedded DNS server: it has a network
+/// namespace of its own connected to networks by the runtime.
+pub fn enabled(container: &Container) -> bool {
+    let config = &container.config;
+    *config.namespaces.mode(NamespaceKind::Net) == NamespaceMode::Private
+        && config.rootless_network.is_none()
+        && config
+            .networks
+            .iter()
+            .any(|a| store::get_network(&a.network).is_ok_and(|n| n.driver != NetworkDriver::None))
+}
+
+/// Answers the DNS queries of a running container, dropping it stops the
+/// server.
+pub struct DnsServer {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DnsServer {
+    /// Listens on `LISTEN_ADDRESS` in the network namespace of process
+    /// `pid`. Queries are answered from the host's, so that upstream
+    /// resolvers only the host reaches work too.
+    pub fn start(container: &Container, pid: i32) -> Result<DnsServer> {
+        let path = format!("/proc/{}/ns/net", pid);
+        let netns = File::open(&path).map_err(|e| anyhow!("Failed to open {}: {}", path, e))?;
+        let address = SocketAddr::from((LISTEN_ADDRESS, 53));
+        let (udp, tcp) = thread::spawn(move || -> io::Result<_> {
+            setns(&netns, CloneFlags::CLONE_NEWNET).map_err(io::Error::from)?;
+            Ok((
+                std::net::UdpSocket::bind(address)?,
+                std::net::TcpListener::bind(address)?,
+            ))
+        })
+        .join()
+        .map_err(|_| anyhow!("DNS setup thread panicked"))?
+        .map_err(|e| anyhow!("Failed to listen on {}: {}", address, e))?;
+
+        let resolver = Arc::new(Resolver {
+            container_id: container.id.clone(),
+            upstreams: etc_files::upstream_nameservers(container),
+        });
+        let (stop, stopped) = bounded::<()>(1);
+        let thread = thread::spawn(move || {
+            task::block_on(async move {
+                let serving = join(serve_udp(udp, resolver.clone()), serve_tcp(tcp, resolver));
+                let stopped = stopped.recv();
+                pin_mut!(serving, stopped);
+                if let Either::Left(((udp, tcp), _)) = select(serving, stopped).await {
+                    for e in [udp, tcp].into_iter().filter_map(|r| r.err()) {
+                        log::error!("DNS server stopped: {}", e);
+                    }
+                }
+            })
+        });
+
+        log::info!("Serving DNS for {} on {}", container.id, address);
+        Ok(DnsServer {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for DnsServer {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The question of a query, as far as the server looks at it.
+#[derive(Clone)]
+struct Question {
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    /// Offset of the end of the question in the message.
+    end: usize,
+}
+
+/// Parses the single question of a standard query.
+fn parse_query(message: &[u8]) -> Option<Question> {
+    if message.len() < 12 || message[2] & 0xf8 != 0 {
+        return None;
+    }
+    if u16::from_be_bytes([message[4], message[5]]) != 1 {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut offset = 12;
+    loop {
+        let len = *message.get(offset)? as usize;
+        offset += 1;
+        if len == 0 {
+            break;
+        }
+        // Queries do not use compression.
+        if len > 63 {
+            return None;
+        }
+        let label = message.get(offset..offset + len)?;
+        labels.push(String::from_utf8_lossy(label).to_lowercase());
+        offset += len;
+    }
+    let fixed = message.get(offset..offset + 4)?;
+    Some(Question {
+        name: labels.join("."),
+        qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+        qclass: u16::from_be_bytes([fixed[2], fixed[3]]),
+        end: offset + 4,
+    })
+}
+
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        encoded.push(label.len() as u8);
+        encoded.extend_from_slice(label.as_bytes());
+    }
+    encoded.push(0);
+    encoded
+}
+
+/// Builds the answer to `query` with the record data in `answers`.
+fn reply(query: &[u8], question: &Question, rcode: u8, answers: &[Vec<u8>]) -> Vec<u8> {
+    let mut message = query[..question.end].to_vec();
+    // Response, authoritative, recursion desired as asked and available.
+    message[2] = 0x84 | (query[2] & 0x01);
+    message[3] = 0x80 | rcode;
+    message[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+    message[8..12].fill(0);
+    for data in answers {
+        // The name is a pointer to the question's.
+        message.extend_from_slice(&[0xc0, 0x0c]);
+        message.extend_from_slice(&question.qtype.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message.extend_from_slice(&TTL.to_be_bytes());
+        message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        message.extend_from_slice(data);
+    }
+    message
+}
+
+/// Answers for the containers on the user-defined networks a container is
+/// connected to, and forwards the rest.
+struct Resolver {
+    container_id: String,
+    upstreams: Vec<IpAddr>,
+}
+
+/// The address a reverse lookup name like `4.3.2.1.in-addr.arpa` or
+/// `b.a.9.8.7.6.5.0.4.0.0.0.3.0.0.0.2.0.0.0.1.0.0.0.0.0.0.0.1.2.3.4.ip6.arpa`
+/// stands for.
+fn reverse_address(name: &str) -> Option<IpAddr> {
+    if let Some(reversed) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets: Vec<&str> = reversed.split('.').collect();
+        octets.reverse();
+        return octets.join(".").parse().ok();
+    }
+    let nibbles: Vec<u8> = name
+        .strip_suffix(".ip6.arpa")?
+        .split('.')
+        .map(|nibble| {
+            u8::from_str_radix(nibble, 16)
+                .ok()
+                .filter(|_| nibble.len() == 1)
+        })
+        .collect::<Option<_>>()?;
+    if nibbles.len() != 32 {
+        return None;
+    }
+    let mut octets = [0u8; 16];
+    for (i, pair) in nibbles.rchunks(2).enumerate() {
+        octets[i] = pair[1] << 4 | pair[0];
+    }
+    Some(IpAddr::V6(Ipv6Addr::from(octets)))
+}
+
+/// What a query is answered with locally.
+enum Answer {
+    Addresses(Vec<IpAddr>),
+    Name(String),
+}
+
+impl Resolver {
+    /// Looks the question up in the metadata store, `None` for names that
+    /// are not the runtime's.
+    fn lookup(&self, question: &Question) -> Result<Option<Answer>> {
+        let container = get_container(&self.container_id)?;
+        let networks: Vec<&str> = container
+            .networks
+            .iter()
+            .map(|e| e.network.as_str())
+            .filter(|network| *network != DEFAULT_NETWORK)
+            .collect();
+        if networks.is_empty() {
+            return Ok(None);
+        }
+        let ptr = reverse_address(&question.name);
+
+        let mut addresses = Vec::new();
+        for other in get_containers()? {
+            if other.status != ContainerStatus::Running {
+                continue;
+            }
+            for endpoint in other
+                .networks
+                .iter()
+                .filter(|e| networks.contains(&e.network.as_str()))
+            {
+                if let Some(ip) = ptr {
+                    if endpoint.addresses().contains(&ip) {
+                        let name = if other.name.is_empty() {
+                            &other.id
+                        } else {
+                            &other.name
+                        };
+                        return Ok(Some(Answer::Name(name.clone())));
+                    }
+                    continue;
+                }
+                let aliases = other
+                    .config
+                    .networks
+                    .iter()
+                    .filter(|a| a.network == endpoint.network)
+                    .flat_map(|a| &a.aliases);
+                let matches = [&other.name, &other.id]
+                    .into_iter()
+                    .chain(aliases)
+                    .any(|name| !name.is_empty() && name.eq_ignore_ascii_case(&question.name));
+                if matches {
+                    for address in endpoint.addresses() {
+                        if !addresses.contains(&address) {
+                            addresses.push(address);
+                        }
+                    }
+                }
+            }
+        }
+        Ok((!addresses.is_empty()).then_some(Answer::Addresses(addresses)))
+    }
+
+    /// Answers `query` if it is about the runtime's containers.
+    async fn answer(self: &Arc<Self>, query: &[u8]) -> Option<Vec<u8>> {
+        let question = parse_query(query)?;
+        if question.qclass != CLASS_IN {
+            return None;
+        }
+        let (resolver, asked) = (self.clone(), question.clone());
+        let answer = task::spawn_blocking(move || resolver.lookup(&asked)).await;
+        let answer = match answer {
+            Ok(answer) => answer?,
+            Err(e) => {
+                log::debug!("DNS lookup of {} failed: {}", question.name, e);
+                return None;
+            }
+        };
+
+        let records: Vec<Vec<u8>> = match (answer, question.qtype) {
+            (Answer::Name(name), TYPE_PTR) => vec![encode_name(&name)],
+            (Answer::Addresses(addresses), TYPE_A) => addresses
+                .iter()
+                .filter_map(|ip| match ip {
+                    IpAddr::V4(ip) => Some(ip.octets().to_vec()),
+                    IpAddr::V6(_) => None,
+                })
+                .collect(),
+            (Answer::Addresses(addresses), TYPE_AAAA) => addresses
+                .iter()
+                .filter_map(|ip| match ip {
+                    IpAddr::V6(ip) => Some(ip.octets().to_vec()),
+                    IpAddr::V4(_) => None,
+                })
+                .collect(),
+            // Other types of a known name have no records.
+            _ => Vec::new(),
+        };
+        Some(reply(query, &question, 0, &records))
+    }
+
+    /// Whether queries may leave the host, which they may not for
+    /// containers only on internal networks.
+    fn forwards(&self) -> bool {
+        get_container(&self.container_id).is_ok_and(|c| {
+            c.networks
+                .iter()
+                .any(|e| store::get_network(&e.network).is_ok_and(|n| !n.internal))
+        })
+    }
+
+    async fn forward_udp(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        let mut last = io::Error::other("no upstream resolvers");
+        for upstream in &self.upstreams {
+            let local: SocketAddr = match upstream {
+                IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                IpAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+            };
+            let exchange = async {
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect((*upstream, 53)).await?;
+                socket.send(query).await?;
+                let mut buf = vec![0u8; 65535];
+                let len = socket.recv(&mut buf).await?;
+                buf.truncate(len);
+                Ok(buf)
+            };
+            match timeout(UPSTREAM_TIMEOUT, exchange).await {
+                Ok(reply) => return Ok(reply),
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+
+    async fn forward_tcp(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        let mut last = io::Error::other("no upstream resolvers");
+        for upstream in &self.upstreams {
+            let exchange = async {
+                let mut stream = TcpStream::connect((*upstream, 53)).await?;
+                write_tcp(&mut stream, query).await?;
+                read_tcp(&mut stream).await
+            };
+            match timeout(UPSTREAM_TIMEOUT, exchange).await {
+                Ok(reply) => return Ok(reply),
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+
+    /// Answers locally or forwards the query over the same transport, a
+    /// failure to forward is answered with SERVFAIL.
+    async fn resolve(self: &Arc<Self>, query: &[u8], tcp: bool) -> Option<Vec<u8>> {
+        if let Some(reply) = self.answer(query).await {
+            return Some(reply);
+        }
+        let resolver = self.clone();
+        if !task::spawn_blocking(move || resolver.forwards()).await {
+            let question = parse_query(query)?;
+            return Some(reply(query, &question, RCODE_SERVFAIL, &[]));
+        }
+        let forwarded = if tcp {
+            self.forward_tcp(query).await
+        } else {
+            self.forward_udp(query).await
+        };
+        match forwarded {
+            Ok(reply) => Some(reply),
+            Err(e) => {
+                log::debug!("Forwarding DNS query failed: {}", e);
+                let question = parse_query(query)?;
+                Some(reply(query, &question, RCODE_SERVFAIL, &[]))
+            }
+        }
+    }
+}
+
+async fn serve_udp(socket: std::net::UdpSocket, resolver: Arc<Resolver>) -> io::Result<()> {
+    let socket = Arc::new(UdpSocket::from(socket));
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let (len, client) = socket.recv_from(&mut buf).await?;
+        let query = buf[..len].to_vec();
+        let (socket, resolver) = (socket.clone(), resolver.clone());
+        task::spawn(async move {
+            if let Some(reply) = resolver.resolve(&query, false).await {
+                let _ = socket.send_to(&reply, client).await;
+            }
+        });
+    }
+}
+
+async fn serve_tcp(listener: std::net::TcpListener, resolver: Arc<Resolver>) -> io::Result<()> {
+    let listener = TcpListener::from(listener);
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let resolver = resolver.clone();
+        task::spawn(async move {
+            while let Ok(query) = read_tcp(&mut stream).await {
+                let Some(reply) = resolver.resolve(&query, true).await else {
+                    break;
+                };
+                if write_tcp(&mut stream, &reply).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Reads a message prefixed by its length, as DNS is sent over TCP.
+async fn read_tcp(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let mut message = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+async fn write_tcp(stream: &mut TcpStream, message: &[u8]) -> io::Result<()> {
+    let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(message);
+    stream.write_all(&framed).await
+}
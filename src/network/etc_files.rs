@@ -0,0 +1,217 @@
+// src/network/etc_files.rs
+use anyhow::{Result, anyhow};
+use nix::mount::{MsFlags, mount};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::network::{dns, slirp};
+use crate::runtime::container::Container;
+use crate::runtime::namespace::{NamespaceKind, NamespaceMode};
+use crate::storage::storage::get_container_dir;
+
+/// Files under the container's `/etc` that are generated by the runtime.
+const FILES: [&str; 3] = ["hosts", "hostname", "resolv.conf"];
+/// Resolvers used when the host only has loopback ones, which cannot be
+/// reached from the container's network namespace.
+const FALLBACK_NAMESERVERS: [IpAddr; 2] = [
+    IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)),
+    IpAddr::V4(Ipv4Addr::new(8, 8, 4, 4)),
+];
+
+/// Value of `--add-host`: `HOST:IP`, a line added to the container's
+/// `/etc/hosts`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtraHost {
+    pub hostname: String,
+    pub ip: IpAddr,
+}
+
+impl FromStr for ExtraHost {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (hostname, ip) = value
+            .split_once(':')
+            .filter(|(hostname, _)| !hostname.is_empty())
+            .ok_or_else(|| anyhow!("Invalid host {:?}, expected HOST:IP", value))?;
+        let ip = ip
+            .parse()
+            .map_err(|e| anyhow!("Invalid address in {:?}: {}", value, e))?;
+        Ok(ExtraHost {
+            hostname: hostname.to_string(),
+            ip,
+        })
+    }
+}
+
+/// The parts of a resolv.conf the runtime passes on.
+#[derive(Debug, Default)]
+struct ResolvConf {
+    nameservers: Vec<IpAddr>,
+    search: Vec<String>,
+    options: Vec<String>,
+}
+
+impl ResolvConf {
+    fn parse(text: &str) -> Self {
+        let mut conf = ResolvConf::default();
+        for line in text.lines() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("nameserver") => conf
+                    .nameservers
+                    .extend(words.next().and_then(|ip| ip.parse::<IpAddr>().ok())),
+                // The last search or domain line wins.
+                Some("search") | Some("domain") => conf.search = words.map(String::from).collect(),
+                Some("options") => conf.options.extend(words.map(String::from)),
+                _ => {}
+            }
+        }
+        conf
+    }
+
+    fn render(&self) -> String {
+        let mut text = String::new();
+        for nameserver in &self.nameservers {
+            text.push_str(&format!("nameserver {}\n", nameserver));
+        }
+        if !self.search.is_empty() {
+            text.push_str(&format!("search {}\n", self.search.join(" ")));
+        }
+        if !self.options.is_empty() {
+            text.push_str(&format!("options {}\n", self.options.join(" ")));
+        }
+        text
+    }
+}
+
+/// The container's resolv.conf: the embedded DNS server, or the host's
+/// resolvers without loopback ones unless the host's network is shared.
+/// `--dns`, `--dns-search` and `--dns-option` replace the respective parts.
+fn resolv_conf(container: &Container) -> String {
+    let config = &container.config;
+    let host_network = *config.namespaces.mode(NamespaceKind::Net) == NamespaceMode::Host;
+    let mut conf = ResolvConf::parse(&fs::read_to_string("/etc/resolv.conf").unwrap_or_default());
+
+    if dns::enabled(container) {
+        conf.nameservers = vec![IpAddr::V4(dns::LISTEN_ADDRESS)];
+    } else if !config.dns.is_empty() {
+        conf.nameservers = config.dns.clone();
+    } else if config.rootless_network.is_some() {
+        conf.nameservers = vec![IpAddr::V4(slirp::DNS_SERVER)];
+    } else if !host_network {
+        conf.nameservers.retain(|ip| !ip.is_loopback());
+        if conf.nameservers.is_empty() {
+            log::info!(
+                "The host has no resolvers {} can reach, using {:?}",
+                container.id,
+                FALLBACK_NAMESERVERS
+            );
+            conf.nameservers = FALLBACK_NAMESERVERS.to_vec();
+        }
+    }
+    if !config.dns_search.is_empty() {
+        conf.search = config.dns_search.clone();
+    }
+    if !config.dns_options.is_empty() {
+        conf.options = config.dns_options.clone();
+    }
+    conf.render()
+}
+
+/// Resolvers the embedded DNS server forwards to from the host, `--dns` or
+/// the host's, loopback ones included.
+pub fn upstream_nameservers(container: &Container) -> Vec<IpAddr> {
+    if !container.config.dns.is_empty() {
+        return container.config.dns.clone();
+    }
+    let conf = ResolvConf::parse(&fs::read_to_string("/etc/resolv.conf").unwrap_or_default());
+    if conf.nameservers.is_empty() {
+        return FALLBACK_NAMESERVERS.to_vec();
+    }
+    conf.nameservers
+}
+
+/// The container's hosts file: the host's if the host's network is shared,
+/// otherwise localhost and the container's own addresses, followed by
+/// `--add-host` entries.
+fn hosts(container: &Container) -> String {
+    let config = &container.config;
+    let mut text = if *config.namespaces.mode(NamespaceKind::Net) == NamespaceMode::Host {
+        fs::read_to_string("/etc/hosts").unwrap_or_default()
+    } else {
+        let mut text =
+            String::from("127.0.0.1\tlocalhost\n::1\tlocalhost ip6-localhost ip6-loopback\n");
+        let addresses: Vec<IpAddr> = match config.rootless_network {
+            Some(_) => vec![IpAddr::V4(slirp::GUEST_ADDRESS)],
+            None => container
+                .networks
+                .iter()
+                .flat_map(|e| e.addresses())
+                .collect(),
+        };
+        for address in addresses {
+            text.push_str(&format!("{}\t{}\n", address, config.hostname));
+        }
+        text
+    };
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    for host in &config.extra_hosts {
+        text.push_str(&format!("{}\t{}\n", host.ip, host.hostname));
+    }
+    text
+}
+
+/// Writes the container's hosts, hostname and resolv.conf to its state
+/// directory and makes sure the rootfs has files to mount them on. Files
+/// already mounted are rewritten in place, so a running container sees
+/// the change.
+pub fn write(container: &Container) -> Result<()> {
+    let dir = get_container_dir(&container.id);
+    fs::create_dir_all(&dir)?;
+    let contents = [
+        hosts(container),
+        format!("{}\n", container.config.hostname),
+        resolv_conf(container),
+    ];
+    for (name, content) in FILES.iter().zip(contents) {
+        fs::write(dir.join(name), content)
+            .map_err(|e| anyhow!("Failed to write {}: {}", name, e))?;
+    }
+
+    let etc = container.config.rootfs.join("etc");
+    fs::create_dir_all(&etc)?;
+    for name in FILES {
+        let target = etc.join(name);
+        // Mounts follow symlinks, which would lead out of the rootfs.
+        if target.symlink_metadata().is_ok_and(|m| m.is_symlink()) {
+            fs::remove_file(&target)?;
+        }
+        if !target.exists() {
+            File::create(&target).map_err(|e| anyhow!("Failed to create /etc/{}: {}", name, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Bind-mounts the files written by `write` over the ones in `rootfs`,
+/// from within the container's mount namespace.
+pub fn mount_files(container_id: &str, rootfs: &Path) -> Result<()> {
+    let dir = get_container_dir(container_id);
+    for name in FILES {
+        mount(
+            Some(&dir.join(name)),
+            &rootfs.join("etc").join(name),
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .map_err(|e| anyhow!("Failed to mount /etc/{}: {}", name, e))?;
+    }
+    Ok(())
+}
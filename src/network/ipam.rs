@@ -0,0 +1,102 @@
+// src/network/ipam.rs
+use anyhow::{Result, anyhow};
+use ipnetwork::{Ipv4Network, Ipv6Network};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Addresses handed out on a network's subnet, stored with the network so
+/// that they survive restarts of the runtime.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Ipam {
+    /// Id of the container holding each address.
+    pub allocations: BTreeMap<Ipv4Addr, String>,
+    /// Likewise on the network's IPv6 subnet.
+    #[serde(default)]
+    pub allocations6: BTreeMap<Ipv6Addr, String>,
+}
+
+impl Ipam {
+    /// Hands out `requested`, or the lowest free address of `range`. The
+    /// network and broadcast addresses of `subnet` and the gateway are never
+    /// handed out.
+    pub fn allocate(
+        &mut self,
+        subnet: Ipv4Network,
+        gateway: Ipv4Addr,
+        range: Option<Ipv4Network>,
+        requested: Option<Ipv4Addr>,
+        container_id: &str,
+    ) -> Result<Ipv4Addr> {
+        let usable = |address: &Ipv4Addr| {
+            *address != subnet.network() && *address != subnet.broadcast() && *address != gateway
+        };
+
+        let address = match requested {
+            Some(address) => {
+                if !subnet.contains(address) || !usable(&address) {
+                    return Err(anyhow!("Address {} cannot be used in {}", address, subnet));
+                }
+                if let Some(holder) = self.allocations.get(&address) {
+                    return Err(anyhow!("Address {} is already used by {}", address, holder));
+                }
+                address
+            }
+            None => {
+                let range = range.unwrap_or(subnet);
+                range
+                    .iter()
+                    .filter(usable)
+                    .find(|address| !self.allocations.contains_key(address))
+                    .ok_or_else(|| anyhow!("No free addresses left in {}", range))?
+            }
+        };
+
+        self.allocations.insert(address, container_id.to_string());
+        Ok(address)
+    }
+
+    /// Like `allocate` on an IPv6 subnet, which has no broadcast address.
+    /// Free addresses are searched from the start of the subnet.
+    pub fn allocate6(
+        &mut self,
+        subnet: Ipv6Network,
+        gateway: Ipv6Addr,
+        requested: Option<Ipv6Addr>,
+        container_id: &str,
+    ) -> Result<Ipv6Addr> {
+        let usable = |address: &Ipv6Addr| *address != subnet.network() && *address != gateway;
+
+        let address = match requested {
+            Some(address) => {
+                if !subnet.contains(address) || !usable(&address) {
+                    return Err(anyhow!("Address {} cannot be used in {}", address, subnet));
+                }
+                if let Some(holder) = self.allocations6.get(&address) {
+                    return Err(anyhow!("Address {} is already used by {}", address, holder));
+                }
+                address
+            }
+            None => subnet
+                .iter()
+                .filter(usable)
+                .find(|address| !self.allocations6.contains_key(address))
+                .ok_or_else(|| anyhow!("No free addresses left in {}", subnet))?,
+        };
+
+        self.allocations6.insert(address, container_id.to_string());
+        Ok(address)
+    }
+
+    /// Frees the addresses of a container, returns whether it had any.
+    pub fn release(&mut self, container_id: &str) -> bool {
+        let allocated = self.allocations.len() + self.allocations6.len();
+        self.allocations.retain(|_, id| id != container_id);
+        self.allocations6.retain(|_, id| id != container_id);
+        self.allocations.len() + self.allocations6.len() != allocated
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allocations.is_empty() && self.allocations6.is_empty()
+    }
+}
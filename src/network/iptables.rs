@@ -0,0 +1,128 @@
+// src/network/iptables.rs
+use anyhow::{Result, anyhow};
+use std::process::Command;
+
+use crate::network::nat::NatRules;
+
+/// Chains of the nat table holding the runtime's rules, flushed and filled
+/// again on every change.
+const DNAT_CHAIN: &str = "NEBULON";
+const MASQUERADE_CHAIN: &str = "NEBULON-POSTROUTING";
+
+/// Runs `program`, iptables or ip6tables, on the nat table.
+fn iptables(program: &str, args: &[&str]) -> Result<()> {
+    let output = Command::new(program)
+        .args(["-w", "-t", "nat"])
+        .args(args)
+        .output()
+        .map_err(|e| anyhow!("Failed to run {}: {}", program, e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Appends `rule` to `chain` unless it is there already.
+fn ensure_rule(program: &str, chain: &str, rule: &[&str]) -> Result<()> {
+    let check = [&["-C", chain], rule].concat();
+    if iptables(program, &check).is_err() {
+        iptables(program, &[&["-A", chain], rule].concat())?;
+    }
+    Ok(())
+}
+
+/// Programs `rules` with iptables and ip6tables, for hosts without
+/// nf_tables. Hosts without ip6tables only fail with IPv6 rules.
+pub fn apply(rules: &NatRules) -> Result<()> {
+    apply_family(rules, false)?;
+    if let Err(e) = apply_family(rules, true) {
+        let ipv6 = rules.ports.iter().any(|p| p.container_ip.is_ipv6())
+            || rules.masquerade.iter().any(|(subnet, _)| subnet.is_ipv6());
+        if ipv6 {
+            return Err(e);
+        }
+        log::debug!("Skipping IPv6 NAT: {}", e);
+    }
+    Ok(())
+}
+
+fn apply_family(rules: &NatRules, ipv6: bool) -> Result<()> {
+    let (program, loopback) = if ipv6 {
+        ("ip6tables", "::1/128")
+    } else {
+        ("iptables", "127.0.0.0/8")
+    };
+    for chain in [DNAT_CHAIN, MASQUERADE_CHAIN] {
+        if iptables(program, &["-n", "-L", chain]).is_err() {
+            iptables(program, &["-N", chain])?;
+        }
+        iptables(program, &["-F", chain])?;
+    }
+    ensure_rule(
+        program,
+        "PREROUTING",
+        &["-m", "addrtype", "--dst-type", "LOCAL", "-j", DNAT_CHAIN],
+    )?;
+    // Loopback addresses cannot be routed to a container.
+    ensure_rule(
+        program,
+        "OUTPUT",
+        &[
+            "!",
+            "-d",
+            loopback,
+            "-m",
+            "addrtype",
+            "--dst-type",
+            "LOCAL",
+            "-j",
+            DNAT_CHAIN,
+        ],
+    )?;
+    ensure_rule(program, "POSTROUTING", &["-j", MASQUERADE_CHAIN])?;
+
+    for port in rules
+        .ports
+        .iter()
+        .filter(|p| p.container_ip.is_ipv6() == ipv6)
+    {
+        let host_ip = port.host_ip.to_string();
+        let protocol = port.protocol.to_string();
+        let host_port = port.host_port.to_string();
+        let destination = port.container_address().to_string();
+        let mut rule = vec!["-A", DNAT_CHAIN];
+        if !port.host_ip.is_unspecified() {
+            rule.extend(["-d", &host_ip]);
+        }
+        rule.extend(["-p", &protocol, "--dport", &host_port]);
+        rule.extend(["-j", "DNAT", "--to-destination", &destination]);
+        iptables(program, &rule)?;
+    }
+    for (subnet, bridge) in rules
+        .masquerade
+        .iter()
+        .filter(|(subnet, _)| subnet.is_ipv6() == ipv6)
+    {
+        let subnet = subnet.to_string();
+        iptables(
+            program,
+            &[
+                "-A",
+                MASQUERADE_CHAIN,
+                "-s",
+                &subnet,
+                "!",
+                "-o",
+                bridge,
+                "-j",
+                "MASQUERADE",
+            ],
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,49 @@
+// src/network/ipvlan.rs
+use anyhow::{Result, anyhow};
+use rtnetlink::packet_route::link::{InfoData, InfoIpVlan, InfoKind, IpVlanMode};
+use rtnetlink::{Handle, LinkMessageBuilder, LinkUnspec};
+use std::collections::HashMap;
+
+use crate::network::macvlan::parent_index;
+use crate::network::netlink::delete_link;
+use crate::network::{Endpoint, Network};
+
+/// Option picking the layer ipvlan interfaces work on.
+pub const MODE_OPTION: &str = "ipvlan_mode";
+
+/// The mode of the `ipvlan_mode` option, l2 if unset. In l2 mode the
+/// containers share the parent's MAC address but otherwise appear on its
+/// network like macvlan ones, in l3 mode the parent routes their traffic
+/// and they see no broadcasts.
+pub fn mode(options: &HashMap<String, String>) -> Result<IpVlanMode> {
+    match options.get(MODE_OPTION).map(String::as_str) {
+        None | Some("l2") => Ok(IpVlanMode::L2),
+        Some("l3") => Ok(IpVlanMode::L3),
+        Some(mode) => Err(anyhow!("Invalid ipvlan mode {:?}, expected l2 or l3", mode)),
+    }
+}
+
+/// Creates the ipvlan interface of `endpoint` on the network's parent
+/// interface right in the network namespace of process `pid`.
+pub async fn attach(handle: Handle, network: Network, endpoint: Endpoint, pid: i32) -> Result<()> {
+    let mode = mode(&network.options)?;
+    let (parent, parent_index) = parent_index(&handle, &network).await?;
+
+    let peer = endpoint.peer_interface();
+    // Left behind by a start that failed half way.
+    delete_link(&handle, &peer).await?;
+    handle
+        .link()
+        .add(
+            LinkMessageBuilder::<LinkUnspec>::new_with_info_kind(InfoKind::IpVlan)
+                .name(peer)
+                .link(parent_index)
+                .set_info_data(InfoData::IpVlan(vec![InfoIpVlan::Mode(mode)]))
+                .setns_by_pid(pid as u32)
+                .build(),
+        )
+        .execute()
+        .await
+        .map_err(|e| anyhow!("Failed to create ipvlan on {}: {}", parent, e))?;
+    Ok(())
+}
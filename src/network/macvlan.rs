@@ -0,0 +1,65 @@
+// src/network/macvlan.rs
+use anyhow::{Result, anyhow};
+use rtnetlink::packet_route::link::MacVlanMode;
+use rtnetlink::{Handle, LinkMacVlan};
+use std::collections::HashMap;
+
+use crate::network::netlink::{delete_link, link_index};
+use crate::network::{Endpoint, Network};
+
+/// Option naming the host interface macvlan and ipvlan interfaces are
+/// created on.
+pub const PARENT_OPTION: &str = "parent";
+/// Option picking how containers on the same parent reach each other.
+pub const MODE_OPTION: &str = "macvlan_mode";
+
+/// The mode of the `macvlan_mode` option, bridge if unset: containers on
+/// the same parent reach each other directly in bridge mode, only through
+/// an external switch that reflects their traffic in vepa mode and not at
+/// all in private mode.
+pub fn mode(options: &HashMap<String, String>) -> Result<MacVlanMode> {
+    match options.get(MODE_OPTION).map(String::as_str) {
+        None | Some("bridge") => Ok(MacVlanMode::Bridge),
+        Some("private") => Ok(MacVlanMode::Private),
+        Some("vepa") => Ok(MacVlanMode::Vepa),
+        Some(mode) => Err(anyhow!(
+            "Invalid macvlan mode {:?}, expected bridge, private or vepa",
+            mode
+        )),
+    }
+}
+
+/// Returns the name and index of the network's parent interface, which
+/// has to exist in the host's network namespace.
+pub async fn parent_index(handle: &Handle, network: &Network) -> Result<(String, u32)> {
+    let parent = network
+        .options
+        .get(PARENT_OPTION)
+        .ok_or_else(|| anyhow!("Network {} has no parent interface", network.name))?;
+    let index = link_index(handle, parent)
+        .await?
+        .ok_or_else(|| anyhow!("Parent interface {} does not exist", parent))?;
+    Ok((parent.clone(), index))
+}
+
+/// Creates the macvlan interface of `endpoint` on the network's parent
+/// interface right in the network namespace of process `pid`.
+pub async fn attach(handle: Handle, network: Network, endpoint: Endpoint, pid: i32) -> Result<()> {
+    let mode = mode(&network.options)?;
+    let (parent, parent_index) = parent_index(&handle, &network).await?;
+
+    let peer = endpoint.peer_interface();
+    // Left behind by a start that failed half way.
+    delete_link(&handle, &peer).await?;
+    handle
+        .link()
+        .add(
+            LinkMacVlan::new(&peer, parent_index, mode)
+                .setns_by_pid(pid as u32)
+                .build(),
+        )
+        .execute()
+        .await
+        .map_err(|e| anyhow!("Failed to create macvlan on {}: {}", parent, e))?;
+    Ok(())
+}
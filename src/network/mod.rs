@@ -0,0 +1,507 @@
+// src/network/mod.rs
+
+pub mod bridge;
+pub mod dns;
+pub mod etc_files;
+pub mod ipam;
+pub mod iptables;
+pub mod ipvlan;
+pub mod macvlan;
+pub mod nat;
+pub mod netlink;
+pub mod nftables;
+pub mod ports;
+pub mod proxy;
+pub mod slirp;
+pub mod store;
+pub mod tc;
+
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use ipnetwork::{Ipv4Network, Ipv6Network};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use crate::network::ipam::Ipam;
+use crate::network::slirp::RootlessNetwork;
+use crate::runtime::container::Container;
+use crate::runtime::namespace::NamespaceMode;
+
+/// Network containers with a private network namespace are attached to.
+pub const DEFAULT_NETWORK: &str = "bridge";
+/// Network whose containers only get a loopback interface.
+pub const NONE_NETWORK: &str = "none";
+/// Host bridge of the default network.
+pub const DEFAULT_BRIDGE: &str = "nb0";
+/// Subnet of the default network, unless `$NEBULON_SUBNET` names another
+/// one when the network is first set up.
+pub const DEFAULT_SUBNET: &str = "10.88.0.0/16";
+/// The default network only gets an IPv6 subnet if `$NEBULON_SUBNET6`
+/// names one when the network is first set up.
+pub const DEFAULT_SUBNET6_ENV: &str = "NEBULON_SUBNET6";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkDriver {
+    /// Containers are plugged into a host bridge with veth pairs.
+    Bridge,
+    /// Containers get a macvlan interface on a host interface, the `parent`
+    /// option, and appear on its network.
+    Macvlan,
+    /// Like macvlan, with interfaces that share the parent's MAC address.
+    Ipvlan,
+    /// Containers only get a loopback interface.
+    None,
+}
+
+impl FromStr for NetworkDriver {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "bridge" => Ok(NetworkDriver::Bridge),
+            "macvlan" => Ok(NetworkDriver::Macvlan),
+            "ipvlan" => Ok(NetworkDriver::Ipvlan),
+            "none" => Ok(NetworkDriver::None),
+            _ => Err(anyhow!(
+                "Invalid network driver {:?}, expected bridge, macvlan, ipvlan or none",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for NetworkDriver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkDriver::Bridge => write!(f, "bridge"),
+            NetworkDriver::Macvlan => write!(f, "macvlan"),
+            NetworkDriver::Ipvlan => write!(f, "ipvlan"),
+            NetworkDriver::None => write!(f, "none"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Network {
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    pub driver: NetworkDriver,
+    /// Host bridge the containers' veth pairs are plugged into, empty for
+    /// other drivers.
+    #[serde(default)]
+    pub bridge: String,
+    /// Unset for networks of the none driver.
+    pub subnet: Option<Ipv4Network>,
+    pub gateway: Option<Ipv4Addr>,
+    /// Part of the subnet addresses are allocated from, the whole subnet if
+    /// unset. Static addresses may lie outside of it.
+    #[serde(default)]
+    pub ip_range: Option<Ipv4Network>,
+    /// IPv6 subnet of a dual-stack network, containers get an address from
+    /// both.
+    #[serde(default)]
+    pub subnet6: Option<Ipv6Network>,
+    #[serde(default)]
+    pub gateway6: Option<Ipv6Addr>,
+    /// Containers only reach each other, no default route is set up.
+    #[serde(default)]
+    pub internal: bool,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Driver options, like `parent` for macvlan and ipvlan.
+    #[serde(default)]
+    pub options: HashMap<String, String>,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub ipam: Ipam,
+}
+
+/// Settings of a network to create, unset addresses are chosen by the
+/// store.
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    pub name: String,
+    pub driver: NetworkDriver,
+    pub subnet: Option<Ipv4Network>,
+    pub gateway: Option<Ipv4Addr>,
+    pub ip_range: Option<Ipv4Network>,
+    /// Adds an IPv6 subnet, a free /64 of fd89::/48 unless `subnet6` is
+    /// given.
+    pub ipv6: bool,
+    pub subnet6: Option<Ipv6Network>,
+    pub gateway6: Option<Ipv6Addr>,
+    pub internal: bool,
+    pub labels: HashMap<String, String>,
+    pub options: HashMap<String, String>,
+}
+
+/// A network a container is connected to, with the address it asked for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetworkAttachment {
+    pub network: String,
+    /// Static address, allocated from the network's range if unset.
+    #[serde(default)]
+    pub ip: Option<Ipv4Addr>,
+    /// Static IPv6 address on a dual-stack network.
+    #[serde(default)]
+    pub ip6: Option<Ipv6Addr>,
+    /// Further names the container is found by on the network.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+}
+
+/// Parses `NAME[:IP]`, with an IPv4 or IPv6 address.
+impl FromStr for NetworkAttachment {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (network, ip) = match value.split_once(':') {
+            Some((network, ip)) => {
+                let ip = ip
+                    .parse::<IpAddr>()
+                    .map_err(|e| anyhow!("Invalid address in {:?}: {}", value, e))?;
+                (network, Some(ip))
+            }
+            None => (value, None),
+        };
+        if network.is_empty() {
+            return Err(anyhow!("Missing network name in {:?}", value));
+        }
+        Ok(NetworkAttachment {
+            network: network.to_string(),
+            ip: ip.and_then(|ip| match ip {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            }),
+            ip6: ip.and_then(|ip| match ip {
+                IpAddr::V4(_) => None,
+                IpAddr::V6(ip) => Some(ip),
+            }),
+            aliases: Vec::new(),
+        })
+    }
+}
+
+/// Value of `--network`: a namespace mode like the other namespace flags,
+/// a userspace network stack for a private namespace, or a network to
+/// connect to.
+#[derive(Debug, Clone)]
+pub enum NetworkMode {
+    Namespace(NamespaceMode),
+    Rootless(RootlessNetwork),
+    Network(NetworkAttachment),
+}
+
+impl FromStr for NetworkMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        if value == "private"
+            || value == "host"
+            || value.starts_with("container:")
+            || value.starts_with('/')
+        {
+            return Ok(NetworkMode::Namespace(value.parse()?));
+        }
+        if let Ok(rootless) = value.parse() {
+            return Ok(NetworkMode::Rootless(rootless));
+        }
+        Ok(NetworkMode::Network(value.parse()?))
+    }
+}
+
+/// A container's interface on a network. The address is assigned when the
+/// container is connected and kept until it is disconnected or removed,
+/// the interfaces are created every time it starts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Endpoint {
+    pub network: String,
+    /// Interface name inside the container, `eth0` for the first network.
+    pub interface: String,
+    /// Host end of the veth pair.
+    pub host_interface: String,
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Ipv4Addr,
+    /// Set on dual-stack networks.
+    #[serde(default)]
+    pub address6: Option<Ipv6Addr>,
+    #[serde(default)]
+    pub prefix_len6: u8,
+    #[serde(default)]
+    pub gateway6: Option<Ipv6Addr>,
+}
+
+impl Endpoint {
+    /// Name of the container's interface while it is still in the host's
+    /// namespace.
+    pub fn peer_interface(&self) -> String {
+        format!("c{}", &self.host_interface[1..])
+    }
+
+    /// The endpoint's addresses, IPv4 first.
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        let mut addresses = vec![IpAddr::V4(self.address)];
+        addresses.extend(self.address6.map(IpAddr::V6));
+        addresses
+    }
+}
+
+/// Allocates an address for a container on the attachment's network and
+/// returns the endpoint to create when it starts, none for networks
+/// without interfaces. `index` numbers the container's interfaces.
+pub fn connect(
+    container_id: &str,
+    attachment: &NetworkAttachment,
+    index: usize,
+) -> Result<Option<Endpoint>> {
+    // Interface names are limited to 15 characters, the random part of
+    // the id is enough to tell containers apart.
+    let short_id = container_id.rsplit('-').next().unwrap_or(container_id);
+    let short_id = &short_id[..short_id.len().min(8)];
+
+    let Some((network, address, address6)) = store::allocate(
+        &attachment.network,
+        container_id,
+        attachment.ip,
+        attachment.ip6,
+    )?
+    else {
+        return Ok(None);
+    };
+    let (Some(subnet), Some(gateway)) = (network.subnet, network.gateway) else {
+        return Err(anyhow!("Network {} has no subnet", network.name));
+    };
+    Ok(Some(Endpoint {
+        network: network.name,
+        interface: format!("eth{}", index),
+        host_interface: format!("veth{}{}", short_id, index),
+        address,
+        prefix_len: subnet.prefix(),
+        gateway,
+        address6,
+        prefix_len6: network.subnet6.map_or(0, |subnet| subnet.prefix()),
+        gateway6: network.gateway6,
+    }))
+}
+
+/// Allocates the endpoints of a new container, `attachments` lists its
+/// networks in the order of its interfaces.
+pub fn connect_all(container_id: &str, attachments: &[NetworkAttachment]) -> Result<Vec<Endpoint>> {
+    let mut endpoints = Vec::new();
+    for attachment in attachments {
+        match connect(container_id, attachment, endpoints.len()) {
+            Ok(Some(endpoint)) => endpoints.push(endpoint),
+            Ok(None) => {}
+            Err(e) => {
+                let _ = disconnect_all(container_id);
+                return Err(e);
+            }
+        }
+    }
+    Ok(endpoints)
+}
+
+/// Checks that a container may join `attachments`, each network once and
+/// the none network only on its own, and replaces network ids by names.
+/// Returns the networks in the same order.
+pub fn resolve_attachments(attachments: &mut [NetworkAttachment]) -> Result<Vec<Network>> {
+    let networks = attachments
+        .iter()
+        .map(|attachment| store::get_network(&attachment.network))
+        .collect::<Result<Vec<_>>>()?;
+
+    for (i, (attachment, network)) in attachments.iter_mut().zip(&networks).enumerate() {
+        if networks[..i].iter().any(|other| other.id == network.id) {
+            return Err(anyhow!("Network {} is given twice", network.name));
+        }
+        if network.driver == NetworkDriver::None {
+            if networks.len() > 1 {
+                return Err(anyhow!(
+                    "Network {} cannot be combined with other networks",
+                    network.name
+                ));
+            }
+            if attachment.ip.is_some() || attachment.ip6.is_some() {
+                return Err(anyhow!("Network {} has no addresses", network.name));
+            }
+        }
+        if attachment.ip6.is_some() && network.subnet6.is_none() {
+            return Err(anyhow!("Network {} has no IPv6 subnet", network.name));
+        }
+        attachment.network = network.name.clone();
+    }
+    Ok(networks)
+}
+
+/// Releases the address a container holds on `network`.
+pub fn disconnect(container_id: &str, network: &str) -> Result<()> {
+    store::release(container_id, Some(network))
+}
+
+/// Releases every address held by a container.
+pub fn disconnect_all(container_id: &str) -> Result<()> {
+    store::release(container_id, None)
+}
+
+/// Creates a network after checking the options of its driver, the parent
+/// interface of macvlan and ipvlan networks has to exist.
+pub fn create(config: NetworkConfig) -> Result<Network> {
+    let parented = match config.driver {
+        NetworkDriver::Macvlan => macvlan::mode(&config.options).map(|_| true),
+        NetworkDriver::Ipvlan => ipvlan::mode(&config.options).map(|_| true),
+        NetworkDriver::Bridge | NetworkDriver::None => Ok(false),
+    }?;
+    if parented && let Some(parent) = config.options.get(macvlan::PARENT_OPTION) {
+        let name = parent.clone();
+        let index =
+            netlink::run(|handle| async move { netlink::link_index(&handle, &name).await })?;
+        if index.is_none() {
+            return Err(anyhow!("Parent interface {} does not exist", parent));
+        }
+    }
+    let network = store::create_network(config)?;
+    log::info!("Created network {}", network.name);
+    Ok(network)
+}
+
+/// Removes a network no container is connected to, along with its bridge.
+pub fn remove(reference: &str) -> Result<Network> {
+    let network = store::remove_network(reference)?;
+    if network.driver == NetworkDriver::Bridge {
+        let bridge = network.bridge.clone();
+        if let Err(e) =
+            netlink::run(|handle| async move { netlink::delete_link(&handle, &bridge).await })
+        {
+            log::warn!("Failed to remove bridge {}: {}", network.bridge, e);
+        }
+    }
+    if let Err(e) = nat::sync() {
+        log::warn!("Failed to update NAT rules: {}", e);
+    }
+    log::info!("Removed network {}", network.name);
+    Ok(network)
+}
+
+/// Default routes of a container, through the first endpoint on a network
+/// that is not internal for each address family.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultRoutes {
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+/// Wires up the fresh network namespace of a container's workload `pid`:
+/// brings up loopback and creates the interfaces of its endpoints.
+pub fn setup(container: &Container, pid: i32) -> Result<()> {
+    let mut routes = DefaultRoutes::default();
+    for endpoint in &container.networks {
+        let network = attach(endpoint, pid)?;
+        limit(container, endpoint, &network)?;
+        if !network.internal {
+            routes.ipv4 = routes.ipv4.or(Some(endpoint.gateway));
+            routes.ipv6 = routes.ipv6.or(endpoint.gateway6);
+        }
+    }
+
+    let endpoints = container.networks.clone();
+    netlink::run_in_netns(pid, move |handle| {
+        netlink::configure(handle, endpoints, routes)
+    })
+    .map_err(|e| anyhow!("Failed to configure container network: {}", e))
+}
+
+/// Adds the interface of `endpoint` to a running container with workload
+/// `pid`. It provides the default route of each address family none of
+/// the container's other endpoints provides.
+pub fn setup_endpoint(container: &Container, endpoint: &Endpoint, pid: i32) -> Result<()> {
+    let network = attach(endpoint, pid)?;
+    limit(container, endpoint, &network)?;
+    let routed: Vec<&Endpoint> = container
+        .networks
+        .iter()
+        .filter(|other| store::get_network(&other.network).is_ok_and(|network| !network.internal))
+        .collect();
+    let mut routes = DefaultRoutes::default();
+    if !network.internal {
+        if routed.is_empty() {
+            routes.ipv4 = Some(endpoint.gateway);
+        }
+        if routed.iter().all(|other| other.gateway6.is_none()) {
+            routes.ipv6 = endpoint.gateway6;
+        }
+    }
+
+    let endpoints = vec![endpoint.clone()];
+    netlink::run_in_netns(pid, move |handle| {
+        netlink::configure(handle, endpoints, routes)
+    })
+    .map_err(|e| anyhow!("Failed to configure container network: {}", e))
+}
+
+/// Removes the interface of `endpoint` from a running container with
+/// workload `pid`.
+pub fn teardown_endpoint(endpoint: &Endpoint, pid: i32) -> Result<()> {
+    let interface = endpoint.interface.clone();
+    netlink::run_in_netns(pid, move |handle| async move {
+        netlink::delete_link(&handle, &interface).await
+    })
+    .map_err(|e| anyhow!("Failed to remove {}: {}", endpoint.interface, e))
+}
+
+/// Applies the container's bandwidth limits to the host end of the veth
+/// pair of `endpoint`, interfaces of other drivers have no host end.
+fn limit(container: &Container, endpoint: &Endpoint, network: &Network) -> Result<()> {
+    let limits = container.config.bandwidth;
+    if limits.is_empty() {
+        return Ok(());
+    }
+    if network.driver != NetworkDriver::Bridge {
+        log::warn!(
+            "Bandwidth limits do not apply on network {} of the {} driver",
+            network.name,
+            network.driver
+        );
+        return Ok(());
+    }
+    let interface = endpoint.host_interface.clone();
+    netlink::run(|handle| async move {
+        let index = netlink::link_index(&handle, &interface)
+            .await?
+            .ok_or_else(|| anyhow!("Interface {} vanished", interface))?;
+        tc::limit(handle, index, limits).await
+    })
+    .map_err(|e| {
+        anyhow!(
+            "Failed to limit bandwidth on network {}: {}",
+            endpoint.network,
+            e
+        )
+    })
+}
+
+/// Creates the interface of `endpoint` and moves it into the network
+/// namespace of `pid`, returns its network.
+fn attach(endpoint: &Endpoint, pid: i32) -> Result<Network> {
+    let network = store::get_network(&endpoint.network)?;
+    let (task_network, task_endpoint) = (network.clone(), endpoint.clone());
+    match network.driver {
+        NetworkDriver::Bridge => {
+            netlink::run(|handle| bridge::attach(handle, task_network, task_endpoint, pid))
+        }
+        NetworkDriver::Macvlan => {
+            netlink::run(|handle| macvlan::attach(handle, task_network, task_endpoint, pid))
+        }
+        NetworkDriver::Ipvlan => {
+            netlink::run(|handle| ipvlan::attach(handle, task_network, task_endpoint, pid))
+        }
+        NetworkDriver::None => Ok(()),
+    }
+    .map_err(|e| anyhow!("Failed to connect to network {}: {}", endpoint.network, e))?;
+    Ok(network)
+}
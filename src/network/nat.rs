@@ -0,0 +1,165 @@
+// src/network/nat.rs
+use anyhow::{Result, anyhow};
+use ipnetwork::IpNetwork;
+use nix::fcntl::{Flock, FlockArg};
+use std::fs::{self, File, OpenOptions};
+use std::net::IpAddr;
+
+use crate::PROGRAM_ROOT;
+use crate::network::ports::{self, PublishedPort};
+use crate::network::proxy::{self, ProxyMode};
+use crate::network::{NetworkDriver, iptables, nftables, store};
+use crate::runtime::container::Container;
+use crate::storage::storage::{get_containers, modify_container};
+
+/// Everything the runtime needs from the host's firewall.
+pub struct NatRules {
+    /// Ports of running containers forwarded from the host, except those
+    /// left to the userland proxy.
+    pub ports: Vec<PublishedPort>,
+    /// Subnets of bridge networks of both families, with their bridge,
+    /// whose traffic to elsewhere leaves with the host's address.
+    pub masquerade: Vec<(IpNetwork, String)>,
+}
+
+/// Held while the rules are worked out and programmed, so that they are
+/// not programmed from stale container records.
+fn lock() -> Result<Flock<File>> {
+    fs::create_dir_all(&*PROGRAM_ROOT)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(PROGRAM_ROOT.join("nat.lock"))?;
+    Flock::lock(file, FlockArg::LockExclusive)
+        .map_err(|(_, e)| anyhow!("Failed to lock NAT rules: {}", e))
+}
+
+fn rules() -> Result<NatRules> {
+    let ports = get_containers()?
+        .into_iter()
+        .flat_map(|c| c.ports)
+        .filter(|p| !p.proxied)
+        .collect();
+    let masquerade = store::get_networks()?
+        .into_iter()
+        .filter(|n| n.driver == NetworkDriver::Bridge && !n.internal)
+        .flat_map(|n| {
+            let subnets = n.subnet.map(IpNetwork::V4).into_iter();
+            let subnets = subnets.chain(n.subnet6.map(IpNetwork::V6));
+            subnets.map(move |subnet| (subnet, n.bridge.clone()))
+        })
+        .collect();
+    Ok(NatRules { ports, masquerade })
+}
+
+/// Programs the rules with nftables, or iptables where nf_tables is not
+/// available.
+fn apply(rules: &NatRules) -> Result<()> {
+    let Err(nft) = nftables::apply(rules) else {
+        return Ok(());
+    };
+    log::debug!("Falling back to iptables: {}", nft);
+    iptables::apply(rules).map_err(|e| {
+        anyhow!(
+            "Failed to program NAT rules with nftables ({}) or iptables ({})",
+            nft,
+            e
+        )
+    })
+}
+
+/// Programs the rules for the containers' current ports and networks.
+pub fn sync() -> Result<()> {
+    let _lock = lock()?;
+    apply(&rules()?)
+}
+
+/// Fails if another container published one of `ports`.
+fn check_conflicts(container: &Container, ports: &[PublishedPort]) -> Result<()> {
+    for other in get_containers()?.iter().filter(|c| c.id != container.id) {
+        if let Some(port) = ports
+            .iter()
+            .find(|p| other.ports.iter().any(|o| o.conflicts(p)))
+        {
+            return Err(anyhow!(
+                "Host port {}/{} is already published by {}",
+                port.host_port,
+                port.protocol,
+                other.id
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Publishes the configured ports of a container that just started on the
+/// addresses of its first routed endpoint, and masquerades its traffic.
+/// Returns the ports the userland proxy has to forward, per the proxy mode
+/// either all of them or those the NAT rules could not be programmed for.
+/// Firewall failures only fail containers that publish ports.
+pub fn publish(container: &Container) -> Result<Vec<PublishedPort>> {
+    let _lock = lock()?;
+    let mode = proxy::mode();
+    let mut ports: Vec<PublishedPort> = Vec::new();
+    if !container.config.ports.is_empty() {
+        let endpoint = container
+            .networks
+            .iter()
+            .find(|e| store::get_network(&e.network).is_ok_and(|n| !n.internal))
+            .ok_or_else(|| {
+                anyhow!("Ports can only be published on a network that is not internal")
+            })?;
+        ports = ports::publish_on(&container.config.ports, &endpoint.addresses())?;
+        ports
+            .iter_mut()
+            .for_each(|p| p.proxied = mode == ProxyMode::Always);
+        check_conflicts(container, &ports)?;
+    }
+    modify_container(&container.id, |c| c.ports = ports.clone())?;
+
+    if let Err(e) = apply(&rules()?) {
+        if ports.iter().all(|p| p.proxied) {
+            log::warn!("{}", e);
+        } else if mode == ProxyMode::Auto {
+            log::warn!("{}, ports of {} are proxied", e, container.id);
+            ports.iter_mut().for_each(|p| p.proxied = true);
+            modify_container(&container.id, |c| c.ports = ports.clone())?;
+            // Masquerading may still work without the ports.
+            if let Err(e) = apply(&rules()?) {
+                log::debug!("{}", e);
+            }
+        } else {
+            modify_container(&container.id, |c| c.ports.clear())?;
+            return Err(e);
+        }
+    }
+    Ok(ports.into_iter().filter(|p| p.proxied).collect())
+}
+
+/// Records the configured ports of a container connected through a
+/// rootless network stack, which forwards them to `address` itself.
+pub fn reserve(container: &Container, address: IpAddr) -> Result<Vec<PublishedPort>> {
+    let _lock = lock()?;
+    let mut ports = ports::publish_on(&container.config.ports, &[address])?;
+    ports.iter_mut().for_each(|p| p.proxied = true);
+    check_conflicts(container, &ports)?;
+    modify_container(&container.id, |c| c.ports = ports.clone())?;
+    Ok(ports)
+}
+
+/// Withdraws the ports of a container that stopped.
+pub fn unpublish(container_id: &str) -> Result<()> {
+    let _lock = lock()?;
+    // Proxied ports are closed by the supervisor, only NAT rules are left
+    // to remove.
+    let mut forwarded = false;
+    modify_container(container_id, |c| {
+        forwarded = c.ports.iter().any(|p| !p.proxied);
+        c.ports.clear();
+    })?;
+    if forwarded {
+        apply(&rules()?)?;
+    }
+    Ok(())
+}
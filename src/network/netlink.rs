@@ -0,0 +1,176 @@
+// src/network/netlink.rs
+use anyhow::{Result, anyhow};
+use futures::future::{Either, select};
+use futures::{TryStreamExt, pin_mut};
+use nix::sched::{CloneFlags, setns};
+use rtnetlink::sys::SmolSocket;
+use rtnetlink::{Handle, LinkUnspec, RouteMessageBuilder, new_connection_with_socket};
+use std::fs::{File, write};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::thread;
+
+use crate::network::{DefaultRoutes, Endpoint};
+
+/// Opens an rtnetlink connection in the calling thread's network namespace
+/// and runs `task` with it until it completes.
+pub fn run<F, Fut, T>(task: F) -> Result<T>
+where
+    F: FnOnce(Handle) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let (connection, handle, _) = new_connection_with_socket::<SmolSocket>()
+        .map_err(|e| anyhow!("Failed to open netlink connection: {}", e))?;
+
+    async_std::task::block_on(async move {
+        let task = task(handle);
+        pin_mut!(task, connection);
+        match select(task, connection).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(anyhow!("Netlink connection closed")),
+        }
+    })
+}
+
+/// Like `run`, inside the network namespace of process `pid`. The
+/// namespace is joined by a thread of its own so that the caller stays in
+/// the host's.
+pub fn run_in_netns<F, Fut, T>(pid: i32, task: F) -> Result<T>
+where
+    F: FnOnce(Handle) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<T>>,
+    T: Send + 'static,
+{
+    let path = format!("/proc/{}/ns/net", pid);
+    let netns = File::open(&path).map_err(|e| anyhow!("Failed to open {}: {}", path, e))?;
+
+    thread::spawn(move || {
+        setns(&netns, CloneFlags::CLONE_NEWNET)
+            .map_err(|e| anyhow!("Failed to join network namespace: {}", e))?;
+        run(task)
+    })
+    .join()
+    .map_err(|_| anyhow!("Network setup thread panicked"))?
+}
+
+/// Returns the index of the interface called `name`, if there is one.
+pub async fn link_index(handle: &Handle, name: &str) -> Result<Option<u32>> {
+    let mut links = handle.link().get().match_name(name.to_string()).execute();
+    match links.try_next().await {
+        Ok(link) => Ok(link.map(|link| link.header.index)),
+        // The kernel answers ENODEV for names it does not know.
+        Err(rtnetlink::Error::NetlinkError(e)) if e.raw_code() == -libc::ENODEV => Ok(None),
+        Err(e) => Err(anyhow!("Failed to look up interface {}: {}", name, e)),
+    }
+}
+
+pub async fn set_up(handle: &Handle, index: u32) -> Result<()> {
+    handle
+        .link()
+        .set(LinkUnspec::new_with_index(index).up().build())
+        .execute()
+        .await
+        .map_err(|e| anyhow!("Failed to bring up interface {}: {}", index, e))
+}
+
+/// Deletes the interface called `name`, if there is one.
+pub async fn delete_link(handle: &Handle, name: &str) -> Result<()> {
+    if let Some(index) = link_index(handle, name).await? {
+        handle
+            .link()
+            .del(index)
+            .execute()
+            .await
+            .map_err(|e| anyhow!("Failed to delete interface {}: {}", name, e))?;
+    }
+    Ok(())
+}
+
+/// Sets an IPv6 sysctl of interface `name` of the calling thread's network
+/// namespace.
+fn set_ipv6_conf(name: &str, key: &str, value: &str) -> Result<()> {
+    write(format!("/proc/sys/net/ipv6/conf/{}/{}", name, key), value)
+        .map_err(|e| anyhow!("Failed to set {} of {}: {}", key, name, e))
+}
+
+/// Run inside the container's network namespace: brings up loopback and
+/// names, addresses and brings up the interfaces moved in by the drivers,
+/// then routes everything else via `routes`.
+pub async fn configure(
+    handle: Handle,
+    endpoints: Vec<Endpoint>,
+    routes: DefaultRoutes,
+) -> Result<()> {
+    let lo = link_index(&handle, "lo")
+        .await?
+        .ok_or_else(|| anyhow!("Container has no loopback interface"))?;
+    set_up(&handle, lo).await?;
+
+    for endpoint in &endpoints {
+        let peer = endpoint.peer_interface();
+        let index = link_index(&handle, &peer)
+            .await?
+            .ok_or_else(|| anyhow!("Interface {} is missing in the container", peer))?;
+
+        handle
+            .link()
+            .set(
+                LinkUnspec::new_with_index(index)
+                    .name(endpoint.interface.clone())
+                    .build(),
+            )
+            .execute()
+            .await
+            .map_err(|e| anyhow!("Failed to rename {}: {}", peer, e))?;
+        // Addresses and routes are the runtime's, routers on the network
+        // must not add others. Addresses are unique within the network, so
+        // they are usable right away instead of after duplicate detection.
+        // Only IPv6 endpoints need these, the host may have IPv6 disabled.
+        for key in ["accept_ra", "accept_dad"] {
+            let result = set_ipv6_conf(&endpoint.interface, key, "0");
+            if endpoint.address6.is_some() {
+                result?;
+            }
+        }
+
+        let mut addresses = vec![(IpAddr::V4(endpoint.address), endpoint.prefix_len)];
+        if let Some(address6) = endpoint.address6 {
+            addresses.push((IpAddr::V6(address6), endpoint.prefix_len6));
+        }
+        for (address, prefix_len) in addresses {
+            handle
+                .address()
+                .add(index, address, prefix_len)
+                .execute()
+                .await
+                .map_err(|e| anyhow!("Failed to assign {}: {}", address, e))?;
+        }
+        set_up(&handle, index).await?;
+    }
+
+    if let Some(gateway) = routes.ipv4 {
+        handle
+            .route()
+            .add(
+                RouteMessageBuilder::<Ipv4Addr>::new()
+                    .gateway(gateway)
+                    .build(),
+            )
+            .execute()
+            .await
+            .map_err(|e| anyhow!("Failed to add default route via {}: {}", gateway, e))?;
+    }
+    if let Some(gateway) = routes.ipv6 {
+        handle
+            .route()
+            .add(
+                RouteMessageBuilder::<Ipv6Addr>::new()
+                    .gateway(gateway)
+                    .build(),
+            )
+            .execute()
+            .await
+            .map_err(|e| anyhow!("Failed to add default route via {}: {}", gateway, e))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,496 @@
+// src/network/nftables.rs
+use anyhow::{Result, anyhow};
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::{FromRawFd, OwnedFd};
+
+use crate::network::nat::NatRules;
+use crate::network::ports::PublishedPort;
+
+/// Table holding every rule of the runtime, replaced as a whole.
+pub const TABLE: &str = "nebulon";
+
+// From linux/netfilter/nfnetlink.h and linux/netfilter/nf_tables.h, libc
+// only has part of them.
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_DELTABLE: u16 = 2;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_NEWRULE: u16 = 6;
+
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_POLICY: u16 = 5;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_DATA_VALUE: u16 = 1;
+
+const NFTA_META_DREG: u16 = 1;
+const NFTA_META_KEY: u16 = 2;
+const NFTA_PAYLOAD_DREG: u16 = 1;
+const NFTA_PAYLOAD_BASE: u16 = 2;
+const NFTA_PAYLOAD_OFFSET: u16 = 3;
+const NFTA_PAYLOAD_LEN: u16 = 4;
+const NFTA_CMP_SREG: u16 = 1;
+const NFTA_CMP_OP: u16 = 2;
+const NFTA_CMP_DATA: u16 = 3;
+const NFTA_BITWISE_SREG: u16 = 1;
+const NFTA_BITWISE_DREG: u16 = 2;
+const NFTA_BITWISE_LEN: u16 = 3;
+const NFTA_BITWISE_MASK: u16 = 4;
+const NFTA_BITWISE_XOR: u16 = 5;
+const NFTA_IMMEDIATE_DREG: u16 = 1;
+const NFTA_IMMEDIATE_DATA: u16 = 2;
+const NFTA_NAT_TYPE: u16 = 1;
+const NFTA_NAT_FAMILY: u16 = 2;
+const NFTA_NAT_REG_ADDR_MIN: u16 = 3;
+const NFTA_NAT_REG_PROTO_MIN: u16 = 5;
+const NFTA_FIB_DREG: u16 = 1;
+const NFTA_FIB_RESULT: u16 = 2;
+const NFTA_FIB_FLAGS: u16 = 3;
+
+const NFT_REG_1: u32 = 1;
+const NFT_REG_2: u32 = 2;
+const NFT_CMP_EQ: u32 = 0;
+const NFT_CMP_NEQ: u32 = 1;
+const NFT_META_OIFNAME: u32 = 7;
+const NFT_META_L4PROTO: u32 = 16;
+const NFT_PAYLOAD_NETWORK_HEADER: u32 = 1;
+const NFT_PAYLOAD_TRANSPORT_HEADER: u32 = 2;
+const NFT_NAT_DNAT: u32 = 1;
+const NFT_FIB_RESULT_ADDRTYPE: u32 = 3;
+const NFTA_FIB_F_DADDR: u32 = 1 << 1;
+const RTN_LOCAL: u32 = 2;
+
+const NF_INET_PRE_ROUTING: u32 = 0;
+const NF_INET_LOCAL_OUT: u32 = 3;
+const NF_INET_POST_ROUTING: u32 = 4;
+const NF_IP_PRI_NAT_DST: i32 = -100;
+const NF_IP_PRI_NAT_SRC: i32 = 100;
+const NF_ACCEPT: u32 = 1;
+
+/// Netlink attributes, encoded as they are added.
+#[derive(Default)]
+struct Attributes(Vec<u8>);
+
+impl Attributes {
+    fn bytes(mut self, kind: u16, data: &[u8]) -> Self {
+        self.0
+            .extend_from_slice(&((4 + data.len()) as u16).to_ne_bytes());
+        self.0.extend_from_slice(&kind.to_ne_bytes());
+        self.0.extend_from_slice(data);
+        self.0.resize(self.0.len().next_multiple_of(4), 0);
+        self
+    }
+
+    fn string(self, kind: u16, value: &str) -> Self {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.bytes(kind, &data)
+    }
+
+    /// Numbers in attributes are big endian.
+    fn u32(self, kind: u16, value: u32) -> Self {
+        self.bytes(kind, &value.to_be_bytes())
+    }
+
+    fn nested(self, kind: u16, inner: Attributes) -> Self {
+        self.bytes(kind | libc::NLA_F_NESTED as u16, &inner.0)
+    }
+
+    fn value(self, kind: u16, data: &[u8]) -> Self {
+        self.nested(kind, Attributes::default().bytes(NFTA_DATA_VALUE, data))
+    }
+}
+
+fn expression(name: &str, data: Attributes) -> Attributes {
+    Attributes::default()
+        .string(NFTA_EXPR_NAME, name)
+        .nested(NFTA_EXPR_DATA, data)
+}
+
+fn meta(key: u32, register: u32) -> Attributes {
+    expression(
+        "meta",
+        Attributes::default()
+            .u32(NFTA_META_DREG, register)
+            .u32(NFTA_META_KEY, key),
+    )
+}
+
+fn payload(base: u32, offset: u32, len: u32, register: u32) -> Attributes {
+    expression(
+        "payload",
+        Attributes::default()
+            .u32(NFTA_PAYLOAD_DREG, register)
+            .u32(NFTA_PAYLOAD_BASE, base)
+            .u32(NFTA_PAYLOAD_OFFSET, offset)
+            .u32(NFTA_PAYLOAD_LEN, len),
+    )
+}
+
+fn cmp(op: u32, register: u32, data: &[u8]) -> Attributes {
+    expression(
+        "cmp",
+        Attributes::default()
+            .u32(NFTA_CMP_SREG, register)
+            .u32(NFTA_CMP_OP, op)
+            .value(NFTA_CMP_DATA, data),
+    )
+}
+
+fn mask(register: u32, mask: &[u8]) -> Attributes {
+    expression(
+        "bitwise",
+        Attributes::default()
+            .u32(NFTA_BITWISE_SREG, register)
+            .u32(NFTA_BITWISE_DREG, register)
+            .u32(NFTA_BITWISE_LEN, mask.len() as u32)
+            .value(NFTA_BITWISE_MASK, mask)
+            .value(NFTA_BITWISE_XOR, &vec![0; mask.len()]),
+    )
+}
+
+fn immediate(register: u32, data: &[u8]) -> Attributes {
+    expression(
+        "immediate",
+        Attributes::default()
+            .u32(NFTA_IMMEDIATE_DREG, register)
+            .value(NFTA_IMMEDIATE_DATA, data),
+    )
+}
+
+/// Matches packets addressed to one of the host's own addresses.
+fn daddr_is_local(register: u32) -> Vec<Attributes> {
+    vec![
+        expression(
+            "fib",
+            Attributes::default()
+                .u32(NFTA_FIB_DREG, register)
+                .u32(NFTA_FIB_RESULT, NFT_FIB_RESULT_ADDRTYPE)
+                .u32(NFTA_FIB_FLAGS, NFTA_FIB_F_DADDR),
+        ),
+        // The address type is stored in host byte order.
+        cmp(NFT_CMP_EQ, register, &RTN_LOCAL.to_ne_bytes()),
+    ]
+}
+
+fn octets(address: IpAddr) -> Vec<u8> {
+    match address {
+        IpAddr::V4(address) => address.octets().to_vec(),
+        IpAddr::V6(address) => address.octets().to_vec(),
+    }
+}
+
+/// Protocol family of rules for addresses like `address`.
+fn family(address: IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => libc::NFPROTO_IPV4 as u8,
+        IpAddr::V6(_) => libc::NFPROTO_IPV6 as u8,
+    }
+}
+
+/// Matches the masked source or destination address of packets against
+/// `network`. They are at offsets 12 and 16 of the IPv4 header and 8 and
+/// 24 of the IPv6 one.
+fn address(op: u32, destination: bool, network: IpNetwork) -> Vec<Attributes> {
+    let offset = match (network, destination) {
+        (IpNetwork::V4(_), false) => 12,
+        (IpNetwork::V4(_), true) => 16,
+        (IpNetwork::V6(_), false) => 8,
+        (IpNetwork::V6(_), true) => 24,
+    };
+    let address = octets(network.network());
+    let len = address.len() as u32;
+    let mut exprs = vec![payload(NFT_PAYLOAD_NETWORK_HEADER, offset, len, NFT_REG_1)];
+    if (network.prefix() as u32) < len * 8 {
+        exprs.push(mask(NFT_REG_1, &octets(network.mask())));
+    }
+    exprs.push(cmp(op, NFT_REG_1, &address));
+    exprs
+}
+
+/// DNAT of a published port. Connections made from the host itself skip
+/// loopback addresses, which cannot be routed to a container.
+fn dnat(port: &PublishedPort, from_host: bool) -> Vec<Attributes> {
+    let mut exprs = Vec::new();
+    if port.host_ip.is_unspecified() {
+        exprs.extend(daddr_is_local(NFT_REG_1));
+    } else {
+        exprs.extend(address(NFT_CMP_EQ, true, IpNetwork::from(port.host_ip)));
+    }
+    if from_host {
+        let loopback = match port.host_ip {
+            IpAddr::V4(_) => Ipv4Network::new(Ipv4Addr::new(127, 0, 0, 0), 8)
+                .unwrap()
+                .into(),
+            IpAddr::V6(_) => Ipv6Network::from(Ipv6Addr::LOCALHOST).into(),
+        };
+        exprs.extend(address(NFT_CMP_NEQ, true, loopback));
+    }
+    exprs.push(meta(NFT_META_L4PROTO, NFT_REG_1));
+    exprs.push(cmp(NFT_CMP_EQ, NFT_REG_1, &[port.protocol.number()]));
+    // The destination port is at the same offset for TCP and UDP.
+    exprs.push(payload(NFT_PAYLOAD_TRANSPORT_HEADER, 2, 2, NFT_REG_1));
+    exprs.push(cmp(NFT_CMP_EQ, NFT_REG_1, &port.host_port.to_be_bytes()));
+    exprs.push(immediate(NFT_REG_1, &octets(port.container_ip)));
+    exprs.push(immediate(NFT_REG_2, &port.container_port.to_be_bytes()));
+    exprs.push(expression(
+        "nat",
+        Attributes::default()
+            .u32(NFTA_NAT_TYPE, NFT_NAT_DNAT)
+            .u32(NFTA_NAT_FAMILY, family(port.container_ip) as u32)
+            .u32(NFTA_NAT_REG_ADDR_MIN, NFT_REG_1)
+            .u32(NFTA_NAT_REG_PROTO_MIN, NFT_REG_2),
+    ));
+    exprs
+}
+
+/// Masquerade of traffic leaving a subnet other than through its bridge.
+fn masquerade(subnet: IpNetwork, bridge: &str) -> Vec<Attributes> {
+    let mut name = [0u8; libc::IFNAMSIZ];
+    name[..bridge.len()].copy_from_slice(bridge.as_bytes());
+
+    let mut exprs = address(NFT_CMP_EQ, false, subnet);
+    exprs.push(meta(NFT_META_OIFNAME, NFT_REG_1));
+    exprs.push(cmp(NFT_CMP_NEQ, NFT_REG_1, &name));
+    exprs.push(expression("masq", Attributes::default()));
+    exprs
+}
+
+/// A batch of nf_tables messages for the tables of one protocol family,
+/// which the kernel applies as a single transaction.
+struct Batch {
+    buf: Vec<u8>,
+    seq: u32,
+    messages: u32,
+    family: u8,
+}
+
+impl Batch {
+    fn new(family: u8) -> Self {
+        let mut batch = Batch {
+            buf: Vec::new(),
+            seq: 0,
+            messages: 0,
+            family,
+        };
+        batch.message(
+            NFNL_MSG_BATCH_BEGIN,
+            0,
+            libc::AF_UNSPEC as u8,
+            Attributes::default(),
+        );
+        batch
+    }
+
+    fn message(&mut self, kind: u16, flags: u16, family: u8, attributes: Attributes) {
+        let batch = kind == NFNL_MSG_BATCH_BEGIN || kind == NFNL_MSG_BATCH_END;
+        let (kind, flags, res_id) = if batch {
+            (kind, libc::NLM_F_REQUEST as u16, NFNL_SUBSYS_NFTABLES)
+        } else {
+            self.messages += 1;
+            (
+                (NFNL_SUBSYS_NFTABLES << 8) | kind,
+                (libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16 | flags,
+                0,
+            )
+        };
+        self.seq += 1;
+
+        let len = 16 + 4 + attributes.0.len();
+        self.buf.extend_from_slice(&(len as u32).to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.buf.extend_from_slice(&flags.to_ne_bytes());
+        self.buf.extend_from_slice(&self.seq.to_ne_bytes());
+        self.buf.extend_from_slice(&0u32.to_ne_bytes());
+        // nfgenmsg: family, version and resource id.
+        self.buf.push(family);
+        self.buf.push(0);
+        self.buf.extend_from_slice(&res_id.to_be_bytes());
+        self.buf.extend_from_slice(&attributes.0);
+    }
+
+    fn table(&mut self, kind: u16) {
+        let attributes = Attributes::default().string(NFTA_TABLE_NAME, TABLE);
+        let flags = if kind == NFT_MSG_NEWTABLE {
+            libc::NLM_F_CREATE as u16
+        } else {
+            0
+        };
+        self.message(kind, flags, self.family, attributes);
+    }
+
+    fn nat_chain(&mut self, name: &str, hook: u32, priority: i32) {
+        let attributes = Attributes::default()
+            .string(NFTA_CHAIN_TABLE, TABLE)
+            .string(NFTA_CHAIN_NAME, name)
+            .nested(
+                NFTA_CHAIN_HOOK,
+                Attributes::default()
+                    .u32(NFTA_HOOK_HOOKNUM, hook)
+                    .u32(NFTA_HOOK_PRIORITY, priority as u32),
+            )
+            .u32(NFTA_CHAIN_POLICY, NF_ACCEPT)
+            .string(NFTA_CHAIN_TYPE, "nat");
+        self.message(
+            NFT_MSG_NEWCHAIN,
+            libc::NLM_F_CREATE as u16,
+            self.family,
+            attributes,
+        );
+    }
+
+    fn rule(&mut self, chain: &str, expressions: Vec<Attributes>) {
+        let list = expressions
+            .into_iter()
+            .fold(Attributes::default(), |list, expr| {
+                list.nested(NFTA_LIST_ELEM, expr)
+            });
+        let attributes = Attributes::default()
+            .string(NFTA_RULE_TABLE, TABLE)
+            .string(NFTA_RULE_CHAIN, chain)
+            .nested(NFTA_RULE_EXPRESSIONS, list);
+        self.message(
+            NFT_MSG_NEWRULE,
+            (libc::NLM_F_CREATE | libc::NLM_F_APPEND) as u16,
+            self.family,
+            attributes,
+        );
+    }
+
+    /// Sends the batch and waits for the kernel to acknowledge every
+    /// message, returns the first error it reports.
+    fn send(mut self) -> Result<()> {
+        self.message(
+            NFNL_MSG_BATCH_END,
+            0,
+            libc::AF_UNSPEC as u8,
+            Attributes::default(),
+        );
+
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_NETFILTER,
+            )
+        };
+        if fd < 0 {
+            return Err(anyhow!(
+                "Failed to open netfilter socket: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        let socket = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        let timeout = libc::timeval {
+            tv_sec: 5,
+            tv_usec: 0,
+        };
+        unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const _ as *const libc::c_void,
+                size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+
+        (&socket)
+            .write_all(&self.buf)
+            .map_err(|e| anyhow!("Failed to send nftables batch: {}", e))?;
+
+        let mut acked = 0;
+        let mut error = None;
+        let mut buf = vec![0u8; 65536];
+        while acked < self.messages {
+            let len = (&socket)
+                .read(&mut buf)
+                .map_err(|e| anyhow!("No answer to nftables batch: {}", e))?;
+            let mut offset = 0;
+            while offset + 16 <= len {
+                let header = &buf[offset..];
+                let msg_len = u32::from_ne_bytes(header[0..4].try_into().unwrap()) as usize;
+                let msg_type = u16::from_ne_bytes(header[4..6].try_into().unwrap());
+                let seq = u32::from_ne_bytes(header[8..12].try_into().unwrap());
+                if msg_len < 16 {
+                    break;
+                }
+                if msg_type == libc::NLMSG_ERROR as u16 && msg_len >= 20 {
+                    let code = i32::from_ne_bytes(header[16..20].try_into().unwrap());
+                    acked += 1;
+                    if code != 0 && error.is_none() {
+                        error = Some(io::Error::from_raw_os_error(-code));
+                    }
+                    // An error for the batch itself ends it.
+                    if seq == 1 {
+                        acked = self.messages;
+                    }
+                }
+                offset += msg_len.next_multiple_of(4);
+            }
+        }
+
+        match error {
+            Some(e) => Err(anyhow!("nftables rejected the rules: {}", e)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Replaces the runtime's tables with ones holding `rules`, a transaction
+/// per protocol family. Hosts without IPv6 NAT only fail with IPv6 rules.
+pub fn apply(rules: &NatRules) -> Result<()> {
+    apply_family(rules, libc::NFPROTO_IPV4 as u8)?;
+    if let Err(e) = apply_family(rules, libc::NFPROTO_IPV6 as u8) {
+        let ipv6 = rules.ports.iter().any(|p| p.container_ip.is_ipv6())
+            || rules.masquerade.iter().any(|(subnet, _)| subnet.is_ipv6());
+        if ipv6 {
+            return Err(e);
+        }
+        log::debug!("Skipping IPv6 NAT: {}", e);
+    }
+    Ok(())
+}
+
+fn apply_family(rules: &NatRules, family: u8) -> Result<()> {
+    let mut batch = Batch::new(family);
+    // Deleting a table that does not exist fails, so it is created first.
+    batch.table(NFT_MSG_NEWTABLE);
+    batch.table(NFT_MSG_DELTABLE);
+    batch.table(NFT_MSG_NEWTABLE);
+    batch.nat_chain("prerouting", NF_INET_PRE_ROUTING, NF_IP_PRI_NAT_DST);
+    batch.nat_chain("output", NF_INET_LOCAL_OUT, NF_IP_PRI_NAT_DST);
+    batch.nat_chain("postrouting", NF_INET_POST_ROUTING, NF_IP_PRI_NAT_SRC);
+
+    for port in rules
+        .ports
+        .iter()
+        .filter(|p| self::family(p.container_ip) == family)
+    {
+        batch.rule("prerouting", dnat(port, false));
+        batch.rule("output", dnat(port, true));
+    }
+    for (subnet, bridge) in rules
+        .masquerade
+        .iter()
+        .filter(|(subnet, _)| self::family(subnet.ip()) == family)
+    {
+        batch.rule("postrouting", masquerade(*subnet, bridge));
+    }
+    batch.send()
+}
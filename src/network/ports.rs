@@ -0,0 +1,259 @@
+// src/network/ports.rs
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    /// IP protocol number.
+    pub fn number(&self) -> u8 {
+        match self {
+            Protocol::Tcp => libc::IPPROTO_TCP as u8,
+            Protocol::Udp => libc::IPPROTO_UDP as u8,
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "tcp" => Ok(Protocol::Tcp),
+            "udp" => Ok(Protocol::Udp),
+            _ => Err(anyhow!("Invalid protocol {:?}, expected tcp or udp", value)),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// A container port to publish on the host, as configured.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortMapping {
+    /// Host address to accept connections on, every address of both
+    /// families if unset and every address of one with 0.0.0.0 or `::`.
+    #[serde(default)]
+    pub host_ip: Option<IpAddr>,
+    pub host_port: u16,
+    pub container_port: u16,
+    pub protocol: Protocol,
+}
+
+impl PortMapping {
+    /// Whether both mappings would take the same host port.
+    pub fn conflicts(&self, other: &PortMapping) -> bool {
+        self.protocol == other.protocol
+            && self.host_port == other.host_port
+            && match (self.host_ip, other.host_ip) {
+                (Some(a), Some(b)) => same_addresses(a, b),
+                _ => true,
+            }
+    }
+}
+
+/// Whether two host addresses of a port take the same socket, the
+/// unspecified address of a family taking every address of it.
+fn same_addresses(a: IpAddr, b: IpAddr) -> bool {
+    a.is_ipv4() == b.is_ipv4() && (a.is_unspecified() || b.is_unspecified() || a == b)
+}
+
+/// Value of `-p`: `[hostip:]hostport:containerport[/tcp|udp]`, where both
+/// ports may be ranges of the same length like `8000-8009:9000-9009` and
+/// IPv6 host addresses are bracketed like `[::1]:8080:80`.
+#[derive(Debug, Clone)]
+pub struct PublishSpec(pub Vec<PortMapping>);
+
+fn parse_ports(value: &str) -> Result<RangeInclusive<u16>> {
+    let port = |port: &str| -> Result<u16> {
+        match port.parse() {
+            Ok(0) | Err(_) => Err(anyhow!("Invalid port {:?}", port)),
+            Ok(port) => Ok(port),
+        }
+    };
+    let range = match value.split_once('-') {
+        Some((start, end)) => port(start)?..=port(end)?,
+        None => port(value)?..=port(value)?,
+    };
+    if range.is_empty() {
+        return Err(anyhow!("Invalid port range {:?}", value));
+    }
+    Ok(range)
+}
+
+impl FromStr for PublishSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (ports, protocol) = match value.split_once('/') {
+            Some((ports, protocol)) => (ports, protocol.parse()?),
+            None => (value, Protocol::Tcp),
+        };
+        let (ip, ports) = match ports.strip_prefix('[') {
+            Some(bracketed) => match bracketed.split_once("]:") {
+                Some((ip, ports)) => (Some(ip), ports),
+                None => return Err(anyhow!("Invalid host address in {:?}", value)),
+            },
+            None => (None, ports),
+        };
+        let parts: Vec<&str> = ports.split(':').collect();
+        let (ip, host_ports, container_ports) = match (ip, parts.as_slice()) {
+            (None, [host, container]) => (None, *host, *container),
+            (None, [ip, host, container]) => (Some(*ip), *host, *container),
+            (Some(ip), [host, container]) => (Some(ip), *host, *container),
+            _ => {
+                return Err(anyhow!(
+                    "Invalid port mapping {:?}, expected [hostip:]hostport:containerport[/tcp|udp]",
+                    value
+                ));
+            }
+        };
+        let host_ip = match ip {
+            Some(ip) => Some(
+                ip.parse::<IpAddr>()
+                    .map_err(|e| anyhow!("Invalid host address in {:?}: {}", value, e))?,
+            ),
+            None => None,
+        };
+        let host_ports = parse_ports(host_ports)?;
+        let container_ports = parse_ports(container_ports)?;
+        if host_ports.len() != container_ports.len() {
+            return Err(anyhow!("Port ranges in {:?} differ in length", value));
+        }
+
+        Ok(PublishSpec(
+            host_ports
+                .zip(container_ports)
+                .map(|(host_port, container_port)| PortMapping {
+                    host_ip,
+                    host_port,
+                    container_port,
+                    protocol,
+                })
+                .collect(),
+        ))
+    }
+}
+
+/// Checks that no two mappings take the same host port.
+pub fn validate(mappings: &[PortMapping]) -> Result<()> {
+    for (i, mapping) in mappings.iter().enumerate() {
+        if mappings[..i].iter().any(|other| other.conflicts(mapping)) {
+            return Err(anyhow!(
+                "Host port {}/{} is published twice",
+                mapping.host_port,
+                mapping.protocol
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// A port published while the container runs, with the address it is
+/// forwarded to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PublishedPort {
+    /// 0.0.0.0 or `::` for every address of the host, of the family of the
+    /// container's address.
+    pub host_ip: IpAddr,
+    pub host_port: u16,
+    pub container_ip: IpAddr,
+    pub container_port: u16,
+    pub protocol: Protocol,
+    /// Forwarded in userspace by the supervisor, its userland proxy or
+    /// rootless network stack, instead of NAT rules.
+    #[serde(default)]
+    pub proxied: bool,
+}
+
+impl PublishedPort {
+    pub fn new(mapping: &PortMapping, container_ip: IpAddr) -> Self {
+        let unspecified = match container_ip {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        PublishedPort {
+            host_ip: mapping.host_ip.unwrap_or(unspecified),
+            host_port: mapping.host_port,
+            container_ip,
+            container_port: mapping.container_port,
+            protocol: mapping.protocol,
+            proxied: false,
+        }
+    }
+
+    /// Whether both ports take the same host port.
+    pub fn conflicts(&self, other: &PublishedPort) -> bool {
+        self.protocol == other.protocol
+            && self.host_port == other.host_port
+            && same_addresses(self.host_ip, other.host_ip)
+    }
+
+    pub fn host_address(&self) -> SocketAddr {
+        SocketAddr::from((self.host_ip, self.host_port))
+    }
+
+    pub fn container_address(&self) -> SocketAddr {
+        SocketAddr::from((self.container_ip, self.container_port))
+    }
+}
+
+/// Publishes `mappings` on a container's `addresses`, one port per
+/// address family a mapping covers. A host address of a family the
+/// container has no address of fails.
+pub fn publish_on(mappings: &[PortMapping], addresses: &[IpAddr]) -> Result<Vec<PublishedPort>> {
+    let mut ports = Vec::new();
+    for mapping in mappings {
+        let matching: Vec<&IpAddr> = addresses
+            .iter()
+            .filter(|address| {
+                mapping
+                    .host_ip
+                    .is_none_or(|ip| ip.is_ipv4() == address.is_ipv4())
+            })
+            .collect();
+        if let (Some(host_ip), []) = (mapping.host_ip, matching.as_slice()) {
+            return Err(anyhow!(
+                "Port {} cannot be published on {}, the container has no address of its family",
+                mapping.host_port,
+                host_ip
+            ));
+        }
+        ports.extend(
+            matching
+                .into_iter()
+                .map(|address| PublishedPort::new(mapping, *address)),
+        );
+    }
+    Ok(ports)
+}
+
+/// Formatted like `nb ps` shows it, `0.0.0.0:8080->80/tcp` or
+/// `[::]:8080->80/tcp`.
+impl fmt::Display for PublishedPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}->{}/{}",
+            self.host_address(),
+            self.container_port,
+            self.protocol
+        )
+    }
+}
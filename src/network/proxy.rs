@@ -0,0 +1,347 @@
+// src/network/proxy.rs
+use anyhow::{Result, anyhow};
+use async_std::channel::{Sender, bounded};
+use async_std::net::{TcpListener, TcpStream, UdpSocket};
+use async_std::task;
+use futures::future::{join_all, select};
+use futures::pin_mut;
+use nix::sched::{CloneFlags, setns};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::net::{Shutdown, SocketAddr};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::network::ports::{Protocol, PublishedPort};
+
+/// Environment variable holding the default userland proxy mode.
+pub const USERLAND_PROXY_ENV: &str = "NEBULON_USERLAND_PROXY";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time after which a UDP client that sent nothing is forgotten.
+const UDP_FLOW_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// When published ports are forwarded by the supervisor instead of NAT
+/// rules.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ProxyMode {
+    /// Only where the NAT rules cannot be programmed.
+    #[default]
+    Auto,
+    /// For every published port, which also makes them reachable on the
+    /// host's loopback addresses.
+    Always,
+    /// Never, starting a container fails without NAT rules.
+    Never,
+}
+
+impl FromStr for ProxyMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "auto" => Ok(ProxyMode::Auto),
+            "always" | "true" => Ok(ProxyMode::Always),
+            "never" | "false" => Ok(ProxyMode::Never),
+            _ => Err(anyhow!(
+                "Invalid userland proxy mode {:?}, expected auto, always or never",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ProxyMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyMode::Auto => write!(f, "auto"),
+            ProxyMode::Always => write!(f, "always"),
+            ProxyMode::Never => write!(f, "never"),
+        }
+    }
+}
+
+static MODE: OnceLock<ProxyMode> = OnceLock::new();
+
+/// Sets the mode to the one given with `--userland-proxy`, otherwise
+/// `NEBULON_USERLAND_PROXY`, otherwise auto. Supervisors forked later keep
+/// it.
+pub fn set_mode(flag: Option<ProxyMode>) {
+    let mode = flag.unwrap_or_else(|| match std::env::var(USERLAND_PROXY_ENV) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            eprintln!("Ignoring invalid {}={:?}", USERLAND_PROXY_ENV, value);
+            ProxyMode::default()
+        }),
+        Err(_) => ProxyMode::default(),
+    });
+    let _ = MODE.set(mode);
+}
+
+pub fn mode() -> ProxyMode {
+    MODE.get().copied().unwrap_or_default()
+}
+
+/// Forwards published ports of a running container from the host. Dropping
+/// it closes the host ports.
+pub struct Proxy {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+enum Listener {
+    Tcp(std::net::TcpListener),
+    Udp(std::net::UdpSocket),
+}
+
+impl Proxy {
+    /// Listens on the host ports of `ports` and forwards what arrives to
+    /// the container from within the network namespace of process `pid`.
+    pub fn start(pid: i32, ports: &[PublishedPort]) -> Result<Proxy> {
+        let path = format!("/proc/{}/ns/net", pid);
+        let netns =
+            Arc::new(File::open(&path).map_err(|e| anyhow!("Failed to open {}: {}", path, e))?);
+
+        // Bound right away, so that taken ports fail the start.
+        let mut listeners = Vec::new();
+        for port in ports {
+            let address = port.host_address();
+            let listener = bind(address, port.protocol)
+                .map_err(|e| anyhow!("Failed to listen on {}/{}: {}", address, port.protocol, e))?;
+            listeners.push((listener, port.clone()));
+        }
+
+        let (stop, stopped) = bounded::<()>(1);
+        let thread = thread::spawn(move || {
+            task::block_on(async move {
+                let serving = join_all(listeners.into_iter().map(|(listener, port)| {
+                    let netns = netns.clone();
+                    async move {
+                        let served = match listener {
+                            Listener::Tcp(listener) => serve_tcp(listener, &port, netns).await,
+                            Listener::Udp(socket) => serve_udp(socket, &port, netns).await,
+                        };
+                        if let Err(e) = served {
+                            log::error!("Proxy for {} stopped: {}", port, e);
+                        }
+                    }
+                }));
+                let stopped = stopped.recv();
+                pin_mut!(serving, stopped);
+                select(serving, stopped).await;
+            })
+        });
+
+        log::info!("Proxying {} ports from the host", ports.len());
+        Ok(Proxy {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        // Closing the channel stops the proxy, which frees the ports once
+        // its thread is done.
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Runs `f` in a thread of its own that joined the container's network
+/// namespace, so sockets it creates belong to the container's network.
+async fn in_netns<T, F>(netns: Arc<File>, f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    task::spawn_blocking(move || {
+        thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    setns(&*netns, CloneFlags::CLONE_NEWNET).map_err(io::Error::from)?;
+                    f()
+                })
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("Proxy thread panicked")))
+        })
+    })
+    .await
+}
+
+/// Binds a listening socket to `address`. IPv6 sockets only take IPv6,
+/// so that the IPv4 port of the same mapping can be bound alongside.
+fn bind(address: SocketAddr, protocol: Protocol) -> io::Result<Listener> {
+    let SocketAddr::V6(address) = address else {
+        return match protocol {
+            Protocol::Tcp => std::net::TcpListener::bind(address).map(Listener::Tcp),
+            Protocol::Udp => std::net::UdpSocket::bind(address).map(Listener::Udp),
+        };
+    };
+    let kind = match protocol {
+        Protocol::Tcp => libc::SOCK_STREAM,
+        Protocol::Udp => libc::SOCK_DGRAM,
+    };
+    let fd = unsafe { libc::socket(libc::AF_INET6, kind | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    let check = |result: libc::c_int| {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    };
+
+    let mut options = vec![(libc::IPPROTO_IPV6, libc::IPV6_V6ONLY)];
+    // Like std does, so that ports in TIME_WAIT can be taken again.
+    if protocol == Protocol::Tcp {
+        options.push((libc::SOL_SOCKET, libc::SO_REUSEADDR));
+    }
+    let on: libc::c_int = 1;
+    for (level, option) in options {
+        check(unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                option,
+                &on as *const _ as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        })?;
+    }
+
+    let mut sockaddr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+    sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    sockaddr.sin6_port = address.port().to_be();
+    sockaddr.sin6_addr.s6_addr = address.ip().octets();
+    sockaddr.sin6_scope_id = address.scope_id();
+    check(unsafe {
+        libc::bind(
+            fd,
+            &sockaddr as *const _ as *const libc::sockaddr,
+            size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+        )
+    })?;
+    Ok(match protocol {
+        Protocol::Tcp => {
+            check(unsafe { libc::listen(fd, 128) })?;
+            Listener::Tcp(socket.into())
+        }
+        Protocol::Udp => Listener::Udp(socket.into()),
+    })
+}
+
+async fn serve_tcp(
+    listener: std::net::TcpListener,
+    port: &PublishedPort,
+    netns: Arc<File>,
+) -> io::Result<()> {
+    let listener = TcpListener::from(listener);
+    let target = port.container_address();
+    loop {
+        let (client, peer) = listener.accept().await?;
+        let netns = netns.clone();
+        task::spawn(async move {
+            let upstream = in_netns(netns, move || {
+                std::net::TcpStream::connect_timeout(&target, CONNECT_TIMEOUT)
+            })
+            .await;
+            match upstream {
+                Ok(upstream) => splice(client, TcpStream::from(upstream)).await,
+                Err(e) => log::debug!("Proxy from {} to {} failed: {}", peer, target, e),
+            }
+        });
+    }
+}
+
+/// Copies between both streams until both directions are done, passing on
+/// half closes.
+async fn splice(client: TcpStream, upstream: TcpStream) {
+    let forward = async {
+        let _ = futures::io::copy(&mut &client, &mut &upstream).await;
+        let _ = upstream.shutdown(Shutdown::Write);
+    };
+    let backward = async {
+        let _ = futures::io::copy(&mut &upstream, &mut &client).await;
+        let _ = client.shutdown(Shutdown::Write);
+    };
+    futures::join!(forward, backward);
+}
+
+async fn serve_udp(
+    socket: std::net::UdpSocket,
+    port: &PublishedPort,
+    netns: Arc<File>,
+) -> io::Result<()> {
+    let socket = Arc::new(UdpSocket::from(socket));
+    let target = port.container_address();
+    // Every client gets a socket of its own in the container, so replies
+    // find their way back.
+    let flows: Arc<Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>> = Default::default();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (len, client) = socket.recv_from(&mut buf).await?;
+        let flow = flows.lock().unwrap().get(&client).cloned();
+        let flow = match flow {
+            Some(flow) => flow,
+            None => {
+                let upstream = in_netns(netns.clone(), move || {
+                    let any = if target.is_ipv4() {
+                        "0.0.0.0:0"
+                    } else {
+                        "[::]:0"
+                    };
+                    let upstream = std::net::UdpSocket::bind(any)?;
+                    upstream.connect(target)?;
+                    Ok(upstream)
+                })
+                .await;
+                let upstream = match upstream {
+                    Ok(upstream) => Arc::new(UdpSocket::from(upstream)),
+                    Err(e) => {
+                        log::debug!("Proxy from {} to {} failed: {}", client, target, e);
+                        continue;
+                    }
+                };
+                flows.lock().unwrap().insert(client, upstream.clone());
+                task::spawn(reply_udp(
+                    upstream.clone(),
+                    socket.clone(),
+                    client,
+                    flows.clone(),
+                ));
+                upstream
+            }
+        };
+        let _ = flow.send(&buf[..len]).await;
+    }
+}
+
+/// Passes the container's replies back to `client` until it has been
+/// quiet for a while.
+async fn reply_udp(
+    upstream: Arc<UdpSocket>,
+    socket: Arc<UdpSocket>,
+    client: SocketAddr,
+    flows: Arc<Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>>,
+) {
+    let mut buf = vec![0u8; 65536];
+    while let Ok(Ok(len)) =
+        async_std::future::timeout(UDP_FLOW_TIMEOUT, upstream.recv(&mut buf)).await
+    {
+        if socket.send_to(&buf[..len], client).await.is_err() {
+            break;
+        }
+    }
+    flows.lock().unwrap().remove(&client);
+}
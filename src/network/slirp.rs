@@ -0,0 +1,293 @@
+// src/network/slirp.rs
+use anyhow::{Result, anyhow};
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::{Pid, pipe};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Shutdown};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+
+use crate::network::ports::{Protocol, PublishedPort};
+use crate::storage::storage::get_container_dir;
+
+/// Interface the userspace network stack attaches inside the container.
+pub const TAP_DEVICE: &str = "tap0";
+/// Both stacks are set up like slirp4netns is by default, the container
+/// reaches the host's network through the gateway and its resolvers
+/// through the DNS address.
+pub const GUEST_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 100);
+pub const PREFIX_LEN: u8 = 24;
+pub const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+pub const DNS_SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+const MTU: u32 = 65520;
+
+/// Userspace network stack that connects a container without privileges on
+/// the host, selected with `--network`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RootlessNetwork {
+    /// pasta, or slirp4netns where pasta is not installed.
+    #[serde(rename = "rootless")]
+    Auto,
+    Slirp4netns,
+    Pasta,
+}
+
+impl FromStr for RootlessNetwork {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "rootless" => Ok(RootlessNetwork::Auto),
+            "slirp4netns" => Ok(RootlessNetwork::Slirp4netns),
+            "pasta" => Ok(RootlessNetwork::Pasta),
+            _ => Err(anyhow!(
+                "Invalid rootless network {:?}, expected rootless, slirp4netns or pasta",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RootlessNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RootlessNetwork::Auto => write!(f, "rootless"),
+            RootlessNetwork::Slirp4netns => write!(f, "slirp4netns"),
+            RootlessNetwork::Pasta => write!(f, "pasta"),
+        }
+    }
+}
+
+impl RootlessNetwork {
+    /// Returns the stack to run with the path of its program.
+    fn resolve(self) -> Result<(RootlessNetwork, PathBuf)> {
+        let candidates: &[RootlessNetwork] = match self {
+            RootlessNetwork::Auto => &[RootlessNetwork::Pasta, RootlessNetwork::Slirp4netns],
+            _ => &[self],
+        };
+        candidates
+            .iter()
+            .find_map(|stack| find_program(&stack.to_string()).map(|path| (*stack, path)))
+            .ok_or_else(|| match self {
+                RootlessNetwork::Auto => {
+                    anyhow!("Rootless networking needs pasta or slirp4netns, neither is installed")
+                }
+                _ => anyhow!("{} is not installed", self),
+            })
+    }
+}
+
+/// Looks `name` up in `$PATH`.
+fn find_program(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| candidate.is_file())
+}
+
+/// A userspace network stack serving a running container. Dropping it
+/// stops the stack, which cuts the container off and closes its ports.
+pub struct Slirp {
+    pid: Pid,
+    /// Set where the stack runs in the foreground and has to be reaped.
+    child: Option<Child>,
+    api_socket: Option<PathBuf>,
+}
+
+impl Slirp {
+    /// Connects the network namespace of process `pid` through `network`,
+    /// forwarding `ports` from the host.
+    pub fn start(
+        container_id: &str,
+        network: RootlessNetwork,
+        pid: i32,
+        ports: &[PublishedPort],
+    ) -> Result<Slirp> {
+        let (stack, program) = network.resolve()?;
+        let dir = get_container_dir(container_id);
+        fs::create_dir_all(&dir)?;
+        let slirp = match stack {
+            RootlessNetwork::Pasta => start_pasta(&program, &dir, pid, ports),
+            _ => start_slirp4netns(&program, &dir, pid, ports),
+        }
+        .map_err(|e| anyhow!("Failed to start {}: {}", stack, e))?;
+        log::info!(
+            "Container {} is connected through {} (PID {})",
+            container_id,
+            stack,
+            slirp.pid
+        );
+        Ok(slirp)
+    }
+}
+
+impl Drop for Slirp {
+    fn drop(&mut self) {
+        let _ = kill(self.pid, Signal::SIGTERM);
+        if let Some(child) = self.child.as_mut() {
+            let _ = child.wait();
+        }
+        if let Some(api_socket) = &self.api_socket {
+            let _ = fs::remove_file(api_socket);
+        }
+    }
+}
+
+/// pasta forks into the background once the namespace is set up and
+/// forwards the ports given on its command line.
+fn start_pasta(program: &Path, dir: &Path, pid: i32, ports: &[PublishedPort]) -> Result<Slirp> {
+    let pid_file = dir.join("pasta.pid");
+    let _ = fs::remove_file(&pid_file);
+
+    let forwards = |protocol: Protocol| {
+        let specs: Vec<String> = ports
+            .iter()
+            .filter(|p| p.protocol == protocol)
+            .map(|p| {
+                let host = if p.host_ip.is_unspecified() {
+                    p.host_port.to_string()
+                } else {
+                    format!("{}/{}", p.host_ip, p.host_port)
+                };
+                format!("{}:{}", host, p.container_port)
+            })
+            .collect();
+        if specs.is_empty() {
+            vec!["none".to_string()]
+        } else {
+            specs
+        }
+    };
+
+    let mut command = Command::new(program);
+    command
+        .args(["--config-net", "--quiet", "--no-map-gw"])
+        .args(["--ns-ifname", TAP_DEVICE])
+        .args(["--mtu", &MTU.to_string()])
+        .args(["--address", &GUEST_ADDRESS.to_string()])
+        .args(["--netmask", &PREFIX_LEN.to_string()])
+        .args(["--gateway", &GATEWAY.to_string()])
+        .args(["--dns-forward", &DNS_SERVER.to_string()])
+        // Nothing the container listens on reaches the host on its own.
+        .args(["-T", "none", "-U", "none"]);
+    for spec in forwards(Protocol::Tcp) {
+        command.args(["-t", &spec]);
+    }
+    for spec in forwards(Protocol::Udp) {
+        command.args(["-u", &spec]);
+    }
+    let output = command
+        .arg("--pid")
+        .arg(&pid_file)
+        .arg(pid.to_string())
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| anyhow!("Failed to run {}: {}", program.display(), e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let daemon = fs::read_to_string(&pid_file)
+        .ok()
+        .and_then(|pid| pid.trim().parse().ok())
+        .ok_or_else(|| anyhow!("Failed to read {:?}", pid_file))?;
+    let _ = fs::remove_file(&pid_file);
+    Ok(Slirp {
+        pid: Pid::from_raw(daemon),
+        child: None,
+        api_socket: None,
+    })
+}
+
+/// slirp4netns stays in the foreground, tells when the tap device is up
+/// through a pipe and has ports added through its API socket.
+fn start_slirp4netns(
+    program: &Path,
+    dir: &Path,
+    pid: i32,
+    ports: &[PublishedPort],
+) -> Result<Slirp> {
+    let api_socket = dir.join("slirp4netns.sock");
+    let _ = fs::remove_file(&api_socket);
+    let log_path = dir.join("slirp4netns.log");
+    let log = File::create(&log_path)?;
+
+    // Unlike the runtime's other pipes the write end is inherited.
+    let (ready_rx, ready_tx) = pipe().map_err(|e| anyhow!("Failed to create pipe: {}", e))?;
+    let child = Command::new(program)
+        .args(["--configure", "--disable-host-loopback"])
+        .arg(format!("--mtu={}", MTU))
+        .arg(format!("--ready-fd={}", ready_tx.as_raw_fd()))
+        .arg("--api-socket")
+        .arg(&api_socket)
+        .arg(pid.to_string())
+        .arg(TAP_DEVICE)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(log)
+        .spawn()
+        .map_err(|e| anyhow!("Failed to run {}: {}", program.display(), e))?;
+    drop(ready_tx);
+
+    // Dropped on errors, which stops and reaps it.
+    let slirp = Slirp {
+        pid: Pid::from_raw(child.id() as i32),
+        child: Some(child),
+        api_socket: Some(api_socket.clone()),
+    };
+    let mut ready = [0u8; 1];
+    if !matches!(File::from(ready_rx).read(&mut ready), Ok(1)) {
+        let log = fs::read_to_string(&log_path).unwrap_or_default();
+        return Err(match log.trim() {
+            "" => anyhow!("it exited early"),
+            log => anyhow!("{}", log),
+        });
+    }
+
+    for port in ports {
+        add_hostfwd(&api_socket, port).map_err(|e| anyhow!("Failed to forward {}: {}", port, e))?;
+    }
+    Ok(slirp)
+}
+
+/// Asks slirp4netns to forward `port`, one request per connection.
+fn add_hostfwd(api_socket: &Path, port: &PublishedPort) -> Result<()> {
+    let request = serde_json::json!({
+        "execute": "add_hostfwd",
+        "arguments": {
+            "proto": port.protocol.to_string(),
+            "host_addr": port.host_ip.to_string(),
+            "host_port": port.host_port,
+            "guest_addr": port.container_ip.to_string(),
+            "guest_port": port.container_port,
+        }
+    });
+    let mut stream = UnixStream::connect(api_socket)?;
+    stream.write_all(request.to_string().as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+
+    let reply: serde_json::Value =
+        serde_json::from_str(&reply).map_err(|e| anyhow!("Invalid reply {:?}: {}", reply, e))?;
+    if let Some(error) = reply.get("error") {
+        return Err(anyhow!(
+            "{}",
+            error
+                .get("desc")
+                .and_then(|desc| desc.as_str())
+                .unwrap_or("unknown error")
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,441 @@
+// src/network/store.rs
+use anyhow::{Result, anyhow};
+use chrono::Utc;
+use ipnetwork::{Ipv4Network, Ipv6Network};
+use nix::fcntl::{Flock, FlockArg};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use crate::PROGRAM_ROOT;
+use crate::network::macvlan::PARENT_OPTION;
+use crate::network::{
+    DEFAULT_BRIDGE, DEFAULT_NETWORK, DEFAULT_SUBNET, DEFAULT_SUBNET6_ENV, NONE_NETWORK, Network,
+    NetworkConfig, NetworkDriver,
+};
+use crate::runtime::container::Container;
+use crate::storage::storage::get_containers;
+
+fn get_networks_file() -> PathBuf {
+    PROGRAM_ROOT.join("networks.json")
+}
+
+/// Networks are shared by every container, so like the container metadata
+/// each access holds an advisory lock. The predefined networks are set up
+/// on first use.
+fn load(arg: FlockArg) -> Result<(Flock<File>, Vec<Network>)> {
+    fs::create_dir_all(&*PROGRAM_ROOT)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(PROGRAM_ROOT.join("networks.lock"))?;
+    let lock = Flock::lock(file, arg)
+        .map_err(|(_, e)| anyhow!("Failed to lock network metadata: {}", e))?;
+
+    let networks_file = get_networks_file();
+    let mut networks: Vec<Network> = if networks_file.exists() {
+        serde_json::from_str(&fs::read_to_string(&networks_file)?)
+            .map_err(|e| anyhow!("Failed to read {:?}: {}", networks_file, e))?
+    } else {
+        Vec::new()
+    };
+    // Networks stored before they had ids get one, kept from the next write.
+    for network in networks.iter_mut().filter(|n| n.id.is_empty()) {
+        network.id = Uuid::new_v4().simple().to_string();
+    }
+    if !networks.iter().any(|n| n.name == DEFAULT_NETWORK) {
+        networks.insert(0, default_network()?);
+    }
+    if !networks.iter().any(|n| n.name == NONE_NETWORK) {
+        let mut none = new_network(NONE_NETWORK, NetworkDriver::None);
+        none.bridge.clear();
+        networks.insert(1, none);
+    }
+    Ok((lock, networks))
+}
+
+fn store(networks: &[Network]) -> Result<()> {
+    let json = serde_json::to_string_pretty(networks)?;
+    let networks_file = get_networks_file();
+    let tmpfile = networks_file.with_extension("json.tmp");
+    fs::write(&tmpfile, json)?;
+    fs::rename(&tmpfile, &networks_file)?;
+    Ok(())
+}
+
+fn new_network(name: &str, driver: NetworkDriver) -> Network {
+    let id = Uuid::new_v4().simple().to_string();
+    Network {
+        // Bridges are named after the id, interface names are limited to 15
+        // characters.
+        bridge: format!("nb-{}", &id[..8]),
+        id,
+        name: name.to_string(),
+        driver,
+        subnet: None,
+        gateway: None,
+        ip_range: None,
+        subnet6: None,
+        gateway6: None,
+        internal: false,
+        labels: HashMap::new(),
+        options: HashMap::new(),
+        created_at: Utc::now(),
+        ipam: Default::default(),
+    }
+}
+
+fn default_network() -> Result<Network> {
+    let subnet = std::env::var("NEBULON_SUBNET").unwrap_or_else(|_| DEFAULT_SUBNET.to_string());
+    let subnet: Ipv4Network = subnet
+        .parse()
+        .map_err(|e| anyhow!("Invalid subnet {:?}: {}", subnet, e))?;
+
+    let mut network = new_network(DEFAULT_NETWORK, NetworkDriver::Bridge);
+    network.bridge = DEFAULT_BRIDGE.to_string();
+    network.gateway = Some(default_gateway(subnet)?);
+    network.subnet = Some(subnet);
+    if let Ok(subnet6) = std::env::var(DEFAULT_SUBNET6_ENV) {
+        let subnet6: Ipv6Network = subnet6
+            .parse()
+            .map_err(|e| anyhow!("Invalid subnet {:?}: {}", subnet6, e))?;
+        network.gateway6 = Some(default_gateway6(subnet6)?);
+        network.subnet6 = Some(subnet6);
+    }
+    Ok(network)
+}
+
+fn default_gateway(subnet: Ipv4Network) -> Result<Ipv4Addr> {
+    subnet
+        .nth(1)
+        .filter(|_| subnet.prefix() <= 30)
+        .ok_or_else(|| anyhow!("Subnet {} is too small", subnet))
+}
+
+fn default_gateway6(subnet: Ipv6Network) -> Result<Ipv6Addr> {
+    subnet
+        .nth(1)
+        .filter(|_| subnet.prefix() <= 126)
+        .ok_or_else(|| anyhow!("Subnet {} is too small", subnet))
+}
+
+fn overlaps(a: Ipv4Network, b: Ipv4Network) -> bool {
+    a.contains(b.network()) || b.contains(a.network())
+}
+
+/// Picks the first /24 of 10.89.0.0/16 that no other network uses.
+fn free_subnet(networks: &[Network]) -> Result<Ipv4Network> {
+    (0..=255)
+        .map(|i| Ipv4Network::new(Ipv4Addr::new(10, 89, i, 0), 24).unwrap())
+        .find(|candidate| {
+            !networks
+                .iter()
+                .filter_map(|n| n.subnet)
+                .any(|subnet| overlaps(subnet, *candidate))
+        })
+        .ok_or_else(|| anyhow!("No free subnet left, give one with --subnet"))
+}
+
+/// Picks the first /64 of fd89::/48 that no other network uses.
+fn free_subnet6(networks: &[Network]) -> Result<Ipv6Network> {
+    (0..=u16::MAX)
+        .map(|i| Ipv6Network::new(Ipv6Addr::new(0xfd89, 0, 0, i, 0, 0, 0, 0), 64).unwrap())
+        .find(|candidate| {
+            !networks
+                .iter()
+                .filter_map(|n| n.subnet6)
+                .any(|subnet| subnet.overlaps(*candidate))
+        })
+        .ok_or_else(|| anyhow!("No free IPv6 subnet left, give one with --subnet6"))
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+    if !valid {
+        return Err(anyhow!(
+            "Invalid network name {:?}, only [a-zA-Z0-9][a-zA-Z0-9_.-] are allowed",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Looks a network up by its name, its id or a prefix of the id that
+/// matches a single network.
+fn find(networks: &[Network], reference: &str) -> Result<usize> {
+    if let Some(index) = networks
+        .iter()
+        .position(|n| n.name == reference || n.id == reference)
+    {
+        return Ok(index);
+    }
+
+    let matches: Vec<usize> = networks
+        .iter()
+        .enumerate()
+        .filter(|(_, n)| !reference.is_empty() && n.id.starts_with(reference))
+        .map(|(index, _)| index)
+        .collect();
+    match matches.as_slice() {
+        [index] => Ok(*index),
+        [] => Err(anyhow!("No such network: {}", reference)),
+        _ => Err(anyhow!(
+            "Network {} is ambiguous, it matches {}",
+            reference,
+            matches
+                .iter()
+                .map(|index| networks[*index].name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+pub fn get_network(reference: &str) -> Result<Network> {
+    let (_lock, networks) = load(FlockArg::LockShared)?;
+    let index = find(&networks, reference)?;
+    Ok(networks[index].clone())
+}
+
+pub fn get_networks() -> Result<Vec<Network>> {
+    let (_lock, networks) = load(FlockArg::LockShared)?;
+    Ok(networks)
+}
+
+pub fn create_network(config: NetworkConfig) -> Result<Network> {
+    validate_name(&config.name)?;
+    let (_lock, mut networks) = load(FlockArg::LockExclusive)?;
+    if networks.iter().any(|n| n.name == config.name) {
+        return Err(anyhow!("Network {} already exists", config.name));
+    }
+
+    let mut network = new_network(&config.name, config.driver);
+    match config.driver {
+        NetworkDriver::None => {
+            if config.subnet.is_some()
+                || config.gateway.is_some()
+                || config.ip_range.is_some()
+                || config.ipv6
+                || config.subnet6.is_some()
+                || config.gateway6.is_some()
+            {
+                return Err(anyhow!("Networks of the none driver have no addresses"));
+            }
+            network.bridge.clear();
+        }
+        NetworkDriver::Bridge | NetworkDriver::Macvlan | NetworkDriver::Ipvlan => {
+            let subnet = match config.subnet {
+                Some(subnet) => subnet,
+                None if config.driver == NetworkDriver::Bridge => free_subnet(&networks)?,
+                None => {
+                    return Err(anyhow!(
+                        "Networks of the {} driver need a --subnet",
+                        config.driver
+                    ));
+                }
+            };
+            // Containers on a bridge are routed through the host, which
+            // cannot tell overlapping subnets apart.
+            if config.driver == NetworkDriver::Bridge
+                && let Some(other) = networks.iter().find(|n| {
+                    n.driver == NetworkDriver::Bridge
+                        && n.subnet.is_some_and(|other| overlaps(other, subnet))
+                })
+            {
+                return Err(anyhow!(
+                    "Subnet {} overlaps with network {}",
+                    subnet,
+                    other.name
+                ));
+            }
+
+            let gateway = match config.gateway {
+                Some(gateway) => gateway,
+                None => default_gateway(subnet)?,
+            };
+            if !subnet.contains(gateway)
+                || gateway == subnet.network()
+                || gateway == subnet.broadcast()
+            {
+                return Err(anyhow!("Gateway {} cannot be used in {}", gateway, subnet));
+            }
+            if let Some(range) = config.ip_range
+                && !(subnet.contains(range.network()) && subnet.contains(range.broadcast()))
+            {
+                return Err(anyhow!("IP range {} is not part of {}", range, subnet));
+            }
+            if config.driver != NetworkDriver::Bridge {
+                network.bridge.clear();
+                if !config.options.contains_key(PARENT_OPTION) {
+                    return Err(anyhow!(
+                        "Networks of the {} driver need a parent interface, -o parent=<if>",
+                        config.driver
+                    ));
+                }
+            }
+
+            network.subnet = Some(subnet);
+            network.gateway = Some(gateway);
+            network.ip_range = config.ip_range;
+
+            if config.gateway6.is_some() && config.subnet6.is_none() && !config.ipv6 {
+                return Err(anyhow!("An IPv6 gateway needs --ipv6 or --subnet6"));
+            }
+            let subnet6 = match config.subnet6 {
+                Some(subnet6) => Some(subnet6),
+                None if config.ipv6 && config.driver == NetworkDriver::Bridge => {
+                    Some(free_subnet6(&networks)?)
+                }
+                None if config.ipv6 => {
+                    return Err(anyhow!(
+                        "IPv6 networks of the {} driver need a --subnet6",
+                        config.driver
+                    ));
+                }
+                None => None,
+            };
+            if let Some(subnet6) = subnet6 {
+                if config.driver == NetworkDriver::Bridge
+                    && let Some(other) = networks.iter().find(|n| {
+                        n.driver == NetworkDriver::Bridge
+                            && n.subnet6.is_some_and(|other| other.overlaps(subnet6))
+                    })
+                {
+                    return Err(anyhow!(
+                        "Subnet {} overlaps with network {}",
+                        subnet6,
+                        other.name
+                    ));
+                }
+                let gateway6 = match config.gateway6 {
+                    Some(gateway6) => gateway6,
+                    None => default_gateway6(subnet6)?,
+                };
+                if !subnet6.contains(gateway6) || gateway6 == subnet6.network() {
+                    return Err(anyhow!(
+                        "Gateway {} cannot be used in {}",
+                        gateway6,
+                        subnet6
+                    ));
+                }
+                network.subnet6 = Some(subnet6);
+                network.gateway6 = Some(gateway6);
+            }
+        }
+    }
+    network.internal = config.internal;
+    network.labels = config.labels;
+    network.options = config.options;
+
+    networks.push(network.clone());
+    store(&networks)?;
+    Ok(network)
+}
+
+/// Names of the containers connected to `network`.
+fn users(network: &Network, containers: &[Container]) -> Vec<String> {
+    containers
+        .iter()
+        .filter(|c| {
+            c.networks.iter().any(|e| e.network == network.name)
+                || c.config.networks.iter().any(|a| a.network == network.name)
+        })
+        .map(|c| {
+            if c.name.is_empty() {
+                c.id.clone()
+            } else {
+                c.name.clone()
+            }
+        })
+        .collect()
+}
+
+/// Removes a network no container is connected to. The predefined
+/// networks are kept.
+pub fn remove_network(reference: &str) -> Result<Network> {
+    let (_lock, mut networks) = load(FlockArg::LockExclusive)?;
+    let index = find(&networks, reference)?;
+    let network = &networks[index];
+    if network.name == DEFAULT_NETWORK || network.name == NONE_NETWORK {
+        return Err(anyhow!(
+            "Network {} is predefined and cannot be removed",
+            network.name
+        ));
+    }
+
+    let in_use = users(network, &get_containers()?);
+    if !in_use.is_empty() {
+        return Err(anyhow!(
+            "Network {} is in use by {}",
+            network.name,
+            in_use.join(", ")
+        ));
+    }
+    if !network.ipam.is_empty() {
+        return Err(anyhow!(
+            "Network {} still has addresses allocated",
+            network.name
+        ));
+    }
+
+    let network = networks.remove(index);
+    store(&networks)?;
+    Ok(network)
+}
+
+/// Allocates an address on the network for a container, `requested` if
+/// given, and an IPv6 address, `requested6` if given, on dual-stack
+/// networks. Networks of the none driver have no addresses.
+pub fn allocate(
+    reference: &str,
+    container_id: &str,
+    requested: Option<Ipv4Addr>,
+    requested6: Option<Ipv6Addr>,
+) -> Result<Option<(Network, Ipv4Addr, Option<Ipv6Addr>)>> {
+    let (_lock, mut networks) = load(FlockArg::LockExclusive)?;
+    let index = find(&networks, reference)?;
+    let network = &mut networks[index];
+    let (Some(subnet), Some(gateway)) = (network.subnet, network.gateway) else {
+        return Ok(None);
+    };
+
+    let address =
+        network
+            .ipam
+            .allocate(subnet, gateway, network.ip_range, requested, container_id)?;
+    let address6 = match (network.subnet6, network.gateway6) {
+        (Some(subnet6), Some(gateway6)) => Some(network.ipam.allocate6(
+            subnet6,
+            gateway6,
+            requested6,
+            container_id,
+        )?),
+        _ if requested6.is_some() => {
+            return Err(anyhow!("Network {} has no IPv6 subnet", network.name));
+        }
+        _ => None,
+    };
+    let network = network.clone();
+    store(&networks)?;
+    Ok(Some((network, address, address6)))
+}
+
+/// Releases the addresses of a container on `network`, or on every network.
+pub fn release(container_id: &str, network: Option<&str>) -> Result<()> {
+    let (_lock, mut networks) = load(FlockArg::LockExclusive)?;
+    let mut released = false;
+    for n in networks.iter_mut() {
+        if network.is_none_or(|name| n.name == name) {
+            released |= n.ipam.release(container_id);
+        }
+    }
+    if released {
+        store(&networks)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,300 @@
+// src/network/tc.rs
+use anyhow::{Result, anyhow};
+use futures::StreamExt;
+use rtnetlink::Handle;
+use rtnetlink::packet_core::{
+    DefaultNla, NLM_F_ACK, NLM_F_CREATE, NLM_F_EXCL, NLM_F_REPLACE, NLM_F_REQUEST, NetlinkMessage,
+    NetlinkPayload,
+};
+use rtnetlink::packet_route::RouteNetlinkMessage;
+use rtnetlink::packet_route::tc::{
+    TcAction, TcActionAttribute, TcActionOption, TcAttribute, TcFilterMatchAllOption, TcHandle,
+    TcMessage, TcOption,
+};
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// The smallest burst, enough for a full GSO packet. Packets that do not
+/// fit the burst never pass the policer.
+const MIN_BURST: u64 = 64 * 1024;
+/// Time traffic to the container may queue up for before it is dropped.
+const LATENCY_MS: u64 = 50;
+
+/// Kernel packet scheduler ticks per second, 64 ns each.
+const TICKS_PER_SEC: u128 = 1_000_000_000 / 64;
+const TC_LINKLAYER_ETHERNET: u8 = 1;
+const TC_ACT_SHOT: i32 = 2;
+/// Rate tables have 256 slots, each for packets of up to 8 more bytes.
+const RATE_TABLE_CELL_LOG: u8 = 3;
+
+const TCA_TBF_PARMS: u16 = 1;
+const TCA_TBF_RATE64: u16 = 4;
+const TCA_TBF_BURST: u16 = 6;
+const TCA_POLICE_TBF: u16 = 1;
+const TCA_POLICE_RATE: u16 = 2;
+const TCA_POLICE_RATE64: u16 = 8;
+
+/// A rate limit, `rate` in bits per second and `burst` in bytes that may
+/// pass at once above it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BandwidthLimit {
+    pub rate: u64,
+    pub burst: u64,
+}
+
+impl BandwidthLimit {
+    /// Limits traffic to `rate`, with a burst of 100 ms worth of traffic
+    /// unless `burst` is given.
+    pub fn new(rate: u64, burst: Option<u64>) -> Self {
+        BandwidthLimit {
+            rate,
+            burst: burst.unwrap_or((rate / 8 / 10).max(MIN_BURST)),
+        }
+    }
+
+    fn bytes_per_second(&self) -> u64 {
+        self.rate / 8
+    }
+}
+
+/// Rate limits of a container's traffic on its bridge network, named from
+/// the container's point of view.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct BandwidthLimits {
+    /// Traffic to the container, shaped by a token bucket on the host end
+    /// of its veth pair.
+    pub ingress: Option<BandwidthLimit>,
+    /// Traffic from the container, policed as it arrives at the host end.
+    pub egress: Option<BandwidthLimit>,
+}
+
+impl BandwidthLimits {
+    pub fn is_empty(&self) -> bool {
+        self.ingress.is_none() && self.egress.is_none()
+    }
+}
+
+/// Parses rates such as `10mbit` or `1gbit` in bits per second, or `1mbps`
+/// in bytes per second, like tc does. Plain numbers are bits per second.
+pub fn parse_rate(value: &str) -> Result<u64> {
+    let lower = value.trim().to_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match &lower[digits.len()..] {
+        "" | "bit" => 1,
+        "kbit" => 1_000,
+        "mbit" => 1_000_000,
+        "gbit" => 1_000_000_000,
+        "bps" => 8,
+        "kbps" => 8_000,
+        "mbps" => 8_000_000,
+        "gbps" => 8_000_000_000,
+        _ => return Err(anyhow!("Invalid rate {:?}, expected e.g. 10mbit", value)),
+    };
+
+    match digits.parse::<u64>() {
+        // Rates are handed to the kernel in bytes per second.
+        Ok(rate) if rate > 0 => match rate.checked_mul(multiplier) {
+            Some(rate) if rate >= 8 => Ok(rate),
+            Some(_) => Err(anyhow!("Rate {:?} is below 8bit", value)),
+            None => Err(anyhow!("Rate {:?} is too large", value)),
+        },
+        _ => Err(anyhow!("Invalid rate {:?}, expected e.g. 10mbit", value)),
+    }
+}
+
+/// Applies `limits` to the host interface with index `index`: a token
+/// bucket filter as its root qdisc for traffic to the container and a
+/// policer dropping traffic from the container above its rate.
+pub async fn limit(handle: Handle, index: u32, limits: BandwidthLimits) -> Result<()> {
+    if let Some(ingress) = limits.ingress {
+        shape(&handle, index, ingress)
+            .await
+            .map_err(|e| anyhow!("Failed to limit ingress: {}", e))?;
+    }
+    if let Some(egress) = limits.egress {
+        police(&handle, index, egress)
+            .await
+            .map_err(|e| anyhow!("Failed to limit egress: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Replaces the root qdisc of the interface by a token bucket filter. An
+/// HTB qdisc would need a class and a default for the only flow on the
+/// veth and is meant for sharing a rate between classes, which a single
+/// container does not have, so tbf shapes it with one qdisc and nothing
+/// left behind when it is replaced.
+async fn shape(handle: &Handle, index: u32, limit: BandwidthLimit) -> Result<()> {
+    let rate = limit.bytes_per_second();
+    // struct tc_tbf_qopt: rate, peak rate, limit, buffer and mtu.
+    let mut parms = Vec::with_capacity(36);
+    parms.extend(ratespec(rate, 0));
+    parms.extend([0u8; 12]);
+    let queue = (rate as u128 * LATENCY_MS as u128 / 1000 + limit.burst as u128)
+        .min(u32::MAX as u128) as u32;
+    parms.extend(queue.to_ne_bytes());
+    parms.extend(ticks(limit.burst, rate).to_ne_bytes());
+    parms.extend(0u32.to_ne_bytes());
+
+    let mut options = vec![
+        TcOption::Other(DefaultNla::new(TCA_TBF_PARMS, parms)),
+        TcOption::Other(DefaultNla::new(
+            TCA_TBF_BURST,
+            (limit.burst.min(u32::MAX as u64) as u32)
+                .to_ne_bytes()
+                .to_vec(),
+        )),
+    ];
+    if rate > u32::MAX as u64 {
+        options.push(TcOption::Other(DefaultNla::new(
+            TCA_TBF_RATE64,
+            rate.to_ne_bytes().to_vec(),
+        )));
+    }
+
+    let mut message = TcMessage::with_index(index as i32);
+    message.header.parent = TcHandle::ROOT;
+    message.header.handle = TcHandle { major: 1, minor: 0 };
+    message.attributes = vec![
+        TcAttribute::Kind("tbf".to_string()),
+        TcAttribute::Options(options),
+    ];
+    request(
+        handle,
+        RouteNetlinkMessage::NewQueueDiscipline(message),
+        NLM_F_CREATE | NLM_F_REPLACE,
+    )
+    .await
+}
+
+/// Adds an ingress qdisc to the interface with a filter matching all
+/// packets, which go through a policer that drops them above the rate.
+async fn police(handle: &Handle, index: u32, limit: BandwidthLimit) -> Result<()> {
+    handle
+        .qdisc()
+        .add(index as i32)
+        .ingress()
+        .execute()
+        .await
+        .map_err(|e| anyhow!("Failed to add ingress qdisc: {}", e))?;
+
+    let rate = limit.bytes_per_second();
+    // struct tc_police: index, action, limit, burst, mtu, rate, peak rate,
+    // refcnt, bindcnt and capab. Packets of any size are policed.
+    let mut parms = Vec::with_capacity(56);
+    parms.extend(0u32.to_ne_bytes());
+    parms.extend(TC_ACT_SHOT.to_ne_bytes());
+    parms.extend(0u32.to_ne_bytes());
+    parms.extend(ticks(limit.burst, rate).to_ne_bytes());
+    parms.extend(u32::MAX.to_ne_bytes());
+    parms.extend(ratespec(rate, RATE_TABLE_CELL_LOG));
+    parms.extend([0u8; 24]);
+
+    // The kernel only takes a policer with a rate table, even though it
+    // does not need one for Ethernet.
+    let table: Vec<u8> = (1..=256u64)
+        .flat_map(|cell| ticks(cell << RATE_TABLE_CELL_LOG, rate).to_ne_bytes())
+        .collect();
+    let mut options = vec![
+        TcActionOption::Other(DefaultNla::new(TCA_POLICE_TBF, parms)),
+        TcActionOption::Other(DefaultNla::new(TCA_POLICE_RATE, table)),
+    ];
+    if rate > u32::MAX as u64 {
+        options.push(TcActionOption::Other(DefaultNla::new(
+            TCA_POLICE_RATE64,
+            rate.to_ne_bytes().to_vec(),
+        )));
+    }
+    let mut action = TcAction::default();
+    action.attributes = vec![
+        TcActionAttribute::Kind("police".to_string()),
+        TcActionAttribute::Options(options),
+    ];
+
+    let mut message = TcMessage::with_index(index as i32);
+    message.header.parent = TcHandle {
+        major: 0xffff,
+        minor: 0,
+    };
+    // Priority 1, in the upper half, for packets of every protocol.
+    message.header.info = (1 << 16) | (libc::ETH_P_ALL as u16).to_be() as u32;
+    message.attributes = vec![
+        TcAttribute::Kind("matchall".to_string()),
+        TcAttribute::Options(vec![TcOption::MatchAll(TcFilterMatchAllOption::Action(
+            vec![action],
+        ))]),
+    ];
+    request(
+        handle,
+        RouteNetlinkMessage::NewTrafficFilter(message),
+        NLM_F_CREATE | NLM_F_EXCL,
+    )
+    .await
+    .map_err(|e| {
+        match e
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::raw_os_error)
+        {
+            Some(libc::ENOENT) => anyhow!("The kernel lacks matchall filters or police actions"),
+            _ => anyhow!("Failed to add policer: {}", e),
+        }
+    })
+}
+
+/// Sends `message`, rtnetlink's builders cannot carry the options of the
+/// token bucket filter and the policer.
+async fn request(handle: &Handle, message: RouteNetlinkMessage, flags: u16) -> Result<()> {
+    let mut request = NetlinkMessage::from(message);
+    request.header.flags = NLM_F_REQUEST | NLM_F_ACK | flags;
+    let mut response = handle.clone().request(request)?;
+    while let Some(message) = response.next().await {
+        if let NetlinkPayload::Error(e) = message.payload
+            && e.code.is_some()
+        {
+            return Err(e.to_io().into());
+        }
+    }
+    Ok(())
+}
+
+/// struct tc_ratespec for `rate` bytes per second. The Ethernet link
+/// layer spares the kernel from guessing it from a rate table.
+fn ratespec(rate: u64, cell_log: u8) -> [u8; 12] {
+    let mut spec = [0u8; 12];
+    spec[0] = cell_log;
+    spec[1] = TC_LINKLAYER_ETHERNET;
+    spec[8..].copy_from_slice(&(rate.min(u32::MAX as u64) as u32).to_ne_bytes());
+    spec
+}
+
+/// Scheduler ticks it takes to send `size` bytes at `rate` bytes per
+/// second.
+fn ticks(size: u64, rate: u64) -> u32 {
+    (size as u128 * TICKS_PER_SEC / rate.max(1) as u128).min(u32::MAX as u128) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("10mbit").unwrap(), 10_000_000);
+        assert_eq!(parse_rate("1GBit").unwrap(), 1_000_000_000);
+        assert_eq!(parse_rate("1mbps").unwrap(), 8_000_000);
+        assert_eq!(parse_rate("8").unwrap(), 8);
+        assert!(parse_rate("7bit").is_err());
+        assert!(parse_rate("0kbit").is_err());
+        assert!(parse_rate("10xbit").is_err());
+    }
+
+    #[test]
+    fn rejects_rates_that_are_not_integers_or_too_large() {
+        assert!(parse_rate("1e30bit").is_err());
+        assert!(parse_rate("inf").is_err());
+        assert!(parse_rate("NaN").is_err());
+        assert!(parse_rate("1.5mbit").is_err());
+        assert!(parse_rate("-1mbit").is_err());
+        assert!(parse_rate("18446744073709551615gbps").is_err());
+    }
+}
@@ -1,12 +1,19 @@
 // src/runtime/cgroups.rs
 use anyhow::{Result, anyhow};
+use nix::sys::signal::{Signal, kill};
 use nix::sys::statfs::{CGROUP_SUPER_MAGIC, CGROUP2_SUPER_MAGIC, statfs};
+use nix::unistd::Pid;
 use std::fs::{self, File, create_dir_all};
 use std::io::Write;
 use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::PROGRAM_NAME;
 
+/// Longest time `kill_all` waits for the killed processes to exit.
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct CgroupManager {
     cgroup_path: PathBuf,
 }
@@ -18,15 +25,14 @@ impl CgroupManager {
 
         if !cgroup_path.exists() {
             create_dir_all(&cgroup_path)
-                .map_err(|e| anyhow!("Failed to create cgroup directory: {}", e))
-                .unwrap();
+                .map_err(|e| anyhow!("Failed to create cgroup directory: {}", e))?;
         }
 
         Ok(Self { cgroup_path })
     }
 
+    #[allow(dead_code)]
     pub fn set_memory_limit(&self, limit_mb: usize) -> Result<()> {
-        
         let version = self.detect_cgroup_version().unwrap();
         let path = if version == 2 {
             "memory.max"
@@ -46,6 +52,7 @@ impl CgroupManager {
         Ok(())
     }
 
+    #[allow(dead_code)]
     fn detect_cgroup_version(&self) -> Result<u8> {
         let stat = statfs("/sys/fs/cgroup").unwrap();
 
@@ -58,6 +65,7 @@ impl CgroupManager {
         }
     }
 
+    #[allow(dead_code)]
     pub fn set_cpu_quota(&self, quota_percent: u32) -> Result<()> {
         let cpu_quota_path = self.cgroup_path.join("cpu.max");
         let mut file = File::create(cpu_quota_path)
@@ -65,7 +73,7 @@ impl CgroupManager {
             .unwrap();
 
         // Calculate quota (percentage of CPU time)
-        let quota = (quota_percent * 1000) as u32; // Convert to millicores
+        let quota = quota_percent * 1000; // Convert to millicores
         write!(file, "{} 100000", quota)
             .map_err(|e| anyhow!("Failed to write CPU quota: {}", e))
             .unwrap();
@@ -76,22 +84,72 @@ impl CgroupManager {
     pub fn add_process(&self, pid: i32) -> Result<()> {
         let procs_path = self.cgroup_path.join("cgroup.procs");
         let mut file = File::create(procs_path)
-            .map_err(|e| anyhow!("Failed to create cgroup procs file: {}", e))
-            .unwrap();
+            .map_err(|e| anyhow!("Failed to create cgroup procs file: {}", e))?;
 
-        write!(file, "{}", pid)
-            .map_err(|e| anyhow!("Failed to add process to cgroup: {}", e))
-            .unwrap();
+        write!(file, "{}", pid).map_err(|e| anyhow!("Failed to add process to cgroup: {}", e))?;
 
         Ok(())
     }
 
+    /// Kills every process left in the cgroup and waits for them to exit,
+    /// the cgroup cannot be removed before.
+    pub fn kill_all(&self) -> Result<()> {
+        let procs_path = self.cgroup_path.join("cgroup.procs");
+        if !procs_path.exists() {
+            return Ok(());
+        }
+
+        let deadline = Instant::now() + KILL_TIMEOUT;
+        loop {
+            let procs = fs::read_to_string(&procs_path)
+                .map_err(|e| anyhow!("Failed to read cgroup processes: {}", e))?;
+            let pids: Vec<i32> = procs
+                .lines()
+                .filter_map(|line| line.trim().parse::<i32>().ok())
+                .collect();
+            if pids.is_empty() {
+                return Ok(());
+            }
+            // Processes forked meanwhile are killed on the next round.
+            for pid in pids {
+                let _ = kill(Pid::from_raw(pid), Signal::SIGKILL);
+            }
+
+            // A plain directory keeps the pids written to it.
+            if !self.is_cgroupfs() {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(anyhow!(
+                    "Processes in cgroup {:?} did not exit in time",
+                    self.cgroup_path
+                ));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
     pub fn cleanup(&self) -> Result<()> {
-        if self.cgroup_path.exists() {
-            fs::remove_dir(&self.cgroup_path)
-                .map_err(|e| anyhow!("Failed to remove cgroup: {}", e))
-                .unwrap();
+        if !self.cgroup_path.exists() {
+            return Ok(());
         }
-        Ok(())
+
+        match fs::remove_dir(&self.cgroup_path) {
+            Ok(()) => Ok(()),
+            // Without a cgroup filesystem mounted here the directory is a
+            // plain one, holding the files written to it.
+            Err(e) if e.raw_os_error() == Some(libc::ENOTEMPTY) && !self.is_cgroupfs() => {
+                fs::remove_dir_all(&self.cgroup_path)
+                    .map_err(|e| anyhow!("Failed to remove cgroup: {}", e))
+            }
+            Err(e) => Err(anyhow!("Failed to remove cgroup: {}", e)),
+        }
+    }
+
+    fn is_cgroupfs(&self) -> bool {
+        statfs(&self.cgroup_path).is_ok_and(|stat| {
+            stat.filesystem_type() == CGROUP2_SUPER_MAGIC
+                || stat.filesystem_type() == CGROUP_SUPER_MAGIC
+        })
     }
 }
@@ -1,6 +1,24 @@
 // src/runtime/container.rs
+use crate::network::etc_files::ExtraHost;
+use crate::network::ports::{PortMapping, PublishedPort};
+use crate::network::slirp::RootlessNetwork;
+use crate::network::tc::BandwidthLimits;
+use crate::network::{Endpoint, NetworkAttachment};
+use crate::runtime::health::{HealthCheck, HealthState};
+use crate::runtime::logs::{LogConfig, LogOptions};
+use crate::runtime::namespace::NamespaceConfig;
+use crate::runtime::restart::RestartPolicy;
+use crate::runtime::signal::{DEFAULT_STOP_SIGNAL, parse_signal};
+use crate::runtime::tty::DEFAULT_DETACH_KEYS;
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use nix::sys::signal::Signal;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::path::PathBuf;
+use std::str::FromStr;
+use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContainerConfig {
@@ -14,6 +32,61 @@ pub struct ContainerConfig {
     pub gid: u32,
     pub volumes: Vec<VolumeMount>,
     pub storage_driver: String,
+    #[serde(default)]
+    pub sysctls: HashMap<String, String>,
+    #[serde(default)]
+    pub namespaces: NamespaceConfig,
+    #[serde(default)]
+    pub tty: bool,
+    #[serde(default)]
+    pub interactive: bool,
+    #[serde(default = "default_detach_keys")]
+    pub detach_keys: String,
+    #[serde(default)]
+    pub log_config: LogConfig,
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    /// Signal sent by `nb stop`, SIGTERM if unset.
+    #[serde(default)]
+    pub stop_signal: Option<String>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Image the container was created from by `nb run`, as `name:tag`.
+    #[serde(default)]
+    pub image: Option<String>,
+    /// Remove the container once it exits, set by `nb run --rm`.
+    #[serde(default)]
+    pub auto_remove: bool,
+    /// Networks to connect to with a private network namespace, in the
+    /// order of the container's interfaces.
+    #[serde(default)]
+    pub networks: Vec<NetworkAttachment>,
+    /// Ports to publish on the host, see `-p`.
+    #[serde(default)]
+    pub ports: Vec<PortMapping>,
+    /// Userspace network stack connecting the private network namespace
+    /// instead of networks, for hosts where the runtime is unprivileged.
+    #[serde(default)]
+    pub rootless_network: Option<RootlessNetwork>,
+    /// Resolvers for the container's resolv.conf, the host's if empty.
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+    #[serde(default)]
+    pub dns_search: Vec<String>,
+    #[serde(default)]
+    pub dns_options: Vec<String>,
+    /// Entries added to the container's hosts file.
+    #[serde(default)]
+    pub extra_hosts: Vec<ExtraHost>,
+    /// Rate limits of the container's traffic on each bridge network.
+    #[serde(default)]
+    pub bandwidth: BandwidthLimits,
+}
+
+fn default_detach_keys() -> String {
+    DEFAULT_DETACH_KEYS.to_string()
 }
 
 impl Default for ContainerConfig {
@@ -29,30 +102,251 @@ impl Default for ContainerConfig {
             gid: 0,
             volumes: vec![],
             storage_driver: "/".to_string(),
+            sysctls: HashMap::new(),
+            namespaces: NamespaceConfig::default(),
+            tty: false,
+            interactive: false,
+            detach_keys: default_detach_keys(),
+            log_config: LogConfig::default(),
+            restart_policy: RestartPolicy::default(),
+            health_check: None,
+            stop_signal: None,
+            labels: HashMap::new(),
+            image: None,
+            auto_remove: false,
+            networks: vec![],
+            ports: vec![],
+            rootless_network: None,
+            dns: vec![],
+            dns_search: vec![],
+            dns_options: vec![],
+            extra_hosts: vec![],
+            bandwidth: BandwidthLimits::default(),
         }
     }
 }
 
+impl ContainerConfig {
+    pub fn stop_signal(&self) -> Result<Signal> {
+        match &self.stop_signal {
+            Some(stop_signal) => parse_signal(stop_signal),
+            None => Ok(DEFAULT_STOP_SIGNAL),
+        }
+    }
+
+    /// Parses `key=value` labels as given on the command line, a label
+    /// without `=` has an empty value.
+    pub fn parse_labels(entries: &[String]) -> Result<HashMap<String, String>> {
+        entries
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry.split_once('=').unwrap_or((entry, ""));
+                if key.is_empty() {
+                    return Err(anyhow!("Invalid label {:?}, expected key=value", entry));
+                }
+                Ok((key.to_string(), value.to_string()))
+            })
+            .collect()
+    }
+
+    /// Returns the `KEY=VALUE` variables of `base` with the ones in
+    /// `overrides` replacing those with the same key.
+    pub fn merge_env(base: &[String], overrides: &[String]) -> Vec<String> {
+        let key = |entry: &String| entry.split('=').next().unwrap_or_default().to_string();
+        let mut env: Vec<String> = base
+            .iter()
+            .filter(|entry| !overrides.iter().any(|o| key(o) == key(entry)))
+            .cloned()
+            .collect();
+        env.extend(overrides.iter().cloned());
+        env
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Container {
     pub id: String,
+    /// Unique name, given with `--name` or generated. Containers created
+    /// before names existed have an empty one.
+    #[serde(default)]
+    pub name: String,
     pub pid: i32,
     pub status: ContainerStatus,
     pub config: ContainerConfig,
+    #[serde(default = "Utc::now")]
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub started_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Exit code of the last run, signals are reported as 128 + signal.
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    /// Restarts done by the restart policy since the container was last
+    /// started with `nb start` or `nb run`.
+    #[serde(default)]
+    pub restart_count: u32,
+    /// Set while the container runs with a health check.
+    #[serde(default)]
+    pub health: Option<HealthState>,
+    /// Interfaces on the networks the container is connected to.
+    #[serde(default)]
+    pub networks: Vec<Endpoint>,
+    /// Ports forwarded to the container while it runs.
+    #[serde(default)]
+    pub ports: Vec<PublishedPort>,
+}
+
+impl Container {
+    /// Names start with a letter or digit followed by letters, digits, `_`,
+    /// `.` or `-`.
+    pub fn validate_name(name: &str) -> Result<()> {
+        let mut chars = name.chars();
+        let valid = chars.next().is_some_and(|c| c.is_ascii_alphanumeric())
+            && chars.all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+        if !valid {
+            return Err(anyhow!(
+                "Invalid container name {:?}, only [a-zA-Z0-9][a-zA-Z0-9_.-] are allowed",
+                name
+            ));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub enum ContainerStatus {
     Created,
     Running,
+    /// Exited and waiting to be restarted by its restart policy.
+    Restarting,
     Stopped,
     Exited,
 }
 
+/// Selects containers for `nb container prune`, given as `until=<time>` or
+/// `label=<key>[=<value>]`.
+#[derive(Debug, Clone)]
+pub enum ContainerFilter {
+    /// Created before this time.
+    Until(DateTime<Utc>),
+    Label(String, Option<String>),
+}
+
+impl FromStr for ContainerFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.split_once('=') {
+            Some(("until", time)) => Ok(ContainerFilter::Until(LogOptions::parse_time(time)?)),
+            Some(("label", label)) => match label.split_once('=') {
+                Some((key, value)) => Ok(ContainerFilter::Label(
+                    key.to_string(),
+                    Some(value.to_string()),
+                )),
+                None => Ok(ContainerFilter::Label(label.to_string(), None)),
+            },
+            _ => Err(anyhow!(
+                "Invalid filter {:?}, expected until=<time> or label=<key>[=<value>]",
+                value
+            )),
+        }
+    }
+}
+
+impl ContainerFilter {
+    pub fn matches(&self, container: &Container) -> bool {
+        match self {
+            ContainerFilter::Until(until) => container.created_at < *until,
+            ContainerFilter::Label(key, value) => match container.config.labels.get(key) {
+                Some(label) => value.as_ref().is_none_or(|value| value == label),
+                None => false,
+            },
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VolumeMount {
+    /// Host path to bind mount, or the name of a volume.
     pub source: String,
     pub target: PathBuf,
     pub read_only: bool,
+    /// Volume created for this container only, removed by `nb rm -v`.
+    #[serde(default)]
+    pub anonymous: bool,
+}
+
+impl VolumeMount {
+    pub fn is_bind(&self) -> bool {
+        self.source.starts_with('/')
+    }
+}
+
+/// Parses `-v` arguments: `/host/path:/target`, `volume:/target` or just
+/// `/target` for an anonymous volume, each optionally followed by `:ro` or
+/// `:rw`.
+impl FromStr for VolumeMount {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let parts: Vec<&str> = value.split(':').collect();
+        let (source, target, mode) = match parts.as_slice() {
+            [target] => (None, *target, None),
+            [target, mode @ ("ro" | "rw")] => (None, *target, Some(*mode)),
+            [source, target] => (Some(*source), *target, None),
+            [source, target, mode] => (Some(*source), *target, Some(*mode)),
+            _ => return Err(anyhow!("Invalid volume {:?}", value)),
+        };
+
+        if !target.starts_with('/') {
+            return Err(anyhow!(
+                "Invalid volume {:?}, the target must be an absolute path",
+                value
+            ));
+        }
+        let read_only = match mode {
+            None | Some("rw") => false,
+            Some("ro") => true,
+            Some(mode) => return Err(anyhow!("Invalid volume mode {:?}", mode)),
+        };
+        if source.is_some_and(str::is_empty) {
+            return Err(anyhow!("Invalid volume {:?}", value));
+        }
+
+        Ok(Self {
+            source: source
+                .map(str::to_string)
+                .unwrap_or_else(|| Uuid::new_v4().simple().to_string()),
+            target: PathBuf::from(target),
+            read_only,
+            anonymous: source.is_none(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_until_filters() {
+        let filter = "until=1h".parse::<ContainerFilter>().unwrap();
+        assert!(matches!(filter, ContainerFilter::Until(until) if until < Utc::now()));
+        assert!(
+            "until=2024-01-01T00:00:00Z"
+                .parse::<ContainerFilter>()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_until_filters() {
+        assert!("until=999999999999d".parse::<ContainerFilter>().is_err());
+        assert!(
+            "until=9999999999999999999h"
+                .parse::<ContainerFilter>()
+                .is_err()
+        );
+    }
 }
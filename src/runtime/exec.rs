@@ -0,0 +1,226 @@
+// src/runtime/exec.rs
+use anyhow::{Result, anyhow};
+use nix::errno::Errno;
+use nix::pty::openpty;
+use nix::sys::signal::kill;
+use nix::sys::stat::stat;
+use nix::sys::wait::{WaitStatus, waitpid};
+use nix::unistd::{ForkResult, Pid, chdir, chroot, dup2_stdin, execvpe, fchdir, fork};
+use std::ffi::CString;
+use std::fs::File;
+use std::os::fd::{AsFd, OwnedFd};
+use std::path::PathBuf;
+
+use crate::runtime::cgroups::CgroupManager;
+use crate::runtime::container::{Container, ContainerConfig, ContainerStatus};
+use crate::runtime::namespace::{NamespaceKind, Namespaces};
+use crate::runtime::tty::Tty;
+use crate::runtime::users::resolve_user;
+
+#[derive(Debug, Clone, Default)]
+pub struct ExecConfig {
+    pub command: Vec<String>,
+    pub env_vars: Vec<String>,
+    pub working_dir: Option<PathBuf>,
+    /// `user[:group]`, by name or id.
+    pub user: Option<String>,
+    pub interactive: bool,
+    pub tty: bool,
+}
+
+pub struct Exec;
+
+impl Exec {
+    /// Runs a new process inside the namespaces and cgroup of a running
+    /// container and returns its exit code.
+    pub fn run(container: &Container, config: &ExecConfig) -> Result<i32> {
+        if config.command.is_empty() {
+            return Err(anyhow!("No command given"));
+        }
+
+        let pid = Self::running_pid(container)?;
+        let namespaces = Namespaces::open_all(&Self::namespace_paths(pid)?)?;
+        let root_path = PathBuf::from(format!("/proc/{}/root", pid));
+        let root = File::open(&root_path)
+            .map_err(|e| anyhow!("Failed to open root of container {}: {}", container.id, e))?;
+        // Without a user the process runs as the container's main process
+        // does.
+        let user = match &config.user {
+            Some(user) => resolve_user(&root_path, user)?,
+            None => (container.config.uid, container.config.gid),
+        };
+
+        log::info!(
+            "Executing {:?} in container {}",
+            config.command,
+            container.id
+        );
+
+        let pty = if config.tty {
+            Some(openpty(None, None).map_err(|e| anyhow!("Failed to allocate a TTY: {}", e))?)
+        } else {
+            None
+        };
+
+        // SAFETY: the CLI is single threaded when exec is called.
+        match unsafe { fork() } {
+            Ok(ForkResult::Parent { child }) => {
+                if let Some(pty) = pty {
+                    drop(pty.slave);
+                    let master = File::from(pty.master);
+                    let resize_master = master.try_clone()?;
+                    Tty::relay(
+                        master.try_clone()?,
+                        master,
+                        config.interactive,
+                        None,
+                        move |rows, cols| {
+                            let _ = Tty::set_size(resize_master.as_fd(), rows, cols);
+                        },
+                    )?;
+                }
+                Ok(Self::wait_for(child))
+            }
+            Ok(ForkResult::Child) => {
+                let slave = pty.map(|pty| pty.slave);
+                let code =
+                    match Self::enter_and_exec(container, config, &namespaces, root, user, slave) {
+                        Ok(code) => code,
+                        Err(e) => {
+                            eprintln!("Error: {}", e);
+                            log::error!("Exec in container {} failed: {}", container.id, e);
+                            126
+                        }
+                    };
+                std::process::exit(code);
+            }
+            Err(e) => Err(anyhow!("Failed to fork process: {}", e)),
+        }
+    }
+
+    /// Replaces the current process with `argv`, searching `PATH` like a shell.
+    pub fn replace_process(argv: &[String], env_vars: &[String]) -> Result<()> {
+        let errno = Self::execute(argv, env_vars)?;
+        Err(anyhow!("Failed to execute {:?}: {}", argv[0], errno))
+    }
+
+    /// Like `replace_process`, returns why the command could not be run.
+    fn execute(argv: &[String], env_vars: &[String]) -> Result<Errno> {
+        let args = argv
+            .iter()
+            .map(|arg| CString::new(arg.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!("Invalid command: {}", e))?;
+        let env = env_vars
+            .iter()
+            .map(|env| CString::new(env.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!("Invalid environment variable: {}", e))?;
+
+        let command = args.first().ok_or_else(|| anyhow!("No command given"))?;
+        let Err(errno) = execvpe(command, &args, &env);
+        Ok(errno)
+    }
+
+    pub fn running_pid(container: &Container) -> Result<i32> {
+        if container.status != ContainerStatus::Running || container.pid <= 0 {
+            return Err(anyhow!("Container {} is not running", container.id));
+        }
+        kill(Pid::from_raw(container.pid), None)
+            .map_err(|_| anyhow!("Container {} is not running", container.id))?;
+        Ok(container.pid)
+    }
+
+    /// Collects the namespaces of `pid` that differ from the ones the calling
+    /// process is already in, joining those would fail for the user namespace.
+    fn namespace_paths(pid: i32) -> Result<Vec<(NamespaceKind, PathBuf)>> {
+        let mut joins = Vec::new();
+
+        for kind in NamespaceKind::ALL {
+            let path = PathBuf::from(format!("/proc/{}/ns/{}", pid, kind.proc_name()));
+            let own = PathBuf::from(format!("/proc/self/ns/{}", kind.proc_name()));
+
+            let (Ok(target), Ok(current)) = (stat(&path), stat(&own)) else {
+                continue;
+            };
+            if target.st_ino != current.st_ino || target.st_dev != current.st_dev {
+                joins.push((kind, path));
+            }
+        }
+
+        Ok(joins)
+    }
+
+    fn enter_and_exec(
+        container: &Container,
+        config: &ExecConfig,
+        namespaces: &[(NamespaceKind, File)],
+        root: File,
+        (uid, gid): (u32, u32),
+        slave: Option<OwnedFd>,
+    ) -> Result<i32> {
+        CgroupManager::new(&container.id)?.add_process(std::process::id() as i32)?;
+
+        // Opened before entering the container, its rootfs may have no /dev.
+        let null = File::open("/dev/null")?;
+
+        for (kind, file) in namespaces {
+            Namespaces::join(*kind, file)?;
+        }
+
+        fchdir(&root).map_err(|e| anyhow!("Failed to enter container root: {}", e))?;
+        chroot(".").map_err(|e| anyhow!("Failed to chroot into container: {}", e))?;
+        drop(root);
+
+        // The PID namespace only applies to children of the joining process.
+        match unsafe { fork() } {
+            Ok(ForkResult::Parent { child }) => {
+                drop(slave);
+                Ok(Self::wait_for(child))
+            }
+            Ok(ForkResult::Child) => {
+                if let Some(slave) = slave {
+                    Tty::make_controlling(&slave)?;
+                } else if !config.interactive {
+                    dup2_stdin(&null)?;
+                }
+                drop(null);
+
+                if uid != 0 || gid != 0 {
+                    Namespaces::drop_privileges(uid, gid)?;
+                }
+
+                let working_dir = config
+                    .working_dir
+                    .as_deref()
+                    .unwrap_or(&container.config.working_dirs);
+                chdir(working_dir).map_err(|e| {
+                    anyhow!("Failed to change directory to {:?}: {}", working_dir, e)
+                })?;
+
+                let env_vars =
+                    ContainerConfig::merge_env(&container.config.env_vars, &config.env_vars);
+
+                let errno = Self::execute(&config.command, &env_vars)?;
+                let error = anyhow!("Failed to execute {:?}: {}", config.command[0], errno);
+                // Like shells, exit with 127 for commands that are not found.
+                if errno == Errno::ENOENT {
+                    eprintln!("Error: {}", error);
+                    log::error!("Exec in container {} failed: {}", container.id, error);
+                    return Ok(127);
+                }
+                Err(error)
+            }
+            Err(e) => Err(anyhow!("Failed to fork process: {}", e)),
+        }
+    }
+
+    /// Waits for `child` and maps its status to a shell style exit code.
+    pub fn wait_for(child: Pid) -> i32 {
+        match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, code)) => code,
+            Ok(WaitStatus::Signaled(_, signal, _)) => 128 + signal as i32,
+            _ => 1,
+        }
+    }
+}
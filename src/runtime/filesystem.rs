@@ -1,10 +1,10 @@
 // src/runtime/filesystem.rs
 
 use anyhow::{Result, anyhow};
-use nix::mount::{MsFlags, mount};
+use nix::mount::{MntFlags, MsFlags, mount, umount2};
 use nix::unistd::{chdir, pivot_root};
-use std::fs::create_dir_all;
-use std::path::{Path};
+use std::fs::{create_dir_all, remove_dir};
+use std::path::Path;
 
 pub struct Filesystem;
 
@@ -39,27 +39,48 @@ impl Filesystem {
                 .map_err(|e| anyhow!("Failed to create rootfs directory: {}", e))
                 .unwrap();
 
-            let dirs = vec!["bin", "dev", "etc", "proc", "sys", "tmp", "usr", "var"];
-            let _ = dirs.iter().map(|f| {
-                create_dir_all(base_path.join(f.to_string().as_str()))
-                    .map_err(|e| anyhow!("Failed to create directory {}: {}", f, e))
-                    .unwrap()
-            });
+            let dirs = ["bin", "dev", "etc", "proc", "sys", "tmp", "usr", "var"];
+            for dir in dirs {
+                create_dir_all(base_path.join(dir))
+                    .map_err(|e| anyhow!("Failed to create directory {}: {}", dir, e))?;
+            }
         }
 
         Ok(())
     }
 
-    pub fn _pivot_root(new_root: &Path) -> Result<()> {
+    /// Makes `new_root` the root of the calling process' mount namespace and
+    /// detaches the old root so the host filesystem is no longer reachable.
+    pub fn pivot_root(new_root: &Path) -> Result<()> {
+        mount(
+            None::<&str>,
+            "/",
+            None::<&str>,
+            MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+            None::<&str>,
+        )
+        .map_err(|e| anyhow!("Failed to make mounts private: {}", e))?;
+
+        // pivot_root requires the new root to be a mount point.
+        mount(
+            Some(new_root),
+            new_root,
+            None::<&str>,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            None::<&str>,
+        )
+        .map_err(|e| anyhow!("Failed to bind mount rootfs: {}", e))?;
+
         let put_old = new_root.join(".pivot_root");
+        create_dir_all(&put_old)?;
 
-        pivot_root(new_root, &put_old)
-            .map_err(|e| anyhow!("Failed to pivot root: {}", e))
-            .unwrap();
+        pivot_root(new_root, &put_old).map_err(|e| anyhow!("Failed to pivot root: {}", e))?;
 
-        chdir("/")
-            .map_err(|e| anyhow!("Failed to change to new root: {}", e))
-            .unwrap();
+        chdir("/").map_err(|e| anyhow!("Failed to change to new root: {}", e))?;
+
+        umount2("/.pivot_root", MntFlags::MNT_DETACH)
+            .map_err(|e| anyhow!("Failed to detach old root: {}", e))?;
+        remove_dir("/.pivot_root")?;
 
         Ok(())
     }
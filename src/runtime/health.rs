@@ -0,0 +1,209 @@
+// src/runtime/health.rs
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use nix::fcntl::OFlag;
+use nix::sys::signal::{Signal, killpg};
+use nix::unistd::{Pid, pipe2};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Number of probe results kept in a container's health state.
+pub const MAX_HEALTH_LOG: usize = 5;
+/// Probe output beyond this many bytes is dropped.
+const MAX_PROBE_OUTPUT: usize = 4096;
+
+/// A command run inside the container to tell whether it works, set with
+/// the `--health-*` options of `nb create`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HealthCheck {
+    pub command: Vec<String>,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Failures during this time after a start do not count towards
+    /// `retries`.
+    pub start_period: Duration,
+    /// Consecutive failures after which the container is unhealthy.
+    pub retries: u32,
+    #[serde(default)]
+    pub restart_on_unhealthy: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    #[default]
+    Starting,
+    Healthy,
+    Unhealthy,
+}
+
+impl fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthStatus::Starting => write!(f, "starting"),
+            HealthStatus::Healthy => write!(f, "healthy"),
+            HealthStatus::Unhealthy => write!(f, "unhealthy"),
+        }
+    }
+}
+
+/// Result of a single run of the health check command.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthProbe {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Exit code of the command, -1 if it timed out or could not be run.
+    pub exit_code: i32,
+    pub output: String,
+}
+
+/// Health of a running container, reset every time its workload starts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HealthState {
+    pub status: HealthStatus,
+    pub failing_streak: u32,
+    pub log: VecDeque<HealthProbe>,
+}
+
+impl HealthState {
+    /// Adds a probe result and updates the status. Returns whether the
+    /// container just turned unhealthy.
+    pub fn record(&mut self, probe: HealthProbe, check: &HealthCheck, starting: bool) -> bool {
+        let passed = probe.exit_code == 0;
+        self.log.push_back(probe);
+        while self.log.len() > MAX_HEALTH_LOG {
+            self.log.pop_front();
+        }
+
+        if passed {
+            self.failing_streak = 0;
+            self.status = HealthStatus::Healthy;
+            return false;
+        }
+        // Failures while the container is still starting up are expected.
+        if starting && self.status == HealthStatus::Starting {
+            return false;
+        }
+
+        self.failing_streak += 1;
+        if self.failing_streak >= check.retries && self.status != HealthStatus::Unhealthy {
+            self.status = HealthStatus::Unhealthy;
+            return true;
+        }
+        false
+    }
+}
+
+impl HealthCheck {
+    pub fn validate(&self) -> Result<()> {
+        if self.command.is_empty() {
+            return Err(anyhow!("Health check command is empty"));
+        }
+        if self.interval.is_zero() || self.timeout.is_zero() {
+            return Err(anyhow!(
+                "Health check interval and timeout must not be zero"
+            ));
+        }
+        if self.retries == 0 {
+            return Err(anyhow!("Health check retries must be at least 1"));
+        }
+        Ok(())
+    }
+
+    /// Runs the check through `nb exec` in a process group of its own, so
+    /// that everything it started can be killed once it runs out of time.
+    pub fn probe(&self, container_id: &str) -> HealthProbe {
+        let start = Utc::now();
+        let (exit_code, output) = match self.run(container_id) {
+            Ok(result) => result,
+            Err(e) => (-1, e.to_string()),
+        };
+
+        HealthProbe {
+            start,
+            end: Utc::now(),
+            exit_code,
+            output,
+        }
+    }
+
+    fn run(&self, container_id: &str) -> Result<(i32, String)> {
+        let exe = std::env::current_exe()
+            .map_err(|e| anyhow!("Failed to locate the nb executable: {}", e))?;
+        let (output_rx, output_tx) =
+            pipe2(OFlag::O_CLOEXEC).map_err(|e| anyhow!("Failed to create pipe: {}", e))?;
+
+        let mut child = Command::new(exe)
+            .args(["--log-level", "warn", "exec", container_id, "--"])
+            .args(&self.command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::from(output_tx.try_clone()?))
+            .stderr(Stdio::from(output_tx))
+            .process_group(0)
+            .spawn()
+            .map_err(|e| anyhow!("Failed to run health check: {}", e))?;
+
+        let reader = thread::spawn(move || {
+            let mut output = Vec::new();
+            let _ = File::from(output_rx).read_to_end(&mut output);
+            output.truncate(MAX_PROBE_OUTPUT);
+            output
+        });
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break Some(status);
+            }
+            if Instant::now() >= deadline {
+                let _ = killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL);
+                let _ = child.wait();
+                break None;
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+
+        let Some(status) = status else {
+            return Ok((
+                -1,
+                format!("Health check exceeded timeout ({:?})", self.timeout),
+            ));
+        };
+        // Processes left behind by the check would keep the pipe open.
+        let _ = killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL);
+        let output = reader.join().unwrap_or_default();
+        let code = status
+            .code()
+            .or_else(|| status.signal().map(|signal| 128 + signal))
+            .unwrap_or(-1);
+        Ok((code, String::from_utf8_lossy(&output).into_owned()))
+    }
+}
+
+/// Parses durations given to the `--health-*` options, such as `500ms`,
+/// `30s`, `5m` or `1h`. A bare number is taken as seconds.
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount = amount
+        .parse::<f64>()
+        .map_err(|_| anyhow!("Invalid duration {:?}", value))?;
+
+    let seconds = match unit {
+        "ms" => amount / 1000.0,
+        "" | "s" => amount,
+        "m" => amount * 60.0,
+        "h" => amount * 3600.0,
+        _ => return Err(anyhow!("Invalid duration {:?}, expected e.g. 30s", value)),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| anyhow!("Invalid duration {:?}", value))
+}
@@ -0,0 +1,90 @@
+// src/runtime/image.rs
+use anyhow::{Result, anyhow};
+use std::path::PathBuf;
+
+use crate::runtime::container::ContainerConfig;
+use crate::runtime::signal::parse_signal;
+use crate::runtime::users::resolve_user;
+use crate::storage::images::ImageConfig;
+
+/// Tag used when an image is given without one.
+pub const DEFAULT_TAG: &str = "latest";
+
+/// Options of `nb run` that take precedence over the image's config.
+#[derive(Debug, Default)]
+pub struct ImageOverrides {
+    /// Replaces the image's entrypoint, and its default command unless
+    /// `command` is given as well.
+    pub entrypoint: Option<String>,
+    pub command: Vec<String>,
+    pub working_dir: Option<PathBuf>,
+    /// `user[:group]`, by name or id.
+    pub user: Option<String>,
+}
+
+/// Splits an image reference given as `name[:tag]`. A colon before the last
+/// `/` belongs to a registry port, not to the tag.
+pub fn parse_reference(reference: &str) -> Result<(String, String)> {
+    let name_start = reference.rfind('/').map_or(0, |slash| slash + 1);
+    let (name, tag) = match reference[name_start..].rfind(':') {
+        Some(colon) => reference.split_at(name_start + colon),
+        None => (reference, ":"),
+    };
+    let tag = match &tag[1..] {
+        "" => DEFAULT_TAG,
+        tag => tag,
+    };
+
+    if name.is_empty() {
+        return Err(anyhow!("Invalid image {:?}", reference));
+    }
+    Ok((name.to_string(), tag.to_string()))
+}
+
+/// Fills in the command, environment, working directory, user and stop
+/// signal of `config` from the image it runs. `config.rootfs` has to point
+/// at the container's root already, users are looked up in its
+/// `/etc/passwd` and `/etc/group`.
+pub fn apply_image_config(
+    config: &mut ContainerConfig,
+    image: &ImageConfig,
+    overrides: &ImageOverrides,
+) -> Result<()> {
+    let entrypoint = match &overrides.entrypoint {
+        Some(entrypoint) => vec![entrypoint.clone()],
+        None => image.entrypoint.clone(),
+    };
+    let command = if !overrides.command.is_empty() {
+        overrides.command.clone()
+    } else if overrides.entrypoint.is_some() {
+        Vec::new()
+    } else {
+        image.cmd.clone()
+    };
+    let mut argv = entrypoint.into_iter().chain(command);
+    let Some(program) = argv.next() else {
+        return Err(anyhow!("No command given and the image has none"));
+    };
+    config.command = vec![program];
+    config.args = argv.collect();
+
+    config.env_vars = ContainerConfig::merge_env(&image.env, &config.env_vars);
+
+    config.working_dirs = match &overrides.working_dir {
+        Some(working_dir) => working_dir.clone(),
+        None if image.working_dir.is_empty() => PathBuf::from("/"),
+        None => PathBuf::from(&image.working_dir),
+    };
+
+    let user = overrides.user.as_deref().unwrap_or(&image.user);
+    (config.uid, config.gid) = resolve_user(&config.rootfs, user)?;
+
+    if config.stop_signal.is_none()
+        && let Some(stop_signal) = &image.stop_signal
+    {
+        parse_signal(stop_signal)
+            .map_err(|e| anyhow!("Invalid stop signal in image config: {}", e))?;
+        config.stop_signal = Some(stop_signal.clone());
+    }
+    Ok(())
+}
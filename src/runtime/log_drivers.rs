@@ -0,0 +1,606 @@
+// src/runtime/log_drivers.rs
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Local, Utc};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::runtime::container::ContainerStatus;
+use crate::runtime::logs::{LogConfig, LogDriverType, LogEntry, LogOptions, LogStream};
+use crate::storage::storage::{get_container, get_container_dir};
+use crate::util::parse_size;
+
+pub trait LogDriver: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn log(&self, entry: &LogEntry) -> Result<()>;
+
+    /// Prints the stored output of a container for `nb logs`. Drivers that
+    /// hand the output to somewhere else cannot read it back.
+    fn read(&self, _container_id: &str, _options: &LogOptions) -> Result<()> {
+        Err(anyhow!(
+            "The {} log driver does not support reading logs",
+            self.name()
+        ))
+    }
+}
+
+/// How entries are stored in a log file.
+#[derive(Debug, Clone, Copy)]
+enum LogFormat {
+    /// One JSON object per line.
+    Json,
+    /// Length prefixed frames of stream, timestamp in nanoseconds and line.
+    Binary,
+}
+
+impl LogFormat {
+    fn encode(&self, entry: &LogEntry) -> Result<Vec<u8>> {
+        match self {
+            LogFormat::Json => {
+                let mut json = serde_json::to_vec(entry)?;
+                json.push(b'\n');
+                Ok(json)
+            }
+            LogFormat::Binary => {
+                let nanos = entry.timestamp.timestamp_nanos_opt().unwrap_or_default();
+                let stream = match entry.stream {
+                    LogStream::Stdout => 1u8,
+                    LogStream::Stderr => 2u8,
+                };
+                let len = (1 + 8 + entry.line.len()) as u32;
+
+                let mut frame = Vec::with_capacity(4 + len as usize);
+                frame.extend_from_slice(&len.to_be_bytes());
+                frame.push(stream);
+                frame.extend_from_slice(&nanos.to_be_bytes());
+                frame.extend_from_slice(entry.line.as_bytes());
+                Ok(frame)
+            }
+        }
+    }
+
+    /// Decodes the entry at the start of `buf`, returning it along with the
+    /// number of bytes it took. `None` means the entry is not complete yet,
+    /// invalid JSON lines are skipped.
+    fn decode(&self, buf: &[u8]) -> Result<Option<(Option<LogEntry>, usize)>> {
+        match self {
+            LogFormat::Json => {
+                let Some(end) = buf.iter().position(|&b| b == b'\n') else {
+                    return Ok(None);
+                };
+                let entry = serde_json::from_slice(&buf[..end])
+                    .map_err(|e| log::warn!("Skipping invalid log entry: {}", e))
+                    .ok();
+                Ok(Some((entry, end + 1)))
+            }
+            LogFormat::Binary => {
+                let Some(header) = buf.get(..4) else {
+                    return Ok(None);
+                };
+                let len = u32::from_be_bytes(header.try_into()?) as usize;
+                let Some(frame) = buf.get(4..4 + len) else {
+                    return Ok(None);
+                };
+                if len < 9 {
+                    return Err(anyhow!("Invalid log frame of {} bytes", len));
+                }
+
+                let stream = match frame[0] {
+                    1 => LogStream::Stdout,
+                    2 => LogStream::Stderr,
+                    other => return Err(anyhow!("Invalid log stream {}", other)),
+                };
+                let nanos = i64::from_be_bytes(frame[1..9].try_into()?);
+                let entry = LogEntry {
+                    stream,
+                    timestamp: DateTime::<Utc>::from_timestamp_nanos(nanos),
+                    line: String::from_utf8_lossy(&frame[9..]).into_owned(),
+                };
+                Ok(Some((Some(entry), 4 + len)))
+            }
+        }
+    }
+
+    /// Decodes every complete entry in `buf`, leaving a trailing partial
+    /// entry in place.
+    fn decode_all(&self, buf: &mut Vec<u8>, entries: &mut Vec<LogEntry>) -> Result<()> {
+        let mut start = 0;
+        while let Some((entry, len)) = self.decode(&buf[start..])? {
+            entries.extend(entry);
+            start += len;
+        }
+        buf.drain(..start);
+        Ok(())
+    }
+}
+
+/// Stores output in a file below the container's directory, rotating it to
+/// `<file>.1`, `<file>.2`, ... once it grows past `max_size`.
+pub struct FileLogDriver {
+    name: &'static str,
+    format: LogFormat,
+    path: PathBuf,
+    max_size: Option<u64>,
+    max_file: u32,
+    compress: bool,
+    // Opened on the first write, `nb logs` creates the driver only to read.
+    file: Mutex<Option<(File, u64)>>,
+}
+
+impl FileLogDriver {
+    pub fn json_file(container_id: &str, options: &HashMap<String, String>) -> Result<Self> {
+        check_options("json-file", options, &["max-size", "max-file", "compress"])?;
+        Self::new(
+            "json-file",
+            LogFormat::Json,
+            get_container_dir(container_id).join("container.log"),
+            options,
+            None,
+            false,
+        )
+    }
+
+    /// The `local` driver keeps a bounded amount of output by default.
+    pub fn local(container_id: &str, options: &HashMap<String, String>) -> Result<Self> {
+        check_options("local", options, &["max-size", "max-file", "compress"])?;
+        Self::new(
+            "local",
+            LogFormat::Binary,
+            get_container_dir(container_id).join("container-local.log"),
+            options,
+            Some(20 * 1024 * 1024),
+            true,
+        )
+    }
+
+    fn new(
+        name: &'static str,
+        format: LogFormat,
+        path: PathBuf,
+        options: &HashMap<String, String>,
+        default_max_size: Option<u64>,
+        default_compress: bool,
+    ) -> Result<Self> {
+        let max_size = match options.get("max-size") {
+            Some(size) => Some(parse_size(size)?),
+            None => default_max_size,
+        };
+        let max_file = match options.get("max-file") {
+            Some(count) => {
+                if max_size.is_none() {
+                    return Err(anyhow!("Log option max-file requires max-size"));
+                }
+                match count.parse::<u32>() {
+                    Ok(count) if count > 0 => count,
+                    _ => return Err(anyhow!("Invalid max-file {:?}", count)),
+                }
+            }
+            None if default_max_size.is_some() => 5,
+            None => 1,
+        };
+        let compress = match options.get("compress") {
+            Some(compress) => compress
+                .parse::<bool>()
+                .map_err(|_| anyhow!("Invalid compress option {:?}", compress))?,
+            None => default_compress,
+        };
+
+        Ok(Self {
+            name,
+            format,
+            path,
+            max_size,
+            max_file,
+            compress,
+            file: Mutex::new(None),
+        })
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        self.path.with_file_name(format!("{}.{}", name, index))
+    }
+
+    fn compressed_path(&self, index: u32) -> PathBuf {
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        self.path.with_file_name(format!("{}.{}.gz", name, index))
+    }
+
+    /// Moves every rotated file one index up, dropping the oldest, and
+    /// starts a new current file. With a single file it is truncated in
+    /// place, like Docker does.
+    fn rotate(&self) -> Result<()> {
+        if self.max_file > 1 {
+            for index in (1..self.max_file).rev() {
+                let (plain, gz) = (self.rotated_path(index), self.compressed_path(index));
+                if index == self.max_file - 1 {
+                    remove_if_exists(&plain)?;
+                    remove_if_exists(&gz)?;
+                } else {
+                    rename_if_exists(&plain, &self.rotated_path(index + 1))?;
+                    rename_if_exists(&gz, &self.compressed_path(index + 1))?;
+                }
+            }
+
+            let rotated = self.rotated_path(1);
+            fs::rename(&self.path, &rotated)
+                .map_err(|e| anyhow!("Failed to rotate {:?}: {}", self.path, e))?;
+            if self.compress {
+                compress_file(&rotated, &self.compressed_path(1))?;
+            }
+        } else {
+            OpenOptions::new()
+                .write(true)
+                .open(&self.path)
+                .and_then(|file| file.set_len(0))
+                .map_err(|e| anyhow!("Failed to truncate {:?}: {}", self.path, e))?;
+        }
+        Ok(())
+    }
+
+    fn open(&self) -> Result<(File, u64)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| anyhow!("Failed to open log file {:?}: {}", self.path, e))?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+
+    /// Every stored entry, oldest first, and the current file positioned
+    /// after the last complete one.
+    fn read_all(&self, entries: &mut Vec<LogEntry>) -> Result<(Option<File>, Vec<u8>)> {
+        for index in (1..self.max_file).rev() {
+            let mut data = Vec::new();
+            let gz = self.compressed_path(index);
+            let plain = self.rotated_path(index);
+            if gz.exists() {
+                GzDecoder::new(File::open(&gz)?).read_to_end(&mut data)?;
+            } else if plain.exists() {
+                File::open(&plain)?.read_to_end(&mut data)?;
+            } else {
+                continue;
+            }
+            self.format.decode_all(&mut data, entries)?;
+        }
+
+        let mut pending = Vec::new();
+        let current = match File::open(&self.path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut pending)?;
+                self.format.decode_all(&mut pending, entries)?;
+                Some(file)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(anyhow!("Failed to open log file {:?}: {}", self.path, e)),
+        };
+        Ok((current, pending))
+    }
+
+    /// Keeps printing new entries until the container stops running or the
+    /// `until` time has passed, reopening the file whenever it is rotated and
+    /// reading it from the start when it is truncated.
+    fn follow(
+        &self,
+        container_id: &str,
+        mut current: Option<File>,
+        mut pending: Vec<u8>,
+        options: &LogOptions,
+    ) -> Result<()> {
+        let mut entries = Vec::new();
+        let mut stopped = false;
+
+        loop {
+            let mut read = 0;
+            if let Some(file) = current.as_mut() {
+                read = file.read_to_end(&mut pending)?;
+            }
+            self.format.decode_all(&mut pending, &mut entries)?;
+            for entry in entries.drain(..) {
+                if options.until.is_some_and(|until| entry.timestamp > until) {
+                    return Ok(());
+                }
+                if options.matches(&entry) {
+                    entry.print(options.timestamps)?;
+                }
+            }
+            if read > 0 {
+                continue;
+            }
+
+            let replaced = match (&current, fs::metadata(&self.path)) {
+                (Some(file), Ok(metadata)) => file.metadata()?.ino() != metadata.ino(),
+                (None, Ok(_)) => true,
+                (_, Err(_)) => false,
+            };
+            if replaced {
+                current = Some(File::open(&self.path)?);
+                pending.clear();
+                continue;
+            }
+            if let Some(file) = current.as_mut()
+                && file.stream_position()? > file.metadata()?.len()
+            {
+                file.rewind()?;
+                pending.clear();
+                continue;
+            }
+
+            // One last pass after the container exits picks up what it
+            // wrote just before.
+            if stopped || options.until.is_some_and(|until| Utc::now() > until) {
+                return Ok(());
+            }
+            stopped = !get_container(container_id)
+                .map(|c| c.status == ContainerStatus::Running)
+                .unwrap_or(false);
+            if !stopped {
+                thread::sleep(Duration::from_millis(200));
+            }
+        }
+    }
+}
+
+impl LogDriver for FileLogDriver {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn log(&self, entry: &LogEntry) -> Result<()> {
+        let data = self.format.encode(entry)?;
+        let mut file = self.file.lock().unwrap();
+
+        if let (Some((_, size)), Some(max_size)) = (file.as_ref(), self.max_size)
+            && *size > 0
+            && size + data.len() as u64 > max_size
+        {
+            *file = None;
+            self.rotate()?;
+        }
+        if file.is_none() {
+            *file = Some(self.open()?);
+        }
+
+        let (file, size) = file.as_mut().unwrap();
+        // Written in one call so `nb logs --follow` rarely sees a partial
+        // entry.
+        file.write_all(&data)?;
+        *size += data.len() as u64;
+        Ok(())
+    }
+
+    fn read(&self, container_id: &str, options: &LogOptions) -> Result<()> {
+        let mut entries = Vec::new();
+        let (current, pending) = self.read_all(&mut entries)?;
+
+        entries.retain(|entry| options.matches(entry));
+        let skip = match options.tail {
+            Some(tail) => entries.len().saturating_sub(tail),
+            None => 0,
+        };
+        for entry in &entries[skip..] {
+            entry.print(options.timestamps)?;
+        }
+
+        if options.follow {
+            self.follow(container_id, current, pending, options)?;
+        }
+        Ok(())
+    }
+}
+
+enum SyslogSocket {
+    Datagram(UnixDatagram),
+    Stream(UnixStream),
+}
+
+/// Sends output to a syslog daemon listening on a local unix socket, as
+/// RFC 3164 messages tagged with the container id.
+pub struct SyslogDriver {
+    address: PathBuf,
+    stream: bool,
+    facility: u8,
+    tag: String,
+    socket: Mutex<Option<SyslogSocket>>,
+}
+
+impl SyslogDriver {
+    pub fn new(container_id: &str, options: &HashMap<String, String>) -> Result<Self> {
+        check_options(
+            "syslog",
+            options,
+            &["syslog-address", "syslog-facility", "tag"],
+        )?;
+
+        let address = options
+            .get("syslog-address")
+            .map(String::as_str)
+            .unwrap_or("unixgram:///dev/log");
+        let (stream, path) = if let Some(path) = address.strip_prefix("unixgram://") {
+            (false, path)
+        } else if let Some(path) = address.strip_prefix("unix://") {
+            (true, path)
+        } else if address.starts_with('/') {
+            (false, address)
+        } else {
+            return Err(anyhow!(
+                "Invalid syslog-address {:?}, only local unix sockets are supported",
+                address
+            ));
+        };
+
+        let facility = match options.get("syslog-facility") {
+            Some(name) => syslog_facility(name)?,
+            None => 3,
+        };
+        let tag = options
+            .get("tag")
+            .cloned()
+            .unwrap_or_else(|| container_id.to_string());
+
+        Ok(Self {
+            address: PathBuf::from(path),
+            stream,
+            facility,
+            tag,
+            socket: Mutex::new(None),
+        })
+    }
+
+    fn connect(&self) -> Result<SyslogSocket> {
+        let socket = if self.stream {
+            SyslogSocket::Stream(UnixStream::connect(&self.address)?)
+        } else {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(&self.address)?;
+            SyslogSocket::Datagram(socket)
+        };
+        Ok(socket)
+    }
+
+    fn send(&self, socket: &mut SyslogSocket, message: &str) -> std::io::Result<()> {
+        match socket {
+            SyslogSocket::Datagram(socket) => socket.send(message.as_bytes()).map(|_| ()),
+            SyslogSocket::Stream(socket) => socket.write_all(format!("{}\n", message).as_bytes()),
+        }
+    }
+}
+
+impl LogDriver for SyslogDriver {
+    fn name(&self) -> &str {
+        "syslog"
+    }
+
+    fn log(&self, entry: &LogEntry) -> Result<()> {
+        let severity = match entry.stream {
+            LogStream::Stdout => 6,
+            LogStream::Stderr => 3,
+        };
+        let message = format!(
+            "<{}>{} {}[{}]: {}",
+            self.facility as u32 * 8 + severity,
+            entry
+                .timestamp
+                .with_timezone(&Local)
+                .format("%b %e %H:%M:%S"),
+            self.tag,
+            std::process::id(),
+            entry.line
+        );
+
+        let mut socket = self.socket.lock().unwrap();
+        // The daemon may have been restarted, so a failed send reconnects
+        // once.
+        for _ in 0..2 {
+            if socket.is_none() {
+                *socket = Some(self.connect().map_err(|e| {
+                    anyhow!("Failed to connect to syslog at {:?}: {}", self.address, e)
+                })?);
+            }
+            if self.send(socket.as_mut().unwrap(), &message).is_ok() {
+                return Ok(());
+            }
+            *socket = None;
+        }
+        Err(anyhow!("Failed to send log message to {:?}", self.address))
+    }
+}
+
+/// Discards all output.
+pub struct NoneDriver;
+
+impl LogDriver for NoneDriver {
+    fn name(&self) -> &str {
+        "none"
+    }
+
+    fn log(&self, _entry: &LogEntry) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub fn create_log_driver(container_id: &str, config: &LogConfig) -> Result<Box<dyn LogDriver>> {
+    match config.driver {
+        LogDriverType::JsonFile => Ok(Box::new(FileLogDriver::json_file(
+            container_id,
+            &config.options,
+        )?)),
+        LogDriverType::Local => Ok(Box::new(FileLogDriver::local(
+            container_id,
+            &config.options,
+        )?)),
+        LogDriverType::Syslog => Ok(Box::new(SyslogDriver::new(container_id, &config.options)?)),
+        LogDriverType::None => {
+            check_options("none", &config.options, &[])?;
+            Ok(Box::new(NoneDriver))
+        }
+    }
+}
+
+fn check_options(driver: &str, options: &HashMap<String, String>, known: &[&str]) -> Result<()> {
+    for key in options.keys() {
+        if !known.contains(&key.as_str()) {
+            return Err(anyhow!(
+                "Unknown log option {:?} for the {} log driver",
+                key,
+                driver
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn syslog_facility(name: &str) -> Result<u8> {
+    const FACILITIES: [&str; 12] = [
+        "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron",
+        "authpriv", "ftp",
+    ];
+
+    if let Some(index) = FACILITIES.iter().position(|f| *f == name) {
+        return Ok(index as u8);
+    }
+    if let Some(local) = name.strip_prefix("local")
+        && let Ok(n @ 0..=7) = local.parse::<u8>()
+    {
+        return Ok(16 + n);
+    }
+    Err(anyhow!("Unknown syslog facility {:?}", name))
+}
+
+fn compress_file(source: &Path, target: &Path) -> Result<()> {
+    let mut input = File::open(source)?;
+    let mut encoder = GzEncoder::new(File::create(target)?, Compression::default());
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(source)?;
+    Ok(())
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(anyhow!("Failed to remove {:?}: {}", path, e))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(anyhow!("Failed to rename {:?}: {}", from, e))
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,249 @@
+// src/runtime/logs.rs
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Write, stderr, stdout};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+/// A single line of container output.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub stream: LogStream,
+    pub timestamp: DateTime<Utc>,
+    pub line: String,
+}
+
+impl LogEntry {
+    pub fn new(stream: LogStream, line: &str) -> Self {
+        Self {
+            stream,
+            timestamp: Utc::now(),
+            line: line.to_string(),
+        }
+    }
+
+    /// Prints the entry to stdout or stderr, matching the stream it was
+    /// written to.
+    pub fn print(&self, timestamps: bool) -> Result<()> {
+        let text = if timestamps {
+            format!(
+                "{} {}\n",
+                self.timestamp.to_rfc3339_opts(SecondsFormat::Nanos, true),
+                self.line
+            )
+        } else {
+            format!("{}\n", self.line)
+        };
+
+        match self.stream {
+            LogStream::Stdout => stdout().write_all(text.as_bytes())?,
+            LogStream::Stderr => stderr().write_all(text.as_bytes())?,
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogDriverType {
+    #[default]
+    JsonFile,
+    Local,
+    Syslog,
+    None,
+}
+
+impl FromStr for LogDriverType {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "json-file" => Ok(LogDriverType::JsonFile),
+            "local" => Ok(LogDriverType::Local),
+            "syslog" => Ok(LogDriverType::Syslog),
+            "none" => Ok(LogDriverType::None),
+            _ => Err(anyhow!(
+                "Unknown log driver {:?}, expected json-file, local, syslog or none",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LogDriverType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogDriverType::JsonFile => write!(f, "json-file"),
+            LogDriverType::Local => write!(f, "local"),
+            LogDriverType::Syslog => write!(f, "syslog"),
+            LogDriverType::None => write!(f, "none"),
+        }
+    }
+}
+
+/// Where the supervisor sends a container's output, set with `--log-driver`
+/// and `--log-opt`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LogConfig {
+    pub driver: LogDriverType,
+    #[serde(default)]
+    pub options: HashMap<String, String>,
+}
+
+impl LogConfig {
+    /// Parses `key=value` log options as given on the command line.
+    pub fn parse_options(entries: &[String]) -> Result<HashMap<String, String>> {
+        entries
+            .iter()
+            .map(|entry| {
+                entry
+                    .split_once('=')
+                    .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+                    .ok_or_else(|| anyhow!("Invalid log option {:?}, expected key=value", entry))
+            })
+            .collect()
+    }
+}
+
+/// Filters applied by `nb logs`.
+#[derive(Debug, Clone, Default)]
+pub struct LogOptions {
+    pub follow: bool,
+    pub tail: Option<usize>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub timestamps: bool,
+}
+
+impl LogOptions {
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        self.since.is_none_or(|since| entry.timestamp >= since)
+            && self.until.is_none_or(|until| entry.timestamp <= until)
+    }
+
+    /// Parses the `--since`/`--until` arguments: an RFC 3339 timestamp, unix
+    /// seconds, or a duration such as `10m` counted back from now.
+    pub fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+        if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+            return Ok(time.with_timezone(&Utc));
+        }
+
+        if let Ok(seconds) = value.parse::<f64>()
+            && seconds.is_finite()
+        {
+            return DateTime::from_timestamp_millis((seconds * 1000.0) as i64)
+                .ok_or_else(|| anyhow!("Timestamp {} is out of range", value));
+        }
+
+        let Some(unit) = value.chars().last() else {
+            return Err(anyhow!("Invalid time {:?}", value));
+        };
+        let amount = value[..value.len() - unit.len_utf8()]
+            .parse::<i64>()
+            .map_err(|_| anyhow!("Invalid time {:?}", value))?;
+        let duration = match unit {
+            's' => Duration::try_seconds(amount),
+            'm' => Duration::try_minutes(amount),
+            'h' => Duration::try_hours(amount),
+            'd' => Duration::try_days(amount),
+            _ => return Err(anyhow!("Invalid time {:?}", value)),
+        };
+        duration
+            .and_then(|duration| Utc::now().checked_sub_signed(duration))
+            .ok_or_else(|| anyhow!("Time {} is out of range", value))
+    }
+}
+
+/// Longest line passed on at once, longer lines are split like Docker does
+/// so that output without newlines is not held back without bound.
+const MAX_LINE: usize = 16 * 1024;
+
+/// Splits a stream of output into lines, holding back an incomplete last
+/// line until more output arrives.
+#[derive(Default)]
+pub struct LineBuffer {
+    pending: Vec<u8>,
+}
+
+impl LineBuffer {
+    pub fn feed<F>(&mut self, output: &[u8], mut emit: F)
+    where
+        F: FnMut(&str),
+    {
+        self.pending.extend_from_slice(output);
+
+        let mut start = 0;
+        loop {
+            let rest = &self.pending[start..];
+            match rest.iter().position(|&b| b == b'\n') {
+                Some(end) if end <= MAX_LINE => {
+                    // A terminal turns every newline into \r\n.
+                    let line = &rest[..end];
+                    let line = line.strip_suffix(b"\r").unwrap_or(line);
+                    emit(&String::from_utf8_lossy(line));
+                    start += end + 1;
+                }
+                _ if rest.len() > MAX_LINE => {
+                    emit(&String::from_utf8_lossy(&rest[..MAX_LINE]));
+                    start += MAX_LINE;
+                }
+                _ => break,
+            }
+        }
+        self.pending.drain(..start);
+    }
+
+    pub fn finish<F>(&mut self, mut emit: F)
+    where
+        F: FnMut(&str),
+    {
+        if !self.pending.is_empty() {
+            emit(&String::from_utf8_lossy(&self.pending));
+            self.pending.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_times_counted_back_from_now() {
+        let since = LogOptions::parse_time("10m").unwrap();
+        let expected = Utc::now() - Duration::minutes(10);
+        assert!((expected - since).num_seconds().abs() < 5);
+    }
+
+    #[test]
+    fn rejects_out_of_range_times() {
+        for value in ["999999999999d", "9223372036854775807s", "1e300", "inf"] {
+            assert!(LogOptions::parse_time(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn splits_lines_without_newlines() {
+        let mut buffer = LineBuffer::default();
+        let mut lines = Vec::new();
+        let output = vec![b'a'; MAX_LINE * 2 + 10];
+        for chunk in output.chunks(4096) {
+            buffer.feed(chunk, |line| lines.push(line.len()));
+        }
+        assert_eq!(lines, [MAX_LINE, MAX_LINE]);
+        assert!(buffer.pending.len() <= MAX_LINE);
+
+        buffer.feed(b"b\r\nc", |line| lines.push(line.len()));
+        buffer.finish(|line| lines.push(line.len()));
+        assert_eq!(lines, [MAX_LINE, MAX_LINE, 11, 1]);
+    }
+}
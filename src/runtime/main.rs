@@ -1,142 +1,932 @@
 use crate::runtime::username;
 // src/runtime/main.rs
 use crate::PROGRAM_CMD;
+use crate::logging;
+use crate::network::dns::{self, DnsServer};
+use crate::network::proxy::Proxy;
+use crate::network::slirp::{self, RootlessNetwork, Slirp};
+use crate::network::{
+    self, DEFAULT_NETWORK, Network, NetworkAttachment, NetworkDriver, etc_files, nat, ports,
+};
 use crate::runtime::cgroups::CgroupManager;
 use crate::runtime::container::{Container, ContainerConfig, ContainerStatus, VolumeMount};
+use crate::runtime::exec::Exec;
 use crate::runtime::filesystem::Filesystem;
-use crate::runtime::namespace::Namespaces;
+use crate::runtime::image::{ImageOverrides, apply_image_config, parse_reference};
+use crate::runtime::log_drivers::create_log_driver;
+use crate::runtime::names::generate_name;
+use crate::runtime::namespace::{NamespaceKind, NamespaceMode, Namespaces};
+use crate::runtime::restart::RestartPolicy;
+use crate::runtime::signal::parse_signal;
+use crate::runtime::supervisor::{NetworkServices, Stdio, Supervisor, WaitCondition, Workload};
+use crate::runtime::sysctl::Sysctl;
+use crate::runtime::tty::Tty;
+use crate::storage::StorageConfig;
+use crate::storage::drivers::{DriverType, create_driver};
+use crate::storage::images::ImageManager;
+use crate::storage::storage::{
+    delete_container, find_container, get_container, get_container_dir, get_containers,
+    modify_container,
+};
+use crate::storage::volumes::{VolumeConfig, VolumeManager};
 use anyhow::{Result, anyhow};
+use chrono::Utc;
+use nix::fcntl::{FcntlArg, FdFlag, OFlag, fcntl};
 use nix::mount::{MsFlags, mount};
-use nix::sys::signal::Signal::SIGTERM;
-use nix::sys::signal::kill;
-use nix::unistd::{ForkResult, Pid, chdir, fork};
-use std::ffi::CString;
-use std::fs::create_dir_all;
-use std::path::Path;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use nix::sys::signal::{SigHandler, Signal, signal};
+use nix::sys::wait::waitpid;
+use nix::unistd::{ForkResult, Pid, Uid, chdir, fork, getpid, pipe2};
+use std::collections::HashMap;
+use std::fs::{File, create_dir_all, remove_dir_all};
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
 use uuid::Uuid;
 
 pub struct Runtime;
 
 impl Runtime {
-    pub fn create_container(config: ContainerConfig) -> Result<Container> {
-        let container_id = format!(
-            "{}-{}",
-            *PROGRAM_CMD,
-            Uuid::new_v4().to_string()[..8].to_string()
-        );
+    /// Checks the config and assigns the container an id, and a generated
+    /// name unless one is given.
+    pub fn create_container(
+        name: Option<String>,
+        mut config: ContainerConfig,
+    ) -> Result<Container> {
+        let containers = get_containers()?;
+        let name = match name {
+            Some(name) => {
+                Container::validate_name(&name)?;
+                if containers.iter().any(|c| c.name == name) {
+                    return Err(anyhow!("Container name {:?} is already in use", name));
+                }
+                name
+            }
+            None => generate_name(|name| containers.iter().any(|c| c.name == name)),
+        };
+        if config.auto_remove && config.restart_policy != RestartPolicy::No {
+            return Err(anyhow!(
+                "A container removed on exit cannot have a restart policy"
+            ));
+        }
+        for key in config.sysctls.keys() {
+            Sysctl::validate(key, &config.namespaces)?;
+        }
+        create_log_driver("", &config.log_config)?;
+        if let Some(check) = &config.health_check {
+            check.validate()?;
+        }
+        if let Some(stop_signal) = &config.stop_signal {
+            parse_signal(stop_signal)?;
+        }
+        Self::create_volumes(&config)?;
+
+        for kind in NamespaceKind::ALL {
+            if let Some(NamespaceMode::Container(reference)) = config.namespaces.mode_mut(kind) {
+                let target = find_container(reference)
+                    .map_err(|e| anyhow!("Cannot share {} namespace: {}", kind.proc_name(), e))?;
+                // Kept by id, which unlike the name never changes.
+                *reference = target.id;
+            }
+        }
+
+        // Containers sharing another namespace use its interfaces.
+        if *config.namespaces.mode(NamespaceKind::Net) == NamespaceMode::Private {
+            // Unprivileged users cannot plug containers into a host bridge.
+            if config.rootless_network.is_none()
+                && config.networks.is_empty()
+                && !Uid::effective().is_root()
+            {
+                config.rootless_network = Some(RootlessNetwork::Auto);
+            }
+            if let Some(rootless) = config.rootless_network {
+                if !config.networks.is_empty() {
+                    return Err(anyhow!("Networks cannot be joined with {}", rootless));
+                }
+                if !config.bandwidth.is_empty() {
+                    return Err(anyhow!(
+                        "Bandwidth limits cannot be applied with {}",
+                        rootless
+                    ));
+                }
+            } else {
+                if config.networks.is_empty() {
+                    config.networks.push(NetworkAttachment {
+                        network: DEFAULT_NETWORK.to_string(),
+                        ip: None,
+                        ip6: None,
+                        aliases: Vec::new(),
+                    });
+                }
+                let networks = network::resolve_attachments(&mut config.networks)?;
+                if !config.ports.is_empty()
+                    && networks.iter().all(|n| n.driver == NetworkDriver::None)
+                {
+                    return Err(anyhow!(
+                        "Ports can only be published on a network with addresses"
+                    ));
+                }
+                if !config.bandwidth.is_empty() {
+                    Self::check_bandwidth_networks(&networks)?;
+                }
+            }
+        } else if config.rootless_network.is_some() {
+            return Err(anyhow!(
+                "Rootless networking needs a private network namespace"
+            ));
+        } else if !config.networks.is_empty() {
+            return Err(anyhow!(
+                "Networks can only be joined with a private network namespace"
+            ));
+        } else if !config.ports.is_empty() {
+            return Err(anyhow!(
+                "Ports can only be published with a private network namespace"
+            ));
+        } else if !config.bandwidth.is_empty() {
+            return Err(anyhow!(
+                "Bandwidth limits need the container on a bridge network"
+            ));
+        }
+        ports::validate(&config.ports)?;
+
+        // Addresses are allocated last, callers release them with
+        // `discard_container` if storing the container fails.
+        let container_id = format!("{}-{}", *PROGRAM_CMD, &Uuid::new_v4().to_string()[..8]);
+        let networks = network::connect_all(&container_id, &config.networks)?;
 
         let container = Container {
             id: container_id.clone(),
+            name,
             pid: 0,
             status: ContainerStatus::Created,
             config,
+            created_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+            exit_code: None,
+            restart_count: 0,
+            health: None,
+            networks,
+            ports: Vec::new(),
         };
-        log::info!("Created container: {}", container_id);
+        log::info!("Created container: {} ({})", container_id, container.name);
         log::info!("Container name is {:?}", container);
         Ok(container)
     }
 
-    pub fn start_container(container: &mut Container) -> Result<()> {
+    /// Creates a container running `reference`, an image given as
+    /// `name[:tag]`, on a snapshot of the image's rootfs made by the
+    /// configured storage driver.
+    pub fn create_from_image(
+        reference: &str,
+        name: Option<String>,
+        mut config: ContainerConfig,
+        overrides: &ImageOverrides,
+    ) -> Result<Container> {
+        let (image_name, tag) = parse_reference(reference)?;
+        let images = ImageManager::new(StorageConfig::default().images_dir)?;
+        let image = images.get(&image_name, &tag).ok_or_else(|| {
+            anyhow!(
+                "Image {}:{} not found, import it with {} image import",
+                image_name,
+                tag,
+                *PROGRAM_CMD
+            )
+        })?;
+        // Snapshots are taken of a single directory.
+        let [layer] = image.layers.as_slice() else {
+            return Err(anyhow!(
+                "Image {}:{} has {} layers, only single layer images are supported",
+                image_name,
+                tag,
+                image.layers.len()
+            ));
+        };
+        let driver_type = config.storage_driver.parse::<DriverType>()?;
+        config.image = Some(format!("{}:{}", image_name, tag));
+
+        let mut container = Self::create_container(name, config)?;
+        let driver = create_driver(driver_type, StorageConfig::default().root)?;
+        let prepared = driver
+            .create_snapshot(&container.id, Path::new(layer))
+            .and_then(|rootfs| {
+                container.config.rootfs = rootfs;
+                apply_image_config(&mut container.config, &image.config, overrides)
+            });
+        if let Err(e) = prepared {
+            Self::discard_container(&container);
+            return Err(e);
+        }
+        Ok(container)
+    }
+
+    /// Undoes `create_container` or `create_from_image` for a container
+    /// that was not stored: releases its addresses and removes the snapshot
+    /// of its image.
+    pub fn discard_container(container: &Container) {
+        if let Err(e) = network::disconnect_all(&container.id) {
+            log::warn!("Failed to release addresses of {}: {}", container.id, e);
+        }
+        if container.config.image.is_none() {
+            return;
+        }
+        let removed = container
+            .config
+            .storage_driver
+            .parse::<DriverType>()
+            .and_then(|driver_type| create_driver(driver_type, StorageConfig::default().root))
+            .and_then(|driver| {
+                if driver.exists(&container.id) {
+                    driver.remove_snapshot(&container.id)
+                } else {
+                    Ok(())
+                }
+            });
+        if let Err(e) = removed {
+            log::warn!("Failed to remove snapshot of {}: {}", container.id, e);
+        }
+    }
+
+    /// Starts the container's supervisor. With `hold_output` its output is
+    /// held back until a client attaches, so that none is missed by the
+    /// attach following the start.
+    pub fn start_container(container: &mut Container, hold_output: bool) -> Result<()> {
+        if container.status == ContainerStatus::Running && Exec::running_pid(container).is_ok() {
+            return Err(anyhow!("Container {} is already running", container.id));
+        }
+
         log::info!("Starting container: {}", container.id);
         username();
 
-        Filesystem::create_rootfs(&container.config.rootfs).unwrap();
+        Filesystem::create_rootfs(&container.config.rootfs)?;
+        // The supervisor does not stay in the directory the CLI was run from.
+        container.config.rootfs = std::path::absolute(&container.config.rootfs)?;
+        let joins = Self::resolve_namespace_joins(container)?;
+
+        *container = Supervisor::spawn(container, &joins, hold_output)?;
 
-        match unsafe { fork() } {
-            Ok(ForkResult::Parent { child, .. }) => {
-                container.pid = child.as_raw();
-                container.status = ContainerStatus::Running;
+        log::info!(
+            "Container {} started with PID: {}",
+            container.id,
+            container.pid
+        );
+        Ok(())
+    }
 
-                let cgroup_manager = CgroupManager::new(&container.id).unwrap();
+    /// Starts the container's process and returns once it runs in the
+    /// container's cgroup and namespaces. Called by the supervisor, which
+    /// keeps the returned stdio descriptors.
+    pub fn spawn_workload(
+        container: &Container,
+        joins: &[(NamespaceKind, PathBuf)],
+    ) -> Result<Workload> {
+        // Mounted by the workload, the addresses are known by now.
+        etc_files::write(container)?;
 
-                cgroup_manager.add_process(child.as_raw()).unwrap();
-                // cgroup_manager.set_memory_limit(512).unwrap();
-                username();
-                // cgroup_manager.set_cpu_quota(100).unwrap();
+        let (pid_rx, pid_tx) =
+            pipe2(OFlag::O_CLOEXEC).map_err(|e| anyhow!("Failed to create pipe: {}", e))?;
+        let (spec_rx, spec_tx) =
+            pipe2(OFlag::O_CLOEXEC).map_err(|e| anyhow!("Failed to create pipe: {}", e))?;
+        let (resume_rx, resume_tx) =
+            pipe2(OFlag::O_CLOEXEC).map_err(|e| anyhow!("Failed to create pipe: {}", e))?;
+
+        // Descriptors kept by the supervisor and the ones handed to the
+        // workload as stdin, stdout and stderr.
+        let (console_rx, console_tx) = if container.config.tty {
+            let (rx, tx) = UnixStream::pair()?;
+            (Some(rx), Some(tx))
+        } else {
+            (None, None)
+        };
+        let pipes = if container.config.tty {
+            None
+        } else {
+            let stdin = if container.config.interactive {
+                Some(pipe2(OFlag::O_CLOEXEC)?)
+            } else {
+                None
+            };
+            Some((stdin, pipe2(OFlag::O_CLOEXEC)?, pipe2(OFlag::O_CLOEXEC)?))
+        };
 
-                log::info!("Container {} started with PID: {}", container.id, child);
+        // The supervisor runs threads, a forked copy of it could deadlock on
+        // a lock held by one of them. So the workload is set up by a new
+        // `nb` process, which gets its end of the pipes by number.
+        let inherited = [
+            ("--spec", spec_rx.as_raw_fd()),
+            ("--pid", pid_tx.as_raw_fd()),
+            ("--resume", resume_rx.as_raw_fd()),
+            (
+                "--console",
+                console_tx.as_ref().map_or(-1, |tx| tx.as_raw_fd()),
+            ),
+        ];
+        let mut command = Command::new("/proc/self/exe");
+        command.arg("init-workload");
+        for (name, fd) in inherited.iter().filter(|(_, fd)| *fd >= 0) {
+            command.args([name.to_string(), fd.to_string()]);
+        }
+        if let Some((stdin, (_, stdout), (_, stderr))) = &pipes {
+            command
+                .stdin(match stdin {
+                    Some((rx, _)) => process::Stdio::from(rx.try_clone()?),
+                    None => process::Stdio::null(),
+                })
+                .stdout(stdout.try_clone()?)
+                .stderr(stderr.try_clone()?);
+        }
+        // SAFETY: the child only makes system calls before it executes.
+        unsafe {
+            command.pre_exec(move || {
+                for (_, fd) in inherited.into_iter().filter(|(_, fd)| *fd >= 0) {
+                    if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        let child = command
+            .spawn()
+            .map_err(|e| anyhow!("Failed to start container process: {}", e))?;
+        let child = Pid::from_raw(child.id() as i32);
+        drop(command);
+        drop(spec_rx);
+        drop(pid_tx);
+        drop(resume_rx);
+        drop(console_tx);
+
+        // Processes forked inside the container inherit the cgroup, so the
+        // child waits for its spec until it has been moved.
+        let cgroup_manager = CgroupManager::new(&container.id)?;
+        cgroup_manager.add_process(child.as_raw())?;
+        // cgroup_manager.set_memory_limit(512).unwrap();
+        // cgroup_manager.set_cpu_quota(100).unwrap();
+        serde_json::to_writer(File::from(spec_tx), &(container, joins))?;
+
+        let mut buf = [0u8; 4];
+        if let Err(e) = File::from(pid_rx).read_exact(&mut buf) {
+            let _ = waitpid(child, None);
+            return Err(anyhow!("Container {} failed to start: {}", container.id, e));
+        }
+        let pid = i32::from_ne_bytes(buf);
+
+        let user = container.config.namespaces.mode(NamespaceKind::User);
+        if *user == NamespaceMode::Private
+            && let Err(e) = Namespaces::write_id_maps(pid)
+        {
+            drop(resume_tx);
+            let _ = waitpid(child, None);
+            return Err(e);
+        }
+
+        // The workload waits for its network before it goes on, and
+        // gives up once the pipe is closed without it.
+        let net = container.config.namespaces.mode(NamespaceKind::Net);
+        let network = if *net == NamespaceMode::Private {
+            match Self::connect_workload(container, pid) {
+                Ok(network) => network,
+                Err(e) => {
+                    drop(resume_tx);
+                    let _ = waitpid(child, None);
+                    return Err(e);
+                }
             }
-            Ok(ForkResult::Child) => {
-                Self::container_process(container).unwrap();
-                std::process::exit(0);
+        } else {
+            NetworkServices::default()
+        };
+        File::from(resume_tx).write_all(&[0])?;
+
+        let stdio = match (console_rx, pipes) {
+            (Some(console), _) => {
+                let master = Tty::recv_fd(&console).map_err(|e| {
+                    anyhow!("Container {} failed to open a TTY: {}", container.id, e)
+                })?;
+                Stdio::Console(File::from(master))
             }
-            Err(e) => return Err(anyhow!("Failed to fork process: {}", e)),
+            (None, Some((stdin, (stdout, _), (stderr, _)))) => Stdio::Pipes {
+                stdin: stdin.map(|(_, tx)| File::from(tx)),
+                stdout: File::from(stdout),
+                stderr: File::from(stderr),
+            },
+            (None, None) => unreachable!(),
+        };
+
+        Ok(Workload {
+            init: child,
+            pid,
+            stdio,
+            network,
+        })
+    }
+
+    /// Sets up the network of a workload with its own namespace and
+    /// publishes its ports. Returns the services that keep it connected:
+    /// the rootless network stack, or the proxy for ports that need one and
+    /// the embedded DNS server.
+    fn connect_workload(container: &Container, pid: i32) -> Result<NetworkServices> {
+        let mut services = NetworkServices::default();
+        let started = if let Some(rootless) = container.config.rootless_network {
+            nat::reserve(container, IpAddr::V4(slirp::GUEST_ADDRESS)).and_then(|ports| {
+                services.slirp = Some(Slirp::start(&container.id, rootless, pid, &ports)?);
+                Ok(())
+            })
+        } else {
+            network::setup(container, pid)?;
+            nat::publish(container).and_then(|proxied| {
+                if !proxied.is_empty() {
+                    services.proxy = Some(Proxy::start(pid, &proxied)?);
+                }
+                if dns::enabled(container) {
+                    services.dns = Some(DnsServer::start(container, pid)?);
+                }
+                Ok(())
+            })
+        };
+        if let Err(e) = started {
+            let _ = nat::unpublish(&container.id);
+            return Err(e);
         }
+        Ok(services)
+    }
 
-        Ok(())
+    /// Runs `nb init-workload`, started by `spawn_workload` with the
+    /// workload's stdio and the descriptors of its pipes. Reads the container
+    /// and the namespaces to join once it was moved into the cgroup, and
+    /// sets up and executes the workload.
+    pub fn init_workload(
+        spec: RawFd,
+        pid: RawFd,
+        resume: RawFd,
+        console: Option<RawFd>,
+    ) -> Result<()> {
+        let spec = File::from(Self::inherit_fd(spec)?);
+        let pid_tx = File::from(Self::inherit_fd(pid)?);
+        let resume = File::from(Self::inherit_fd(resume)?);
+        let console = console
+            .map(|fd| Self::inherit_fd(fd).map(UnixStream::from))
+            .transpose()?;
+
+        let (container, joins): (Container, Vec<(NamespaceKind, PathBuf)>) =
+            serde_json::from_reader(spec).map_err(|_| anyhow!("Container setup was aborted"))?;
+        logging::set_container_id(&container.id);
+
+        // Rust ignores SIGPIPE, which would otherwise be inherited by the
+        // workload.
+        unsafe { signal(Signal::SIGPIPE, SigHandler::SigDfl) }?;
+
+        Self::container_process(&container, &joins, pid_tx, resume, console).inspect_err(|e| {
+            log::error!("Container {} failed: {}", container.id, e);
+        })
     }
 
-    fn container_process(container: &Container) -> Result<()> {
-        Namespaces::unshare_all().unwrap();
-        Namespaces::set_hosename(&container.config.hostname).unwrap();
+    /// Takes ownership of a descriptor passed on by the supervisor, which is
+    /// closed again when the workload executes.
+    fn inherit_fd(fd: RawFd) -> Result<OwnedFd> {
+        // SAFETY: the descriptor is handed to this process alone, nothing
+        // else owns it.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        fcntl(&fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+            .map_err(|e| anyhow!("Invalid descriptor {}: {}", fd.as_raw_fd(), e))?;
+        Ok(fd)
+    }
 
-        Filesystem::setup_rootfs(&container.config.rootfs).unwrap();
+    /// Maps every namespace configured to join a container or a path to the
+    /// namespace file that has to be passed to `setns`.
+    fn resolve_namespace_joins(container: &Container) -> Result<Vec<(NamespaceKind, PathBuf)>> {
+        let mut joins = Vec::new();
 
-        if container.config.uid != 0 {
-            Namespaces::drop_privileges(container.config.uid, container.config.gid).unwrap();
+        for kind in NamespaceKind::ALL {
+            match container.config.namespaces.mode(kind) {
+                NamespaceMode::Container(id) => {
+                    let target = get_container(id)?;
+                    if target.status != ContainerStatus::Running || target.pid <= 0 {
+                        return Err(anyhow!(
+                            "Cannot join {} namespace of container {}, it is not running",
+                            kind.proc_name(),
+                            id
+                        ));
+                    }
+                    joins.push((
+                        kind,
+                        PathBuf::from(format!("/proc/{}/ns/{}", target.pid, kind.proc_name())),
+                    ));
+                }
+                NamespaceMode::Path(path) => {
+                    if !path.exists() {
+                        return Err(anyhow!(
+                            "{} namespace path {:?} does not exist",
+                            kind.proc_name(),
+                            path
+                        ));
+                    }
+                    joins.push((kind, path.clone()));
+                }
+                NamespaceMode::Private | NamespaceMode::Host => {}
+            }
         }
 
-        chdir(&container.config.working_dirs)
-            .map_err(|e| anyhow!("Failed to change directory: {}", e))
-            .unwrap();
+        Ok(joins)
+    }
+
+    fn container_process(
+        container: &Container,
+        joins: &[(NamespaceKind, PathBuf)],
+        mut pid_tx: File,
+        mut resume: File,
+        console: Option<UnixStream>,
+    ) -> Result<()> {
+        let namespaces = &container.config.namespaces;
+        Namespaces::enter(namespaces, joins)?;
+
+        if namespaces.needs_fork() {
+            // SAFETY: the process is single threaded at this point.
+            match unsafe { fork() } {
+                Ok(ForkResult::Parent { child }) => {
+                    pid_tx.write_all(&child.as_raw().to_ne_bytes())?;
+                    drop(pid_tx);
+                    drop(resume);
+                    drop(console);
+
+                    std::process::exit(Exec::wait_for(child));
+                }
+                Ok(ForkResult::Child) => drop(pid_tx),
+                Err(e) => return Err(anyhow!("Failed to fork container init: {}", e)),
+            }
+        } else {
+            pid_tx.write_all(&getpid().as_raw().to_ne_bytes())?;
+            drop(pid_tx);
+        }
+
+        let mut go = [0u8; 1];
+        resume
+            .read_exact(&mut go)
+            .map_err(|_| anyhow!("Container setup was aborted"))?;
+        drop(resume);
+
+        if *namespaces.mode(NamespaceKind::Uts) == NamespaceMode::Private {
+            Namespaces::set_hosename(&container.config.hostname)?;
+        }
+
+        Filesystem::setup_rootfs(&container.config.rootfs).unwrap();
+
+        if let Some(console) = console {
+            Tty::setup_devpts(&container.config.rootfs)?;
+            let master = Tty::open_console(&container.config.rootfs)?;
+            Tty::send_fd(&console, master.as_fd())?;
+        }
+        Sysctl::apply(
+            Path::new("/proc/sys"),
+            &container.config.sysctls,
+            namespaces,
+        )?;
 
         for volume_mount in &container.config.volumes {
-            Self::mount_volume(volume_mount, &container.id)?;
+            Self::mount_volume(volume_mount, &container.config.rootfs)?;
+        }
+        etc_files::mount_files(&container.id, &container.config.rootfs)?;
+
+        Filesystem::pivot_root(&container.config.rootfs)?;
+
+        if container.config.uid != 0 {
+            Namespaces::drop_privileges(container.config.uid, container.config.gid)?;
         }
 
-        let _command = CString::new(container.config.command[0].as_bytes())
-            .map_err(|e| anyhow!("invalid command: {}", e))
+        chdir(&container.config.working_dirs)
+            .map_err(|e| anyhow!("Failed to change directory: {}", e))
             .unwrap();
 
-        let _args: Vec<CString> = container
+        let argv: Vec<String> = container
             .config
             .command
             .iter()
             .chain(container.config.args.iter())
-            .map(|arg| CString::new(arg.as_bytes()).unwrap())
+            .cloned()
             .collect();
 
-        let _env: Vec<CString> = container
-            .config
-            .env_vars
-            .iter()
-            .map(|env| CString::new(env.as_bytes()).unwrap())
-            .collect();
+        Exec::replace_process(&argv, &container.config.env_vars)
+    }
+
+    /// Sends the container's stop signal and waits up to `timeout` seconds
+    /// for it to exit before killing it. Returns once the supervisor recorded
+    /// the exit and cleaned up the container.
+    pub fn stop_container(container: &mut Container, timeout: u64) -> Result<()> {
+        let restarting = container.status == ContainerStatus::Restarting;
+        if container.status != ContainerStatus::Running && !restarting {
+            return Err(anyhow!("Container is not running"));
+        }
 
-        // execve(&command, &args, &env)
-        // .map_err(|e| anyhow!("Failed to execute command: {}", e))
-        // .unwrap();
+        let signal = container.config.stop_signal()?;
+        log::info!("Stopping container {} with {}", container.id, signal);
 
+        // Marked first so the supervisor records the exit as a stop and does
+        // not apply the restart policy.
+        modify_container(&container.id, |c| c.status = ContainerStatus::Stopped)?;
+        match Supervisor::stop(&container.id, signal, timeout) {
+            // The supervisor may have given up on restarting and exited.
+            Err(_) if restarting => {}
+            result => result.map_err(|e| anyhow!("Failed to stop container: {}", e))?,
+        }
+        *container = get_container(&container.id)?;
         Ok(())
     }
 
-    pub fn stop_container(container: &mut Container) -> Result<()> {
-        if container.status != ContainerStatus::Running {
-            return Err(anyhow!("Container is not running"));
+    /// Blocks until `condition` holds for the container and returns its last
+    /// exit code. Running containers are waited on through their supervisor.
+    /// Watching the container's directory tells when a supervisor starts, for
+    /// `next-exit`, and when the container is removed.
+    pub fn wait_container(container_id: &str, condition: WaitCondition) -> Result<Option<i32>> {
+        get_container(container_id)?;
+        let dir = get_container_dir(container_id);
+        create_dir_all(&dir)?;
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC)
+            .map_err(|e| anyhow!("Failed to set up inotify: {}", e))?;
+        inotify
+            .add_watch(
+                &dir,
+                AddWatchFlags::IN_MOVED_TO | AddWatchFlags::IN_DELETE_SELF,
+            )
+            .map_err(|e| anyhow!("Failed to watch {:?}: {}", dir, e))?;
+        // Checked after the watch is set up, so no removal goes unnoticed.
+        get_container(container_id)?;
+
+        let socket_path = Supervisor::socket_path(container_id);
+        if condition == WaitCondition::NextExit {
+            while !socket_path.exists() {
+                if Self::removed(&inotify)? {
+                    return Err(anyhow!("Container {} was removed", container_id));
+                }
+            }
         }
 
-        log::info!("Stopping container: {}", container.id);
+        let exit_code = match Supervisor::wait(container_id, condition) {
+            Ok(exit_code) => exit_code,
+            Err(e) => {
+                let container = get_container(container_id)?;
+                let running = matches!(
+                    container.status,
+                    ContainerStatus::Running | ContainerStatus::Restarting
+                );
+                if running || condition == WaitCondition::NextExit {
+                    return Err(e);
+                }
+                container.exit_code
+            }
+        };
 
-        kill(Pid::from_raw(container.pid), SIGTERM)
-            .map_err(|e| anyhow!("Failed to send SIFTERM to container: {}", e))
-            .unwrap();
+        if condition == WaitCondition::Removed {
+            while get_container(container_id).is_ok() {
+                if Self::removed(&inotify)? {
+                    break;
+                }
+            }
+        }
+        Ok(exit_code)
+    }
 
-        container.status = ContainerStatus::Stopped;
+    /// Reads the next batch of events from the watch on a container's
+    /// directory and returns whether the directory is gone.
+    fn removed(inotify: &Inotify) -> Result<bool> {
+        let events = inotify
+            .read_events()
+            .map_err(|e| anyhow!("Failed to read inotify events: {}", e))?;
+        Ok(events.iter().any(|event| {
+            event
+                .mask
+                .intersects(AddWatchFlags::IN_DELETE_SELF | AddWatchFlags::IN_IGNORED)
+        }))
+    }
 
-        let cgroup_manager = CgroupManager::new(&container.id).unwrap();
-        cgroup_manager.cleanup().unwrap();
+    /// Creates the volumes the container mounts that do not exist yet.
+    fn create_volumes(config: &ContainerConfig) -> Result<()> {
+        if config.volumes.iter().all(VolumeMount::is_bind) {
+            return Ok(());
+        }
 
+        let mut manager = VolumeManager::new(StorageConfig::default().volumes_dir)?;
+        for volume in config.volumes.iter().filter(|v| !v.is_bind()) {
+            if manager.get(&volume.source).is_none() {
+                manager.create(VolumeConfig {
+                    name: volume.source.clone(),
+                    driver: "local".to_string(),
+                    labels: HashMap::new(),
+                    options: HashMap::new(),
+                })?;
+            }
+        }
         Ok(())
     }
 
-    fn mount_volume(volume_mount: &VolumeMount, _container_id: &str) -> Result<()> {
-        let target_path = Path::new(&volume_mount.target);
+    /// Removes a container that is not running, or kills it first with
+    /// `force`: its cgroup, storage snapshot, logs and metadata, and with
+    /// `volumes` the anonymous volumes created for it, and its addresses.
+    pub fn remove_container(container: &Container, force: bool, volumes: bool) -> Result<()> {
+        if matches!(
+            container.status,
+            ContainerStatus::Running | ContainerStatus::Restarting
+        ) {
+            if !force {
+                return Err(anyhow!(
+                    "Container {} is running, stop it first or use --force",
+                    container.id
+                ));
+            }
+            // Without a timeout the container is killed right away.
+            if let Err(e) = Self::stop_container(&mut container.clone(), 0) {
+                log::warn!("Failed to stop container {}: {}", container.id, e);
+            }
+        }
+
+        log::info!("Removing container: {}", container.id);
+
+        let cgroup = CgroupManager::new(&container.id)?;
+        cgroup.kill_all()?;
+        cgroup.cleanup()?;
+
+        // Containers on a plain rootfs directory have no snapshot.
+        if let Ok(driver_type) = container.config.storage_driver.parse::<DriverType>() {
+            let driver = create_driver(driver_type, StorageConfig::default().root)?;
+            if driver.exists(&container.id) {
+                driver.remove_snapshot(&container.id)?;
+            }
+        }
+
+        network::disconnect_all(&container.id)?;
+
+        if volumes && container.config.volumes.iter().any(|v| v.anonymous) {
+            let mut manager = VolumeManager::new(StorageConfig::default().volumes_dir)?;
+            for volume in container.config.volumes.iter().filter(|v| v.anonymous) {
+                if let Err(e) = manager.remove(&volume.source) {
+                    log::warn!("Failed to remove volume {}: {}", volume.source, e);
+                }
+            }
+        }
+
+        delete_container(&container.id)?;
+        // Left behind when the supervisor did not see the container exit.
+        if !container.ports.is_empty()
+            && let Err(e) = nat::sync()
+        {
+            log::warn!("Failed to withdraw ports of {}: {}", container.id, e);
+        }
+
+        // Logs and the control socket, removed last as `nb wait --condition
+        // removed` watches for it.
+        let dir = get_container_dir(&container.id);
+        if dir.exists() {
+            remove_dir_all(&dir).map_err(|e| anyhow!("Failed to remove {:?}: {}", dir, e))?;
+        }
+        Ok(())
+    }
+
+    /// Connects a container to a network. A running container gets its
+    /// interface on the network right away, others when they start.
+    pub fn connect_network(
+        container: &Container,
+        mut attachment: NetworkAttachment,
+    ) -> Result<Container> {
+        if *container.config.namespaces.mode(NamespaceKind::Net) != NamespaceMode::Private {
+            return Err(anyhow!(
+                "Container {} does not have a network namespace of its own",
+                container.id
+            ));
+        }
+        if let Some(rootless) = container.config.rootless_network {
+            return Err(anyhow!(
+                "Container {} is connected through {}",
+                container.id,
+                rootless
+            ));
+        }
+        let name = network::store::get_network(&attachment.network)?.name;
+        if Self::is_connected(container, &name) {
+            return Err(anyhow!(
+                "Container {} is already connected to network {}",
+                container.id,
+                name
+            ));
+        }
+        let mut attachments = container.config.networks.clone();
+        attachments.push(attachment.clone());
+        let networks = network::resolve_attachments(&mut attachments)?;
+        if !container.config.bandwidth.is_empty() {
+            Self::check_bandwidth_networks(&networks)?;
+        }
+        attachment.network = name;
+
+        // Interfaces left by a disconnect are numbered again.
+        let index = (0..)
+            .find(|i| {
+                let interface = format!("eth{}", i);
+                !container.networks.iter().any(|e| e.interface == interface)
+            })
+            .unwrap_or_default();
+        let endpoint = network::connect(&container.id, &attachment, index)?;
+        if let Some(endpoint) = &endpoint
+            && container.status == ContainerStatus::Running
+            && container.pid > 0
+            && let Err(e) = network::setup_endpoint(container, endpoint, container.pid)
+        {
+            let _ = network::disconnect(&container.id, &attachment.network);
+            return Err(e);
+        }
+
+        log::info!(
+            "Connected {} to network {}",
+            container.id,
+            attachment.network
+        );
+        let container = modify_container(&container.id, |c| {
+            c.config.networks.push(attachment);
+            c.networks.extend(endpoint);
+        })?;
+        Self::update_etc_files(&container);
+        Ok(container)
+    }
+
+    /// Bandwidth limits apply to the host end of a veth pair, so a container
+    /// with limits has to be on exactly one bridge network for them to hold
+    /// for all of its traffic.
+    fn check_bandwidth_networks(networks: &[Network]) -> Result<()> {
+        match networks
+            .iter()
+            .filter(|n| n.driver == NetworkDriver::Bridge)
+            .count()
+        {
+            0 => Err(anyhow!(
+                "Bandwidth limits need the container on a bridge network"
+            )),
+            1 => Ok(()),
+            _ => Err(anyhow!(
+                "Bandwidth limits cannot be applied on more than one bridge network"
+            )),
+        }
+    }
+
+    /// Disconnects a container from a network, removing its interface if it
+    /// is running, and releases its address there.
+    pub fn disconnect_network(container: &Container, reference: &str) -> Result<Container> {
+        let name = network::store::get_network(reference)?.name;
+        if !Self::is_connected(container, &name) {
+            return Err(anyhow!(
+                "Container {} is not connected to network {}",
+                container.id,
+                name
+            ));
+        }
+
+        if let Some(endpoint) = container.networks.iter().find(|e| e.network == name)
+            && container.status == ContainerStatus::Running
+            && container.pid > 0
+        {
+            network::teardown_endpoint(endpoint, container.pid)?;
+        }
+        network::disconnect(&container.id, &name)?;
+
+        log::info!("Disconnected {} from network {}", container.id, name);
+        let container = modify_container(&container.id, |c| {
+            c.config.networks.retain(|a| a.network != name);
+            c.networks.retain(|e| e.network != name);
+        })?;
+        Self::update_etc_files(&container);
+        Ok(container)
+    }
+
+    /// Lists a running container's new addresses in its hosts file.
+    fn update_etc_files(container: &Container) {
+        if container.status == ContainerStatus::Running
+            && let Err(e) = etc_files::write(container)
+        {
+            log::warn!("Failed to update /etc files of {}: {}", container.id, e);
+        }
+    }
+
+    fn is_connected(container: &Container, network: &str) -> bool {
+        container
+            .config
+            .networks
+            .iter()
+            .any(|a| a.network == network)
+            || container.networks.iter().any(|e| e.network == network)
+    }
+
+    fn mount_volume(volume_mount: &VolumeMount, rootfs: &Path) -> Result<()> {
+        let target_path = &rootfs.join(
+            volume_mount
+                .target
+                .strip_prefix("/")
+                .unwrap_or(&volume_mount.target),
+        );
 
         if !target_path.exists() {
             create_dir_all(target_path)?;
         }
 
-        if volume_mount.source.starts_with('/') {
+        if volume_mount.is_bind() {
             mount(
                 Some(Path::new(&volume_mount.source)),
                 target_path,
@@ -145,14 +935,15 @@ impl Runtime {
                 None::<&str>,
             )?;
         } else {
-            todo!();
+            VolumeManager::new(StorageConfig::default().volumes_dir)?
+                .mount(&volume_mount.source, target_path)?;
         }
         if volume_mount.read_only {
             mount(
                 Some(target_path),
                 target_path,
                 None::<&str>,
-                MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+                MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
                 None::<&str>,
             )?;
         }
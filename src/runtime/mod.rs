@@ -3,8 +3,20 @@
 use nix::unistd::{Uid, User};
 
 pub mod cgroups;
+pub mod exec;
 pub mod filesystem;
+pub mod health;
+pub mod image;
+pub mod log_drivers;
+pub mod logs;
+pub mod names;
 pub mod namespace;
+pub mod restart;
+pub mod signal;
+pub mod supervisor;
+pub mod sysctl;
+pub mod tty;
+pub mod users;
 
 pub mod container;
 pub mod main;
@@ -16,7 +28,7 @@ fn get_username() -> Option<String> {
 
 pub fn username() {
     match get_username() {
-        Some(user) => println!("Current user: {}", user),
-        None => println!("Unable to get username"),
+        Some(user) => log::debug!("Current user: {}", user),
+        None => log::debug!("Unable to get username"),
     }
 }
@@ -0,0 +1,45 @@
+// src/runtime/names.rs
+use uuid::Uuid;
+
+const ADJECTIVES: [&str; 48] = [
+    "admiring", "agile", "amazing", "bold", "brave", "bright", "calm", "clever", "cosmic", "crisp",
+    "curious", "daring", "eager", "elegant", "epic", "fervent", "fond", "frosty", "gentle",
+    "glowing", "happy", "hopeful", "humble", "jolly", "keen", "kind", "lucid", "lunar", "mellow",
+    "modest", "nimble", "noble", "pensive", "polar", "quiet", "radiant", "serene", "sharp",
+    "silent", "solar", "stellar", "swift", "tender", "upbeat", "vibrant", "vivid", "wise",
+    "zealous",
+];
+
+const NOUNS: [&str; 48] = [
+    "altair", "antares", "aurora", "bootes", "blazar", "cassini", "comet", "corona", "cosmos",
+    "deneb", "eclipse", "equinox", "galaxy", "halley", "horizon", "hubble", "kepler", "lyra",
+    "magnetar", "meteor", "nadir", "nebula", "neutron", "nova", "orbit", "orion", "parsec",
+    "perseus", "photon", "polaris", "pulsar", "quasar", "rigel", "saturn", "sirius", "solstice",
+    "spica", "stardust", "sagan", "titan", "umbra", "vega", "voyager", "zenith", "apollo",
+    "gemini", "hyperion", "io",
+];
+
+/// Tries with a bare adjective_noun before adding a number to it.
+const PLAIN_ATTEMPTS: u32 = 10;
+
+/// Generates an `adjective_noun` name for a container created without one,
+/// such as `stellar_orion`. `taken` tells which names are in use.
+pub fn generate_name(taken: impl Fn(&str) -> bool) -> String {
+    let mut attempt = 0;
+    loop {
+        let random = Uuid::new_v4();
+        let bytes = random.as_bytes();
+        let adjective = ADJECTIVES[bytes[0] as usize % ADJECTIVES.len()];
+        let noun = NOUNS[bytes[1] as usize % NOUNS.len()];
+
+        let name = if attempt < PLAIN_ATTEMPTS {
+            format!("{}_{}", adjective, noun)
+        } else {
+            format!("{}_{}{}", adjective, noun, bytes[2] % 100)
+        };
+        if !taken(&name) {
+            return name;
+        }
+        attempt += 1;
+    }
+}
@@ -1,38 +1,301 @@
 // src/runtime/namespace.rs
 use anyhow::{Result, anyhow};
-// use nix::libc::uname;
-use nix::sched::{CloneFlags, unshare};
+use nix::sched::{CloneFlags, setns, unshare};
 use nix::unistd::{Gid, Uid, setgid, sethostname, setuid};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum NamespaceKind {
+    Mount,
+    Uts,
+    Ipc,
+    Pid,
+    Net,
+    User,
+    Cgroup,
+    Time,
+}
+
+impl NamespaceKind {
+    // The user namespace comes first so that namespaces joined or created
+    // afterwards are owned by it.
+    pub const ALL: [NamespaceKind; 8] = [
+        NamespaceKind::User,
+        NamespaceKind::Mount,
+        NamespaceKind::Uts,
+        NamespaceKind::Ipc,
+        NamespaceKind::Pid,
+        NamespaceKind::Net,
+        NamespaceKind::Cgroup,
+        NamespaceKind::Time,
+    ];
+
+    pub fn clone_flag(&self) -> CloneFlags {
+        match self {
+            NamespaceKind::Mount => CloneFlags::CLONE_NEWNS,
+            NamespaceKind::Uts => CloneFlags::CLONE_NEWUTS,
+            NamespaceKind::Ipc => CloneFlags::CLONE_NEWIPC,
+            NamespaceKind::Pid => CloneFlags::CLONE_NEWPID,
+            NamespaceKind::Net => CloneFlags::CLONE_NEWNET,
+            NamespaceKind::User => CloneFlags::CLONE_NEWUSER,
+            NamespaceKind::Cgroup => CloneFlags::CLONE_NEWCGROUP,
+            NamespaceKind::Time => CloneFlags::from_bits_retain(libc::CLONE_NEWTIME),
+        }
+    }
+
+    /// Name of the namespace entry under `/proc/<pid>/ns`.
+    pub fn proc_name(&self) -> &'static str {
+        match self {
+            NamespaceKind::Mount => "mnt",
+            NamespaceKind::Uts => "uts",
+            NamespaceKind::Ipc => "ipc",
+            NamespaceKind::Pid => "pid",
+            NamespaceKind::Net => "net",
+            NamespaceKind::User => "user",
+            NamespaceKind::Cgroup => "cgroup",
+            NamespaceKind::Time => "time",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum NamespaceMode {
+    /// A new namespace is created for the container.
+    Private,
+    /// The host's namespace is shared.
+    Host,
+    /// The namespace of another container is joined.
+    Container(String),
+    /// The namespace referenced by a path (e.g. `/run/netns/foo`) is joined.
+    Path(PathBuf),
+}
+
+impl FromStr for NamespaceMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "private" => Ok(NamespaceMode::Private),
+            "host" => Ok(NamespaceMode::Host),
+            _ => {
+                if let Some(id) = value.strip_prefix("container:") {
+                    if id.is_empty() {
+                        return Err(anyhow!("Missing container id in {:?}", value));
+                    }
+                    Ok(NamespaceMode::Container(id.to_string()))
+                } else if value.starts_with('/') {
+                    Ok(NamespaceMode::Path(PathBuf::from(value)))
+                } else {
+                    Err(anyhow!(
+                        "Invalid namespace mode {:?}, expected private, host, container:<id> or a path",
+                        value
+                    ))
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for NamespaceMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NamespaceMode::Private => write!(f, "private"),
+            NamespaceMode::Host => write!(f, "host"),
+            NamespaceMode::Container(id) => write!(f, "container:{}", id),
+            NamespaceMode::Path(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NamespaceConfig {
+    pub uts: NamespaceMode,
+    pub ipc: NamespaceMode,
+    pub pid: NamespaceMode,
+    pub net: NamespaceMode,
+    pub user: NamespaceMode,
+    pub cgroup: NamespaceMode,
+    pub time: NamespaceMode,
+}
+
+impl Default for NamespaceConfig {
+    fn default() -> Self {
+        Self {
+            uts: NamespaceMode::Private,
+            ipc: NamespaceMode::Private,
+            pid: NamespaceMode::Private,
+            net: NamespaceMode::Private,
+            user: NamespaceMode::Private,
+            cgroup: NamespaceMode::Host,
+            time: NamespaceMode::Host,
+        }
+    }
+}
+
+impl NamespaceConfig {
+    /// The mount namespace is always private since the container's rootfs is
+    /// set up inside it.
+    pub fn mode(&self, kind: NamespaceKind) -> &NamespaceMode {
+        match kind {
+            NamespaceKind::Mount => &NamespaceMode::Private,
+            NamespaceKind::Uts => &self.uts,
+            NamespaceKind::Ipc => &self.ipc,
+            NamespaceKind::Pid => &self.pid,
+            NamespaceKind::Net => &self.net,
+            NamespaceKind::User => &self.user,
+            NamespaceKind::Cgroup => &self.cgroup,
+            NamespaceKind::Time => &self.time,
+        }
+    }
+
+    /// Like `mode`, for the namespaces that can be configured.
+    pub fn mode_mut(&mut self, kind: NamespaceKind) -> Option<&mut NamespaceMode> {
+        match kind {
+            NamespaceKind::Mount => None,
+            NamespaceKind::Uts => Some(&mut self.uts),
+            NamespaceKind::Ipc => Some(&mut self.ipc),
+            NamespaceKind::Pid => Some(&mut self.pid),
+            NamespaceKind::Net => Some(&mut self.net),
+            NamespaceKind::User => Some(&mut self.user),
+            NamespaceKind::Cgroup => Some(&mut self.cgroup),
+            NamespaceKind::Time => Some(&mut self.time),
+        }
+    }
+
+    /// PID and time namespaces only apply to children of the process that
+    /// unshares or joins them, so the workload has to be forked once more.
+    pub fn needs_fork(&self) -> bool {
+        *self.mode(NamespaceKind::Pid) != NamespaceMode::Host
+            || *self.mode(NamespaceKind::Time) != NamespaceMode::Host
+    }
+}
 
 pub struct Namespaces;
 
 impl Namespaces {
-    pub fn unshare_all() -> Result<()> {
-        let flags = CloneFlags::CLONE_NEWNS // Mount Namespace
-        | CloneFlags::CLONE_NEWUTS  // UTS name space (hostname)
-        | CloneFlags::CLONE_NEWPID  // PID namespace
-        | CloneFlags::CLONE_NEWNET  // newtwork namespace
-        | CloneFlags::CLONE_NEWIPC // IPC namespace
-        | CloneFlags::CLONE_NEWUSER; // User namespace
-
-        unshare(flags)
-            .map_err(|e| anyhow!("Failed to unshare namespaces: {}", e))
-            .unwrap();
+    /// Joins the namespaces in `joins` and unshares every namespace configured
+    /// as private. `joins` holds the resolved namespace path for every kind
+    /// configured to join a container or a path.
+    pub fn enter(config: &NamespaceConfig, joins: &[(NamespaceKind, PathBuf)]) -> Result<()> {
+        // Every namespace is opened up front, joining a mount namespace changes
+        // what the paths resolve to.
+        let files = Self::open_all(joins)?;
+        for (kind, file) in &files {
+            Self::join(*kind, file)?;
+        }
+
+        let flags = NamespaceKind::ALL
+            .iter()
+            .filter(|kind| *config.mode(**kind) == NamespaceMode::Private)
+            .fold(CloneFlags::empty(), |flags, kind| flags | kind.clone_flag());
+
+        unshare(flags).map_err(|e| anyhow!("Failed to unshare namespaces: {}", e))?;
+        Ok(())
+    }
+
+    /// Opens the namespace files in `joins`, ordered so that the user
+    /// namespace is joined first.
+    pub fn open_all(joins: &[(NamespaceKind, PathBuf)]) -> Result<Vec<(NamespaceKind, File)>> {
+        let mut files = Vec::new();
+
+        for kind in NamespaceKind::ALL {
+            if let Some((_, path)) = joins.iter().find(|(k, _)| *k == kind) {
+                let file = File::open(path).map_err(|e| {
+                    anyhow!(
+                        "Failed to open {} namespace {:?}: {}",
+                        kind.proc_name(),
+                        path,
+                        e
+                    )
+                })?;
+                files.push((kind, file));
+            }
+        }
+
+        Ok(files)
+    }
+
+    pub fn join(kind: NamespaceKind, file: &File) -> Result<()> {
+        setns(file, kind.clone_flag())
+            .map_err(|e| anyhow!("Failed to join {} namespace: {}", kind.proc_name(), e))?;
+        Ok(())
+    }
+
+    /// Maps the ids of the user namespace process `pid` created, which has
+    /// none until they are written from outside of it. As root every id
+    /// maps to itself, otherwise only root of the namespace can be mapped,
+    /// to the calling user.
+    pub fn write_id_maps(pid: i32) -> Result<()> {
+        let (uid, gid) = (Uid::effective(), Gid::effective());
+        let (uid_map, gid_map) = if uid.is_root() {
+            let identity = format!("0 0 {}\n", u32::MAX);
+            (identity.clone(), identity)
+        } else {
+            // Unprivileged users may only map their group once setgroups is
+            // denied.
+            fs::write(format!("/proc/{}/setgroups", pid), "deny")
+                .map_err(|e| anyhow!("Failed to deny setgroups: {}", e))?;
+            (format!("0 {} 1\n", uid), format!("0 {} 1\n", gid))
+        };
+        fs::write(format!("/proc/{}/uid_map", pid), uid_map)
+            .map_err(|e| anyhow!("Failed to write UID map: {}", e))?;
+        fs::write(format!("/proc/{}/gid_map", pid), gid_map)
+            .map_err(|e| anyhow!("Failed to write GID map: {}", e))?;
         Ok(())
     }
 
     pub fn set_hosename(hostname: &str) -> Result<()> {
-        sethostname(hostname).map_err(|e| anyhow!("Failed to set HostName: {}", e)).unwrap();
+        sethostname(hostname).map_err(|e| anyhow!("Failed to set HostName: {}", e))?;
         Ok(())
     }
 
     pub fn drop_privileges(uid: u32, gid: u32) -> Result<()> {
-        setgid(Gid::from_raw(gid))
-            .map_err(|e| anyhow!("Failed to set GID: {}", e))
-            .unwrap();
-        setuid(Uid::from_raw(uid))
-            .map_err(|e| anyhow!("Failed to set UID: {}", e))
-            .unwrap();
+        setgid(Gid::from_raw(gid)).map_err(|e| anyhow!("Failed to set GID: {}", e))?;
+        setuid(Uid::from_raw(uid)).map_err(|e| anyhow!("Failed to set UID: {}", e))?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::wait::{WaitStatus, waitpid};
+    use nix::unistd::{ForkResult, fork, pipe, read, write};
+
+    #[test]
+    fn drops_privileges_in_a_private_user_namespace() {
+        if !Uid::effective().is_root() {
+            eprintln!("skipped, mapping users other than root needs root");
+            return;
+        }
+        let (ready_rx, ready_tx) = pipe().unwrap();
+        let (mapped_rx, mapped_tx) = pipe().unwrap();
+
+        // SAFETY: the child only makes system calls before it exits.
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let mut byte = [0u8; 1];
+                let dropped = unshare(CloneFlags::CLONE_NEWUSER).is_ok()
+                    && write(&ready_tx, &byte) == Ok(1)
+                    && read(&mapped_rx, &mut byte) == Ok(1)
+                    && Namespaces::drop_privileges(1000, 1000).is_ok()
+                    && Uid::current().as_raw() == 1000
+                    && Gid::current().as_raw() == 1000;
+                unsafe { libc::_exit(if dropped { 0 } else { 1 }) };
+            }
+            ForkResult::Parent { child } => {
+                drop(ready_tx);
+                drop(mapped_rx);
+                let mut byte = [0u8; 1];
+                assert_eq!(read(&ready_rx, &mut byte), Ok(1));
+                Namespaces::write_id_maps(child.as_raw()).unwrap();
+                write(&mapped_tx, &byte).unwrap();
+                assert_eq!(waitpid(child, None), Ok(WaitStatus::Exited(child, 0)));
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+// src/runtime/restart.rs
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Delay before the first restart, doubled after every restart in a row.
+pub const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A container that ran at least this long starts over with the initial
+/// backoff.
+pub const BACKOFF_RESET_AFTER: Duration = Duration::from_secs(10);
+
+/// What the supervisor does when a container's process exits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "name", rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// Never restart.
+    #[default]
+    No,
+    /// Restart on a non-zero exit code, at most `max_retries` times if set.
+    OnFailure { max_retries: Option<u32> },
+    /// Always restart unless the container was stopped with `nb stop`.
+    Always,
+    /// Same as `always`. Kept for compatibility with Docker's policy names,
+    /// which only differ in what happens when the daemon restarts.
+    UnlessStopped,
+}
+
+impl RestartPolicy {
+    /// Whether a container that exited with `exit_code` after having been
+    /// restarted `restart_count` times comes back. Containers stopped by the
+    /// user are never restarted, the caller checks for that.
+    pub fn should_restart(&self, exit_code: i32, restart_count: u32) -> bool {
+        match self {
+            RestartPolicy::No => false,
+            RestartPolicy::OnFailure { max_retries } => {
+                exit_code != 0 && max_retries.is_none_or(|max| restart_count < max)
+            }
+            RestartPolicy::Always | RestartPolicy::UnlessStopped => true,
+        }
+    }
+}
+
+impl FromStr for RestartPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (name, max) = match value.split_once(':') {
+            Some((name, max)) => (name, Some(max)),
+            None => (value, None),
+        };
+
+        match (name, max) {
+            ("no", None) => Ok(RestartPolicy::No),
+            ("always", None) => Ok(RestartPolicy::Always),
+            ("unless-stopped", None) => Ok(RestartPolicy::UnlessStopped),
+            ("on-failure", None) => Ok(RestartPolicy::OnFailure { max_retries: None }),
+            ("on-failure", Some(max)) => {
+                let max_retries = max
+                    .parse::<u32>()
+                    .map_err(|_| anyhow!("Invalid maximum retry count {:?}", max))?;
+                Ok(RestartPolicy::OnFailure {
+                    max_retries: Some(max_retries),
+                })
+            }
+            ("no" | "always" | "unless-stopped", Some(_)) => Err(anyhow!(
+                "Only the on-failure restart policy takes a retry count"
+            )),
+            _ => Err(anyhow!(
+                "Invalid restart policy {:?}, expected no, on-failure[:max], always or unless-stopped",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestartPolicy::No => write!(f, "no"),
+            RestartPolicy::OnFailure { max_retries: None } => write!(f, "on-failure"),
+            RestartPolicy::OnFailure {
+                max_retries: Some(max),
+            } => write!(f, "on-failure:{}", max),
+            RestartPolicy::Always => write!(f, "always"),
+            RestartPolicy::UnlessStopped => write!(f, "unless-stopped"),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+// src/runtime/signal.rs
+use anyhow::{Result, anyhow};
+use nix::sys::signal::Signal;
+use std::str::FromStr;
+
+/// Signal sent by `nb stop` unless the container or its image sets one.
+pub const DEFAULT_STOP_SIGNAL: Signal = Signal::SIGTERM;
+/// Seconds `nb stop` waits for a container to exit before killing it.
+pub const DEFAULT_STOP_TIMEOUT: u64 = 10;
+
+/// Parses a signal given as a name with or without the `SIG` prefix, in any
+/// case, or as a number: `TERM`, `sigkill`, `9`.
+pub fn parse_signal(value: &str) -> Result<Signal> {
+    if let Ok(number) = value.parse::<i32>() {
+        return Signal::try_from(number).map_err(|_| anyhow!("Invalid signal number {}", number));
+    }
+
+    let name = value.to_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+    Signal::from_str(&name).map_err(|_| anyhow!("Invalid signal {:?}", value))
+}
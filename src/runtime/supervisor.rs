@@ -0,0 +1,969 @@
+// src/runtime/supervisor.rs
+use anyhow::{Result, anyhow};
+use chrono::Utc;
+use nix::fcntl::OFlag;
+use nix::sys::signal::{Signal, kill};
+use nix::sys::wait::waitpid;
+use nix::unistd::{
+    ForkResult, Pid, chdir, dup2_stderr, dup2_stdin, dup2_stdout, fork, pipe2, setsid,
+};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, create_dir_all, remove_file, rename};
+use std::io::{self, BufRead, BufReader, Read, Write, stderr, stdin, stdout};
+use std::net::Shutdown;
+use std::os::fd::AsFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::logging;
+use crate::network::dns::DnsServer;
+use crate::network::nat;
+use crate::network::proxy::Proxy;
+use crate::network::slirp::Slirp;
+use crate::runtime::cgroups::CgroupManager;
+use crate::runtime::container::{Container, ContainerStatus};
+use crate::runtime::exec::Exec;
+use crate::runtime::health::{HealthCheck, HealthState};
+use crate::runtime::log_drivers::{LogDriver, create_log_driver};
+use crate::runtime::logs::{LineBuffer, LogEntry, LogStream};
+use crate::runtime::main::Runtime;
+use crate::runtime::namespace::NamespaceKind;
+use crate::runtime::restart::{BACKOFF_RESET_AFTER, INITIAL_BACKOFF, MAX_BACKOFF};
+use crate::runtime::tty::{DetachKeys, Tty};
+use crate::storage::storage::{get_container, get_container_dir, modify_container};
+
+/// Longest time output is held back for a client that is about to attach.
+const HOLD_OUTPUT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest time an exiting supervisor waits for control requests to finish.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Requests understood by a container's control socket, sent as a single
+/// JSON line. After an `Attach` request the connection carries the raw
+/// console stream in both directions, or for containers without a TTY their
+/// input and their output in frames: a header of the stream (1 for stdout, 2
+/// for stderr), three zero bytes and the big-endian length of the data that
+/// follows. Every other request is answered with a `ControlResponse` line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Attach {
+        #[serde(default)]
+        stdin: bool,
+    },
+    Resize {
+        rows: u16,
+        cols: u16,
+    },
+    Kill {
+        signal: i32,
+    },
+    /// Sends `signal` and waits up to `timeout` seconds for the container to
+    /// exit before killing it. Answered once the container is cleaned up.
+    Stop {
+        signal: i32,
+        timeout: u64,
+    },
+    /// Answered with the exit code once the container exits for good, or
+    /// exits at all for `next-exit`. A `removed` wait is answered like
+    /// `not-running`, the caller waits for the removal.
+    Wait {
+        condition: WaitCondition,
+    },
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ControlResponse {
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+}
+
+/// What `nb wait` waits for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WaitCondition {
+    /// The container is not running, returns at once if it is not.
+    #[default]
+    NotRunning,
+    /// The next exit of the container's process, even if the restart policy
+    /// brings it back.
+    NextExit,
+    /// The container is removed.
+    Removed,
+}
+
+impl FromStr for WaitCondition {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "not-running" => Ok(WaitCondition::NotRunning),
+            "next-exit" => Ok(WaitCondition::NextExit),
+            "removed" => Ok(WaitCondition::Removed),
+            _ => Err(anyhow!(
+                "Invalid condition {:?}, expected not-running, next-exit or removed",
+                value
+            )),
+        }
+    }
+}
+
+/// Descriptors the supervisor holds for the standard streams of a workload.
+pub enum Stdio {
+    /// Master side of the container's terminal.
+    Console(File),
+    /// Pipes connected to the workload. Without `-i` its stdin is /dev/null.
+    Pipes {
+        stdin: Option<File>,
+        stdout: File,
+        stderr: File,
+    },
+}
+
+pub struct Workload {
+    /// Process the supervisor waits on, the parent of `pid` when the
+    /// container needs an extra fork for its PID or time namespace.
+    pub init: Pid,
+    /// PID of the container's main process as seen from the host.
+    pub pid: i32,
+    pub stdio: Stdio,
+    pub network: NetworkServices,
+}
+
+/// Helpers serving the workload's network namespace for as long as the
+/// workload runs.
+#[derive(Default)]
+pub struct NetworkServices {
+    /// Forwards the container's proxied ports.
+    pub proxy: Option<Proxy>,
+    /// Rootless network stack the workload is connected through.
+    pub slirp: Option<Slirp>,
+    /// Embedded DNS server of a container on networks.
+    pub dns: Option<DnsServer>,
+}
+
+/// State shared between the threads serving the control socket.
+struct Console {
+    container_id: String,
+    tty: bool,
+    clients: Mutex<Vec<UnixStream>>,
+    // Replaced every time the workload is restarted.
+    input: Mutex<Option<File>>,
+    terminal: Mutex<Option<File>>,
+    log: Option<Box<dyn LogDriver>>,
+    /// PID of the running workload, 0 between restarts.
+    pid: AtomicI32,
+    /// Set when the health check killed the workload to have it restarted.
+    restart_unhealthy: AtomicBool,
+    /// Set while output is held back for the first client to attach.
+    hold_output: AtomicBool,
+    /// Control requests being handled.
+    requests: AtomicUsize,
+    /// `changed` is notified whenever the state, `pid` or `hold_output`
+    /// changes.
+    state: Mutex<RunState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct RunState {
+    /// Exits of the workload so far, restarts included.
+    exits: u64,
+    exit_code: Option<i32>,
+    /// Set once the workload exited for good and the container is cleaned
+    /// up.
+    finished: bool,
+}
+
+impl Console {
+    fn set_pid(&self, pid: i32) {
+        let _state = self.state.lock().unwrap();
+        self.pid.store(pid, Ordering::SeqCst);
+        self.changed.notify_all();
+    }
+
+    fn exited(&self, code: i32) {
+        let mut state = self.state.lock().unwrap();
+        self.pid.store(0, Ordering::SeqCst);
+        state.exits += 1;
+        state.exit_code = Some(code);
+        self.changed.notify_all();
+    }
+
+    fn finish(&self) {
+        self.state.lock().unwrap().finished = true;
+        self.changed.notify_all();
+    }
+
+    fn release_output(&self) {
+        let _state = self.state.lock().unwrap();
+        self.hold_output.store(false, Ordering::SeqCst);
+        self.changed.notify_all();
+    }
+
+    /// Blocks while output is held back, for at most `HOLD_OUTPUT_TIMEOUT`.
+    fn wait_for_attach(&self) {
+        let deadline = Instant::now() + HOLD_OUTPUT_TIMEOUT;
+        let mut state = self.state.lock().unwrap();
+        while self.hold_output.load(Ordering::SeqCst) {
+            match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => {
+                    state = self.changed.wait_timeout(state, left).unwrap().0;
+                }
+                _ => {
+                    log::warn!(
+                        "No client attached to {} in time, passing on its output",
+                        self.container_id
+                    );
+                    self.hold_output.store(false, Ordering::SeqCst);
+                }
+            }
+        }
+    }
+}
+
+pub struct Supervisor;
+
+impl Supervisor {
+    pub fn socket_path(container_id: &str) -> PathBuf {
+        get_container_dir(container_id).join("control.sock")
+    }
+
+    /// Starts a supervisor for `container` that is detached from the calling
+    /// process, so the container keeps running after the CLI exits. Returns
+    /// the container as recorded once its workload has started. With
+    /// `hold_output` the workload's output waits for a client to attach.
+    pub fn spawn(
+        container: &Container,
+        joins: &[(NamespaceKind, PathBuf)],
+        hold_output: bool,
+    ) -> Result<Container> {
+        let (ready_rx, ready_tx) =
+            pipe2(OFlag::O_CLOEXEC).map_err(|e| anyhow!("Failed to create pipe: {}", e))?;
+
+        // SAFETY: the CLI is single threaded when the container is started.
+        match unsafe { fork() } {
+            Ok(ForkResult::Parent { child }) => {
+                drop(ready_tx);
+                let _ = waitpid(child, None);
+
+                // The container's init may hold the write end for as long as
+                // it lives, so only the first line is read.
+                let mut report = String::new();
+                BufReader::new(File::from(ready_rx)).read_line(&mut report)?;
+                match report.trim_end() {
+                    "ok" => get_container(&container.id),
+                    "" => Err(anyhow!(
+                        "Supervisor of container {} exited before starting it",
+                        container.id
+                    )),
+                    report => Err(anyhow!(
+                        "Container {} failed to start: {}",
+                        container.id,
+                        report.strip_prefix("error: ").unwrap_or(report)
+                    )),
+                }
+            }
+            Ok(ForkResult::Child) => {
+                drop(ready_rx);
+                let _ = setsid();
+
+                // After the second fork the supervisor is no session leader,
+                // so it can never acquire a controlling terminal, and it is
+                // reparented once this process exits.
+                match unsafe { fork() } {
+                    Ok(ForkResult::Parent { .. }) => std::process::exit(0),
+                    Ok(ForkResult::Child) => {
+                        Self::run(container, joins, File::from(ready_tx), hold_output)
+                    }
+                    Err(_) => std::process::exit(1),
+                }
+            }
+            Err(e) => Err(anyhow!("Failed to fork container supervisor: {}", e)),
+        }
+    }
+
+    fn run(
+        container: &Container,
+        joins: &[(NamespaceKind, PathBuf)],
+        mut ready: File,
+        hold_output: bool,
+    ) -> ! {
+        logging::set_container_id(&container.id);
+        if let Err(e) = Self::detach_stdio() {
+            log::warn!("Supervisor could not detach from the terminal: {}", e);
+        }
+
+        let socket_path = Self::socket_path(&container.id);
+        let (listener, workload) = match Self::prepare(container, joins, &socket_path) {
+            Ok(started) => started,
+            Err(e) => {
+                log::error!("Container {} failed to start: {}", container.id, e);
+                let _ = writeln!(ready, "error: {}", e);
+                let _ = remove_file(&socket_path);
+                std::process::exit(1);
+            }
+        };
+        let _ = writeln!(ready, "ok");
+        drop(ready);
+
+        log::info!(
+            "Supervising container {} with PID: {}",
+            container.id,
+            workload.pid
+        );
+        let log = create_log_driver(&container.id, &container.config.log_config)
+            .map_err(|e| log::warn!("Output of {} is not logged: {}", container.id, e))
+            .ok();
+        let code = Self::serve(container, joins, listener, workload, log, hold_output);
+        log::info!("Supervisor of {} exits after code {}", container.id, code);
+
+        let _ = remove_file(&socket_path);
+        std::process::exit(0);
+    }
+
+    fn detach_stdio() -> Result<()> {
+        let null = File::options().read(true).write(true).open("/dev/null")?;
+        dup2_stdin(&null)?;
+        dup2_stdout(&null)?;
+        dup2_stderr(&null)?;
+        chdir("/")?;
+        Ok(())
+    }
+
+    fn prepare(
+        container: &Container,
+        joins: &[(NamespaceKind, PathBuf)],
+        socket_path: &Path,
+    ) -> Result<(UnixListener, Workload)> {
+        create_dir_all(get_container_dir(&container.id))?;
+        // Bound under another name and moved into place, so the socket only
+        // shows up once it accepts connections.
+        let bind_path = socket_path.with_extension("sock.new");
+        if bind_path.exists() {
+            remove_file(&bind_path)?;
+        }
+        let listener = UnixListener::bind(&bind_path)
+            .map_err(|e| anyhow!("Failed to bind {:?}: {}", bind_path, e))?;
+        rename(&bind_path, socket_path)
+            .map_err(|e| anyhow!("Failed to create {:?}: {}", socket_path, e))?;
+
+        let workload = Runtime::spawn_workload(container, joins)?;
+
+        let recorded = modify_container(&container.id, |c| {
+            c.pid = workload.pid;
+            c.status = ContainerStatus::Running;
+            c.started_at = Some(Utc::now());
+            c.finished_at = None;
+            c.exit_code = None;
+            c.restart_count = 0;
+            c.health = c
+                .config
+                .health_check
+                .as_ref()
+                .map(|_| HealthState::default());
+        });
+        if let Err(e) = recorded {
+            let _ = kill(workload.init, Signal::SIGKILL);
+            let _ = waitpid(workload.init, None);
+            return Err(e);
+        }
+
+        Ok((listener, workload))
+    }
+
+    /// Relays the workload's streams to attached clients and answers control
+    /// requests until the workload exits and is not restarted by the
+    /// container's restart policy. Returns the last exit code. A container
+    /// created with `--rm` is removed before waiting clients are answered.
+    fn serve(
+        container: &Container,
+        joins: &[(NamespaceKind, PathBuf)],
+        listener: UnixListener,
+        mut workload: Workload,
+        log: Option<Box<dyn LogDriver>>,
+        hold_output: bool,
+    ) -> i32 {
+        let console = Arc::new(Console {
+            container_id: container.id.clone(),
+            tty: container.config.tty,
+            clients: Mutex::new(Vec::new()),
+            input: Mutex::new(None),
+            terminal: Mutex::new(None),
+            log,
+            pid: AtomicI32::new(workload.pid),
+            restart_unhealthy: AtomicBool::new(false),
+            hold_output: AtomicBool::new(hold_output),
+            requests: AtomicUsize::new(0),
+            state: Mutex::new(RunState::default()),
+            changed: Condvar::new(),
+        });
+
+        let accept_console = console.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let console = accept_console.clone();
+                console.requests.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || {
+                    if let Err(e) = Self::handle_client(stream, &console) {
+                        log::warn!("Control request failed: {}", e);
+                    }
+                    console.requests.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        if let Some(check) = container.config.health_check.clone() {
+            let console = console.clone();
+            thread::spawn(move || Self::monitor_health(check, &console));
+        }
+
+        let mut backoff = INITIAL_BACKOFF;
+        let code = loop {
+            let started = Instant::now();
+            let code = Self::supervise(container, workload, &console);
+
+            if !Self::record_exit(container, code, &console) {
+                break code;
+            }
+
+            if started.elapsed() >= BACKOFF_RESET_AFTER {
+                backoff = INITIAL_BACKOFF;
+            }
+            log::info!(
+                "Restarting container {} in {:?} after exit code {}",
+                container.id,
+                backoff,
+                code
+            );
+            Self::wait_backoff(&container.id, backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+
+            workload = match Self::restart(container, joins, &console) {
+                Ok(Some(workload)) => workload,
+                Ok(None) => break code,
+                Err(e) => {
+                    log::error!("Failed to restart container {}: {}", container.id, e);
+                    let _ = modify_container(&container.id, |c| {
+                        if c.status == ContainerStatus::Restarting {
+                            c.status = ContainerStatus::Exited;
+                        }
+                    });
+                    break code;
+                }
+            };
+        };
+
+        match CgroupManager::new(&container.id).and_then(|cgroup| cgroup.cleanup()) {
+            Ok(()) => {}
+            Err(e) => log::warn!("Failed to clean up cgroup of {}: {}", container.id, e),
+        }
+        if container.config.auto_remove {
+            let removed = get_container(&container.id)
+                .and_then(|container| Runtime::remove_container(&container, false, true));
+            if let Err(e) = removed {
+                log::error!("Failed to remove container {}: {}", container.id, e);
+            }
+        }
+        console.finish();
+
+        // Requests answered by the finish, like `nb wait`, get to send their
+        // response before the supervisor exits.
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        while console.requests.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        code
+    }
+
+    /// Serves a single run of the workload and returns its exit code.
+    fn supervise(container: &Container, workload: Workload, console: &Arc<Console>) -> i32 {
+        let outputs = match workload.stdio {
+            Stdio::Console(master) => {
+                *console.input.lock().unwrap() = master.try_clone().ok();
+                let output = master.try_clone().ok();
+                *console.terminal.lock().unwrap() = Some(master);
+                output
+                    .map(|output| vec![(output, LogStream::Stdout)])
+                    .unwrap_or_default()
+            }
+            Stdio::Pipes {
+                stdin,
+                stdout,
+                stderr,
+            } => {
+                *console.input.lock().unwrap() = stdin;
+                vec![(stdout, LogStream::Stdout), (stderr, LogStream::Stderr)]
+            }
+        };
+
+        let pumps: Vec<_> = outputs
+            .into_iter()
+            .map(|(output, stream)| {
+                let console = console.clone();
+                thread::spawn(move || Self::broadcast(output, stream, &console))
+            })
+            .collect();
+
+        let code = Exec::wait_for(workload.init);
+        console.exited(code);
+        log::info!("Container {} exited with code {}", container.id, code);
+        // The host ports are freed before a restart publishes them again.
+        drop(workload.network);
+
+        // Output still buffered in the pipes or the terminal is passed on
+        // before the clients are disconnected, a restarted workload gets new
+        // ones.
+        for pump in pumps {
+            let _ = pump.join();
+        }
+        console.input.lock().unwrap().take();
+        console.terminal.lock().unwrap().take();
+        for client in console.clients.lock().unwrap().drain(..) {
+            let _ = client.shutdown(Shutdown::Both);
+        }
+
+        code
+    }
+
+    /// Records the exit of the workload and returns whether the restart
+    /// policy, or a failed health check, brings it back. A container stopped
+    /// by `nb stop` stays stopped.
+    fn record_exit(container: &Container, code: i32, console: &Console) -> bool {
+        let policy = container.config.restart_policy;
+        let unhealthy = console.restart_unhealthy.swap(false, Ordering::SeqCst);
+        let mut restart = false;
+
+        let recorded = modify_container(&container.id, |c| {
+            c.pid = 0;
+            c.exit_code = Some(code);
+            c.finished_at = Some(Utc::now());
+
+            if c.status != ContainerStatus::Stopped {
+                restart = unhealthy || policy.should_restart(code, c.restart_count);
+                c.status = if restart {
+                    ContainerStatus::Restarting
+                } else {
+                    ContainerStatus::Exited
+                };
+            }
+        });
+        if let Err(e) = recorded {
+            log::error!("Failed to record exit of container {}: {}", container.id, e);
+            return false;
+        }
+        if let Err(e) = nat::unpublish(&container.id) {
+            log::warn!("Failed to withdraw ports of {}: {}", container.id, e);
+        }
+        restart
+    }
+
+    /// Sleeps for `backoff`, returning early once the container is stopped.
+    fn wait_backoff(container_id: &str, backoff: Duration) {
+        let deadline = Instant::now() + backoff;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            thread::sleep(left.min(Duration::from_millis(500)));
+            match get_container(container_id) {
+                Ok(c) if c.status == ContainerStatus::Restarting => {}
+                _ => return,
+            }
+        }
+    }
+
+    /// Starts the workload again unless the container was stopped while the
+    /// supervisor waited.
+    fn restart(
+        container: &Container,
+        joins: &[(NamespaceKind, PathBuf)],
+        console: &Console,
+    ) -> Result<Option<Workload>> {
+        if get_container(&container.id)?.status != ContainerStatus::Restarting {
+            return Ok(None);
+        }
+
+        let workload = Runtime::spawn_workload(container, joins)?;
+        console.set_pid(workload.pid);
+
+        let updated = modify_container(&container.id, |c| {
+            c.pid = workload.pid;
+            c.restart_count += 1;
+            c.started_at = Some(Utc::now());
+            c.finished_at = None;
+            c.health = c
+                .config
+                .health_check
+                .as_ref()
+                .map(|_| HealthState::default());
+            // A stop that raced with the restart has signalled the new
+            // process already.
+            if c.status == ContainerStatus::Restarting {
+                c.status = ContainerStatus::Running;
+            }
+        })?;
+        log::info!(
+            "Restarted container {} ({} restarts) with PID: {}",
+            container.id,
+            updated.restart_count,
+            workload.pid
+        );
+
+        Ok(Some(workload))
+    }
+
+    /// Runs the container's health check every interval while the workload
+    /// is up and records the results. An unhealthy workload is killed if the
+    /// check asks for a restart.
+    fn monitor_health(check: HealthCheck, console: &Console) {
+        let container_id = &console.container_id;
+        logging::set_container_id(container_id);
+
+        loop {
+            thread::sleep(check.interval);
+            let pid = console.pid.load(Ordering::SeqCst);
+            if pid == 0 {
+                continue;
+            }
+
+            let probe = check.probe(container_id);
+            // The result says nothing about a workload started since.
+            if console.pid.load(Ordering::SeqCst) != pid {
+                continue;
+            }
+
+            let mut unhealthy = false;
+            let recorded = modify_container(container_id, |c| {
+                let starting = c.started_at.is_some_and(|started| {
+                    (Utc::now() - started)
+                        .to_std()
+                        .is_ok_and(|up| up < check.start_period)
+                });
+                if c.status == ContainerStatus::Running
+                    && let Some(health) = c.health.as_mut()
+                {
+                    unhealthy = health.record(probe, &check, starting);
+                }
+            });
+            if let Err(e) = recorded {
+                log::warn!("Failed to record health of {}: {}", container_id, e);
+                continue;
+            }
+
+            if unhealthy {
+                log::warn!("Container {} is unhealthy", container_id);
+                if check.restart_on_unhealthy {
+                    console.restart_unhealthy.store(true, Ordering::SeqCst);
+                    let _ = kill(Pid::from_raw(pid), Signal::SIGKILL);
+                }
+            }
+        }
+    }
+
+    /// Copies workload output to the container's log and every attached
+    /// client until the workload closes its side.
+    fn broadcast(mut output: File, stream: LogStream, console: &Console) {
+        logging::set_container_id(&console.container_id);
+        console.wait_for_attach();
+        let mut lines = LineBuffer::default();
+        let log_line = |line: &str| {
+            if let Some(log) = &console.log
+                && let Err(e) = log.log(&LogEntry::new(stream, line))
+            {
+                log::warn!("Failed to log container output: {}", e);
+            }
+        };
+
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = output.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            lines.feed(&buf[..n], log_line);
+
+            let mut clients = console.clients.lock().unwrap();
+            if console.tty {
+                clients.retain_mut(|client| client.write_all(&buf[..n]).is_ok());
+            } else {
+                let mut header = [0u8; 8];
+                header[0] = match stream {
+                    LogStream::Stdout => 1,
+                    LogStream::Stderr => 2,
+                };
+                header[4..].copy_from_slice(&(n as u32).to_be_bytes());
+                clients.retain_mut(|client| {
+                    client
+                        .write_all(&header)
+                        .and_then(|_| client.write_all(&buf[..n]))
+                        .is_ok()
+                });
+            }
+        }
+        lines.finish(log_line);
+    }
+
+    fn handle_client(stream: UnixStream, console: &Console) -> Result<()> {
+        logging::set_container_id(&console.container_id);
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let request: ControlRequest = serde_json::from_str(&line)?;
+
+        let mut exit_code = None;
+        let result = match request {
+            ControlRequest::Attach { stdin } => {
+                console.clients.lock().unwrap().push(stream.try_clone()?);
+                console.release_output();
+                if stdin {
+                    Self::forward_input(reader, console);
+                }
+                return Ok(());
+            }
+            ControlRequest::Resize { rows, cols } => match &*console.terminal.lock().unwrap() {
+                Some(terminal) => Tty::set_size(terminal.as_fd(), rows, cols),
+                None => Ok(()),
+            },
+            ControlRequest::Kill { signal } => Signal::try_from(signal)
+                .map_err(|e| anyhow!("Invalid signal {}: {}", signal, e))
+                .and_then(|signal| match console.pid.load(Ordering::SeqCst) {
+                    0 => Err(anyhow!("Container is not running")),
+                    pid => kill(Pid::from_raw(pid), signal)
+                        .map_err(|e| anyhow!("Failed to send {} to container: {}", signal, e)),
+                }),
+            ControlRequest::Stop { signal, timeout } => Signal::try_from(signal)
+                .map_err(|e| anyhow!("Invalid signal {}: {}", signal, e))
+                .map(|signal| Self::stop_workload(console, signal, Duration::from_secs(timeout))),
+            ControlRequest::Wait { condition } => {
+                exit_code = Self::wait_workload(console, condition);
+                Ok(())
+            }
+        };
+
+        let response = ControlResponse {
+            error: result.err().map(|e| e.to_string()),
+            exit_code,
+        };
+        let mut line = serde_json::to_string(&response)?;
+        line.push('\n');
+        (&stream).write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Sends `signal` to the workload and waits for the supervisor to finish
+    /// with it, killing it once `timeout` has passed. A workload started by a
+    /// restart racing with the stop is signalled as well.
+    fn stop_workload(console: &Console, signal: Signal, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut signalled = 0;
+        let mut killed = 0;
+
+        let mut state = console.state.lock().unwrap();
+        while !state.finished {
+            let pid = console.pid.load(Ordering::SeqCst);
+            let now = Instant::now();
+
+            if pid != 0 && now >= deadline && killed != pid {
+                log::warn!(
+                    "Container {} did not exit within {:?}, killing it",
+                    console.container_id,
+                    timeout
+                );
+                let _ = kill(Pid::from_raw(pid), Signal::SIGKILL);
+                killed = pid;
+            } else if pid != 0 && signalled != pid {
+                let _ = kill(Pid::from_raw(pid), signal);
+                signalled = pid;
+            }
+
+            state = match deadline.checked_duration_since(now) {
+                Some(left) if !left.is_zero() => {
+                    console.changed.wait_timeout(state, left).unwrap().0
+                }
+                _ => console.changed.wait(state).unwrap(),
+            };
+        }
+    }
+
+    /// Blocks until the workload exits once more, or for good, and returns
+    /// its exit code.
+    fn wait_workload(console: &Console, condition: WaitCondition) -> Option<i32> {
+        let mut state = console.state.lock().unwrap();
+        let exits = state.exits;
+        loop {
+            let exited = condition == WaitCondition::NextExit && state.exits > exits;
+            if state.finished || exited {
+                break;
+            }
+            state = console.changed.wait(state).unwrap();
+        }
+        state.exit_code
+    }
+
+    fn forward_input(mut reader: BufReader<UnixStream>, console: &Console) {
+        // Input typed before the request line was consumed may already sit
+        // in the reader's buffer.
+        let mut pending = reader.buffer().to_vec();
+        let consumed = pending.len();
+        reader.consume(consumed);
+
+        let mut client = reader.into_inner();
+        let mut buf = [0u8; 1024];
+        loop {
+            if !pending.is_empty() {
+                let mut input = console.input.lock().unwrap();
+                let Some(input) = input.as_mut() else {
+                    return;
+                };
+                if input.write_all(&pending).is_err() {
+                    return;
+                }
+            }
+            match client.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => pending = buf[..n].to_vec(),
+            }
+        }
+
+        // Without a terminal the end of the client's input is the end of
+        // the workload's stdin, so that `nb attach` can be fed from a pipe.
+        if !console.tty {
+            console.input.lock().unwrap().take();
+        }
+    }
+
+    /// Connects to the control socket of a container and sends `request`.
+    pub fn request(container_id: &str, request: &ControlRequest) -> Result<UnixStream> {
+        let socket_path = Self::socket_path(container_id);
+        let mut stream = UnixStream::connect(&socket_path).map_err(|e| {
+            anyhow!(
+                "Failed to connect to container {}, is it running? {}",
+                container_id,
+                e
+            )
+        })?;
+
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        stream.write_all(line.as_bytes())?;
+        Ok(stream)
+    }
+
+    /// Sends `request` and waits for the supervisor's response.
+    pub fn call(container_id: &str, request: &ControlRequest) -> Result<ControlResponse> {
+        let stream = Self::request(container_id, request)?;
+        Self::response(container_id, stream)
+    }
+
+    fn response(container_id: &str, stream: UnixStream) -> Result<ControlResponse> {
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+
+        let response: ControlResponse = serde_json::from_str(&line)
+            .map_err(|e| anyhow!("Invalid response from container {}: {}", container_id, e))?;
+        match response.error {
+            Some(error) => Err(anyhow!(error)),
+            None => Ok(response),
+        }
+    }
+
+    pub fn stop(container_id: &str, signal: Signal, timeout: u64) -> Result<()> {
+        Self::call(
+            container_id,
+            &ControlRequest::Stop {
+                signal: signal as i32,
+                timeout,
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn kill(container_id: &str, signal: Signal) -> Result<()> {
+        Self::call(
+            container_id,
+            &ControlRequest::Kill {
+                signal: signal as i32,
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Waits for the container's supervisor to report an exit matching
+    /// `condition` and returns the exit code.
+    pub fn wait(container_id: &str, condition: WaitCondition) -> Result<Option<i32>> {
+        Ok(Self::call(container_id, &ControlRequest::Wait { condition })?.exit_code)
+    }
+
+    /// Sends a wait request right away and waits for its response on another
+    /// thread, so an exit that follows cannot be missed.
+    pub fn wait_in_background(
+        container_id: &str,
+        condition: WaitCondition,
+    ) -> Result<JoinHandle<Result<Option<i32>>>> {
+        let stream = Self::request(container_id, &ControlRequest::Wait { condition })?;
+        let container_id = container_id.to_string();
+        Ok(thread::spawn(move || {
+            Ok(Self::response(&container_id, stream)?.exit_code)
+        }))
+    }
+
+    /// Attaches the local terminal to a container's console, or to its
+    /// standard streams when it has no TTY. Returns whether the user
+    /// detached rather than the container closing its output.
+    pub fn attach(
+        container_id: &str,
+        tty: bool,
+        interactive: bool,
+        detach_keys: DetachKeys,
+    ) -> Result<bool> {
+        let stream = Self::request(container_id, &ControlRequest::Attach { stdin: interactive })?;
+
+        if !tty {
+            if interactive {
+                let mut input = stream.try_clone()?;
+                thread::spawn(move || {
+                    let _ = std::io::copy(&mut stdin(), &mut input);
+                    let _ = input.shutdown(Shutdown::Write);
+                });
+            }
+            Self::demux(&stream)?;
+            return Ok(false);
+        }
+
+        let input = stream.try_clone()?;
+        let id = container_id.to_string();
+        Tty::relay(
+            stream,
+            input,
+            interactive,
+            Some(detach_keys),
+            move |rows, cols| {
+                let _ = Self::call(&id, &ControlRequest::Resize { rows, cols });
+            },
+        )
+    }
+
+    /// Copies the framed output of a container without a TTY to stdout and
+    /// stderr until the supervisor closes the connection.
+    fn demux(mut stream: &UnixStream) -> Result<()> {
+        let mut header = [0u8; 8];
+        loop {
+            match stream.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+            let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+            let mut data = stream.take(len as u64);
+            if header[0] == 2 {
+                io::copy(&mut data, &mut stderr())?;
+            } else {
+                io::copy(&mut data, &mut stdout())?;
+                stdout().flush()?;
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+// src/runtime/sysctl.rs
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::runtime::namespace::{NamespaceConfig, NamespaceKind, NamespaceMode};
+
+// kernel.* keys that are scoped to the IPC namespace
+const IPC_KERNEL_KEYS: [&str; 8] = [
+    "kernel.msgmax",
+    "kernel.msgmnb",
+    "kernel.msgmni",
+    "kernel.sem",
+    "kernel.shmall",
+    "kernel.shmmax",
+    "kernel.shmmni",
+    "kernel.shm_rmid_forced",
+];
+
+pub struct Sysctl;
+
+impl Sysctl {
+    /// Parses `key=value` pairs as given on the command line.
+    pub fn parse(entries: &[String]) -> Result<HashMap<String, String>> {
+        let mut sysctls = HashMap::new();
+
+        for entry in entries {
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid sysctl {:?}, expected key=value", entry))?;
+
+            let key = key.trim();
+            Self::namespace_of(key)?;
+            sysctls.insert(key.to_string(), value.trim().to_string());
+        }
+
+        Ok(sysctls)
+    }
+
+    /// Returns the namespace a sysctl is scoped to.
+    pub fn namespace_of(key: &str) -> Result<NamespaceKind> {
+        if key.contains('/') || key.split('.').any(|part| part.is_empty()) {
+            return Err(anyhow!("Invalid sysctl key: {:?}", key));
+        }
+
+        if key.starts_with("net.") {
+            return Ok(NamespaceKind::Net);
+        }
+
+        if IPC_KERNEL_KEYS.contains(&key) || key.starts_with("fs.mqueue.") {
+            return Ok(NamespaceKind::Ipc);
+        }
+
+        Err(anyhow!(
+            "Sysctl {} is not namespaced and cannot be set for a container",
+            key
+        ))
+    }
+
+    /// Only keys that belong to a namespace the container gets on its own are
+    /// accepted, anything else would change the settings of the host or of
+    /// another container.
+    pub fn validate(key: &str, namespaces: &NamespaceConfig) -> Result<()> {
+        let kind = Self::namespace_of(key)?;
+        let mode = namespaces.mode(kind);
+
+        if *mode != NamespaceMode::Private {
+            return Err(anyhow!(
+                "Sysctl {} requires a private {} namespace, but it is {}",
+                key,
+                kind.proc_name(),
+                mode
+            ));
+        }
+        Ok(())
+    }
+
+    /// Writes the sysctls below `proc_sys`. Must run from inside the
+    /// container's namespaces so the per-namespace values are the ones changed.
+    pub fn apply(
+        proc_sys: &Path,
+        sysctls: &HashMap<String, String>,
+        namespaces: &NamespaceConfig,
+    ) -> Result<()> {
+        for (key, value) in sysctls {
+            Self::validate(key, namespaces)?;
+
+            let path = proc_sys.join(key.replace('.', "/"));
+            fs::write(&path, value)
+                .map_err(|e| anyhow!("Failed to set sysctl {}={}: {}", key, value, e))?;
+
+            log::info!("Set sysctl {}={}", key, value);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,416 @@
+// src/runtime/tty.rs
+use anyhow::{Result, anyhow};
+use nix::fcntl::{OFlag, open};
+use nix::mount::{MsFlags, mount};
+use nix::pty::Winsize;
+use nix::sys::signal::{SigSet, SigmaskHow, Signal, pthread_sigmask};
+use nix::sys::socket::{ControlMessage, ControlMessageOwned, MsgFlags, recvmsg, sendmsg};
+use nix::sys::stat::Mode;
+use nix::sys::termios::{SetArg, Termios, cfmakeraw, tcgetattr, tcsetattr};
+use nix::unistd::{dup2_stderr, dup2_stdin, dup2_stdout, isatty, setsid};
+use std::fmt;
+use std::fs::{File, create_dir_all};
+use std::io::{IoSlice, IoSliceMut, Read, Write, stdin, stdout};
+use std::net::Shutdown;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::symlink;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+pub const DEFAULT_DETACH_KEYS: &str = "ctrl-p,ctrl-q";
+
+pub struct Tty;
+
+impl Tty {
+    /// Mounts a private devpts instance at `<rootfs>/dev/pts` so the
+    /// container's terminals are not shared with the host.
+    pub fn setup_devpts(rootfs: &Path) -> Result<()> {
+        let pts = rootfs.join("dev/pts");
+        create_dir_all(&pts)?;
+
+        mount(
+            Some("devpts"),
+            &pts,
+            Some("devpts"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+            Some("newinstance,ptmxmode=0666,mode=0620"),
+        )
+        .map_err(|e| anyhow!("Failed to mount devpts: {}", e))?;
+
+        let ptmx = rootfs.join("dev/ptmx");
+        if ptmx.symlink_metadata().is_err() {
+            symlink("pts/ptmx", &ptmx)?;
+        }
+        Ok(())
+    }
+
+    /// Allocates a terminal from the container's devpts instance and makes it
+    /// the controlling terminal and stdio of the calling process. The master
+    /// side is returned so it can be handed to the supervisor.
+    pub fn open_console(rootfs: &Path) -> Result<OwnedFd> {
+        let master = open(
+            &rootfs.join("dev/pts/ptmx"),
+            OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .map_err(|e| anyhow!("Failed to open ptmx: {}", e))?;
+
+        let unlock: libc::c_int = 0;
+        // SAFETY: both ioctls only read or return plain integers.
+        let slave = unsafe {
+            if libc::ioctl(master.as_raw_fd(), libc::TIOCSPTLCK, &unlock) < 0 {
+                return Err(anyhow!(
+                    "Failed to unlock pty: {}",
+                    std::io::Error::last_os_error()
+                ));
+            }
+            let fd = libc::ioctl(
+                master.as_raw_fd(),
+                libc::TIOCGPTPEER,
+                libc::O_RDWR | libc::O_NOCTTY,
+            );
+            if fd < 0 {
+                return Err(anyhow!(
+                    "Failed to open pty slave: {}",
+                    std::io::Error::last_os_error()
+                ));
+            }
+            OwnedFd::from_raw_fd(fd)
+        };
+
+        Self::make_controlling(&slave)?;
+        Ok(master)
+    }
+
+    /// Starts a new session with `slave` as its controlling terminal and
+    /// standard input, output and error.
+    pub fn make_controlling(slave: &OwnedFd) -> Result<()> {
+        setsid().map_err(|e| anyhow!("Failed to create session: {}", e))?;
+
+        // SAFETY: TIOCSCTTY takes no pointer argument.
+        if unsafe { libc::ioctl(slave.as_raw_fd(), libc::TIOCSCTTY, 0) } < 0 {
+            return Err(anyhow!(
+                "Failed to set controlling terminal: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        dup2_stdin(slave)?;
+        dup2_stdout(slave)?;
+        dup2_stderr(slave)?;
+        Ok(())
+    }
+
+    pub fn send_fd(socket: &UnixStream, fd: BorrowedFd) -> Result<()> {
+        let fds = [fd.as_raw_fd()];
+        sendmsg::<()>(
+            socket.as_raw_fd(),
+            &[IoSlice::new(b"\0")],
+            &[ControlMessage::ScmRights(&fds)],
+            MsgFlags::empty(),
+            None,
+        )
+        .map_err(|e| anyhow!("Failed to send file descriptor: {}", e))?;
+        Ok(())
+    }
+
+    pub fn recv_fd(socket: &UnixStream) -> Result<OwnedFd> {
+        let mut buf = [0u8; 1];
+        let mut iov = [IoSliceMut::new(&mut buf)];
+        let mut cmsg = nix::cmsg_space!([std::os::fd::RawFd; 1]);
+
+        let msg = recvmsg::<()>(
+            socket.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )
+        .map_err(|e| anyhow!("Failed to receive file descriptor: {}", e))?;
+
+        for cmsg in msg.cmsgs()? {
+            if let ControlMessageOwned::ScmRights(fds) = cmsg
+                && let Some(fd) = fds.first()
+            {
+                // SAFETY: the descriptor was just received and is owned by us.
+                return Ok(unsafe { OwnedFd::from_raw_fd(*fd) });
+            }
+        }
+        Err(anyhow!("No file descriptor received"))
+    }
+
+    pub fn get_size(fd: BorrowedFd) -> Option<Winsize> {
+        let mut size = Winsize {
+            ws_row: 0,
+            ws_col: 0,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // SAFETY: TIOCGWINSZ writes a winsize struct.
+        if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCGWINSZ, &mut size) } < 0 {
+            return None;
+        }
+        Some(size)
+    }
+
+    /// Resizing the master makes the kernel send SIGWINCH to the terminal's
+    /// foreground process group.
+    pub fn set_size(fd: BorrowedFd, rows: u16, cols: u16) -> Result<()> {
+        let size = Winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // SAFETY: TIOCSWINSZ reads a winsize struct.
+        if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCSWINSZ, &size) } < 0 {
+            return Err(anyhow!(
+                "Failed to resize terminal: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Copies the terminal behind `output` to stdout and, when `interactive`,
+    /// stdin to `input` until the other side closes or the detach keys, if
+    /// any, are typed. `resize` is called with the local terminal size initially and on
+    /// every SIGWINCH. Returns whether the user detached.
+    pub fn relay<R, W, F>(
+        mut output: R,
+        mut input: W,
+        interactive: bool,
+        detach_keys: Option<DetachKeys>,
+        resize: F,
+    ) -> Result<bool>
+    where
+        R: Read,
+        W: ConsoleInput,
+        F: Fn(u16, u16) + Send + 'static,
+    {
+        let _raw_mode = if interactive {
+            RawMode::enable()?
+        } else {
+            None
+        };
+
+        if let Some(size) = Self::get_size(stdout().as_fd()) {
+            resize(size.ws_row, size.ws_col);
+        }
+
+        // SIGWINCH is blocked before spawning threads so that only the
+        // watcher receives it.
+        let mut winch = SigSet::empty();
+        winch.add(Signal::SIGWINCH);
+        pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&winch), None)?;
+        thread::spawn(move || {
+            while winch.wait().is_ok() {
+                if let Some(size) = Self::get_size(stdout().as_fd()) {
+                    resize(size.ws_row, size.ws_col);
+                }
+            }
+        });
+
+        let detached = Arc::new(AtomicBool::new(false));
+        if interactive {
+            let detached = detached.clone();
+            thread::spawn(move || {
+                let mut matcher = detach_keys.as_ref().map(|keys| keys.matcher());
+                let mut buf = [0u8; 1024];
+                let mut forward = Vec::with_capacity(buf.len());
+
+                while let Ok(n) = stdin().read(&mut buf) {
+                    if n == 0 {
+                        break;
+                    }
+                    forward.clear();
+                    let detach = match matcher.as_mut() {
+                        Some(matcher) => matcher.feed(&buf[..n], &mut forward),
+                        None => {
+                            forward.extend_from_slice(&buf[..n]);
+                            false
+                        }
+                    };
+                    if input.write_all(&forward).is_err() {
+                        break;
+                    }
+                    if detach {
+                        detached.store(true, Ordering::SeqCst);
+                        // Closing the connection ends the output loop below.
+                        input.close();
+                        return;
+                    }
+                }
+            });
+        }
+
+        let mut buf = [0u8; 4096];
+        let mut out = stdout();
+        loop {
+            match output.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    out.write_all(&buf[..n])?;
+                    out.flush()?;
+                }
+                // A pty master returns EIO once the slave side is closed.
+                Err(_) => break,
+            }
+            if detached.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+
+        Ok(detached.load(Ordering::SeqCst))
+    }
+}
+
+/// Where keyboard input of a console goes. Detaching closes it so the output
+/// side stops as well.
+pub trait ConsoleInput: Write + Send + 'static {
+    fn close(&self) {}
+}
+
+impl ConsoleInput for UnixStream {
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+impl ConsoleInput for File {}
+
+/// Puts the local terminal into raw mode and restores it on drop.
+pub struct RawMode {
+    original: Termios,
+}
+
+impl RawMode {
+    pub fn enable() -> Result<Option<Self>> {
+        let input = stdin();
+        if !isatty(input.as_fd()).unwrap_or(false) {
+            return Ok(None);
+        }
+
+        let original = tcgetattr(input.as_fd())?;
+        let mut raw = original.clone();
+        cfmakeraw(&mut raw);
+        tcsetattr(input.as_fd(), SetArg::TCSANOW, &raw)?;
+
+        Ok(Some(Self { original }))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = tcsetattr(stdin().as_fd(), SetArg::TCSANOW, &self.original);
+    }
+}
+
+/// Key sequence that detaches from a container's console, written as a comma
+/// separated list of characters or `ctrl-<key>` combinations.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DetachKeys(Vec<u8>);
+
+impl Default for DetachKeys {
+    fn default() -> Self {
+        DEFAULT_DETACH_KEYS.parse().unwrap()
+    }
+}
+
+impl FromStr for DetachKeys {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let mut keys = Vec::new();
+
+        for key in value.split(',') {
+            let key = key.trim();
+            let byte = if let Some(ctrl) = key.strip_prefix("ctrl-") {
+                match ctrl.as_bytes() {
+                    [c @ b'a'..=b'z'] => c - b'a' + 1,
+                    [b'@'] => 0,
+                    [b'['] => 27,
+                    [b'\\'] => 28,
+                    [b']'] => 29,
+                    [b'^'] => 30,
+                    [b'_'] => 31,
+                    _ => return Err(anyhow!("Invalid detach key {:?}", key)),
+                }
+            } else {
+                match key.as_bytes() {
+                    [c] if c.is_ascii() => *c,
+                    _ => return Err(anyhow!("Invalid detach key {:?}", key)),
+                }
+            };
+            keys.push(byte);
+        }
+
+        Ok(Self(keys))
+    }
+}
+
+impl fmt::Display for DetachKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let keys: Vec<String> = self
+            .0
+            .iter()
+            .map(|&key| match key {
+                0 => "ctrl-@".to_string(),
+                1..=26 => format!("ctrl-{}", (b'a' + key - 1) as char),
+                27 => "ctrl-[".to_string(),
+                28 => "ctrl-\\".to_string(),
+                29 => "ctrl-]".to_string(),
+                30 => "ctrl-^".to_string(),
+                31 => "ctrl-_".to_string(),
+                _ => (key as char).to_string(),
+            })
+            .collect();
+        write!(f, "{}", keys.join(","))
+    }
+}
+
+impl DetachKeys {
+    pub fn matcher(&self) -> DetachMatcher<'_> {
+        DetachMatcher {
+            keys: &self.0,
+            matched: 0,
+        }
+    }
+}
+
+pub struct DetachMatcher<'a> {
+    keys: &'a [u8],
+    matched: usize,
+}
+
+impl DetachMatcher<'_> {
+    /// Appends the bytes of `input` that should reach the container to
+    /// `forward`, holding back a partially typed detach sequence. Returns
+    /// true once the whole sequence has been typed.
+    pub fn feed(&mut self, input: &[u8], forward: &mut Vec<u8>) -> bool {
+        for &byte in input {
+            if byte == self.keys[self.matched] {
+                self.matched += 1;
+                if self.matched == self.keys.len() {
+                    return true;
+                }
+                continue;
+            }
+
+            // The held back bytes and this one may still end in the start
+            // of the sequence, like ^P^P^Q does for ctrl-p,ctrl-q. Keep the
+            // longest such tail held back and forward the rest.
+            let mut held = self.keys[..self.matched].to_vec();
+            held.push(byte);
+            let start = (1..=held.len())
+                .find(|&start| self.keys.starts_with(&held[start..]))
+                .unwrap_or(held.len());
+            forward.extend_from_slice(&held[..start]);
+            self.matched = held.len() - start;
+        }
+        false
+    }
+}
@@ -0,0 +1,149 @@
+// src/runtime/users.rs
+use anyhow::{Result, anyhow};
+use nix::errno::Errno;
+use nix::fcntl::{OFlag, OpenHow, ResolveFlag, openat2};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Maps `user[:group]`, each by name or id, to ids using the account files
+/// of the container with its root at `root`. Without a group the user's
+/// primary group is used. An empty user is root.
+pub fn resolve_user(root: &Path, user: &str) -> Result<(u32, u32)> {
+    let (name, group) = match user.split_once(':') {
+        Some((name, group)) => (name, Some(group)),
+        None => (user, None),
+    };
+
+    let (uid, default_gid) = match name {
+        "" => (0, 0),
+        name => {
+            let passwd = read_file(root, "etc/passwd")?;
+            match (lookup(passwd.as_deref(), name), name.parse::<u32>()) {
+                (Some(entry), _) => match (id(&entry, 2), id(&entry, 3)) {
+                    (Some(uid), Some(gid)) => (uid, gid),
+                    _ => return Err(anyhow!("Invalid passwd entry for user {}", name)),
+                },
+                // Ids need no entry, their group is the same id.
+                (None, Ok(uid)) => (uid, uid),
+                (None, Err(_)) => return Err(anyhow!("User {} not found in container", name)),
+            }
+        }
+    };
+
+    let gid = match group {
+        None | Some("") => default_gid,
+        Some(group) => match group.parse::<u32>() {
+            Ok(gid) => gid,
+            Err(_) => {
+                let groups = read_file(root, "etc/group")?;
+                lookup(groups.as_deref(), group)
+                    .and_then(|entry| id(&entry, 2))
+                    .ok_or_else(|| anyhow!("Group {} not found in container", group))?
+            }
+        },
+    };
+
+    Ok((uid, gid))
+}
+
+/// Finds the entry of a passwd or group style file by name or by id, both
+/// keep the id in their third field.
+fn lookup(entries: Option<&str>, name: &str) -> Option<Vec<String>> {
+    let Some(entries) = entries else {
+        // Containers without account files can still run as root.
+        return (name == "root").then(|| ["root", "x", "0", "0"].map(String::from).to_vec());
+    };
+
+    let fields = |line: &str| line.split(':').map(str::to_string).collect::<Vec<_>>();
+    entries
+        .lines()
+        .map(fields)
+        .find(|entry| entry.first().is_some_and(|field| field == name))
+        .or_else(|| {
+            name.parse::<u32>().ok()?;
+            entries
+                .lines()
+                .map(fields)
+                .find(|entry| entry.get(2).is_some_and(|field| field == name))
+        })
+}
+
+fn id(entry: &[String], field: usize) -> Option<u32> {
+    entry.get(field).and_then(|id| id.parse::<u32>().ok())
+}
+
+/// Reads `path` below `root`, resolving symlinks as if `root` was `/` so
+/// that the container's files cannot lead to the host's. Returns `None`
+/// when it does not exist.
+fn read_file(root: &Path, path: &str) -> Result<Option<String>> {
+    let root_dir = File::open(root).map_err(|e| anyhow!("Failed to open {:?}: {}", root, e))?;
+    // Not blocking on a FIFO put in its place.
+    let how = OpenHow::new()
+        .flags(OFlag::O_RDONLY | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC)
+        .resolve(ResolveFlag::RESOLVE_IN_ROOT | ResolveFlag::RESOLVE_NO_MAGICLINKS);
+    let mut file = match openat2(&root_dir, path, how) {
+        Ok(fd) => File::from(fd),
+        Err(Errno::ENOENT) => return Ok(None),
+        Err(e) => return Err(anyhow!("Failed to open /{} of container: {}", path, e)),
+    };
+    if !file.metadata()?.is_file() {
+        return Err(anyhow!("/{} of container is not a regular file", path));
+    }
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .map_err(|e| anyhow!("Failed to read /{} of container: {}", path, e))?;
+    Ok(Some(contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+
+    fn rootfs(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("nb-users-{}-{}", name, std::process::id()));
+        let _ = remove_dir_all(&root);
+        create_dir_all(root.join("etc")).unwrap();
+        root
+    }
+
+    #[test]
+    fn resolves_users_and_groups_by_name_or_id() {
+        let root = rootfs("names");
+        write(
+            root.join("etc/passwd"),
+            "root:x:0:0::/root:/bin/sh\napp:x:1000:1001::/home/app:/bin/sh\n",
+        )
+        .unwrap();
+        write(root.join("etc/group"), "root:x:0:\nstaff:x:50:app\n").unwrap();
+
+        assert_eq!(resolve_user(&root, "").unwrap(), (0, 0));
+        assert_eq!(resolve_user(&root, "app").unwrap(), (1000, 1001));
+        assert_eq!(resolve_user(&root, "1000").unwrap(), (1000, 1001));
+        assert_eq!(resolve_user(&root, "2000").unwrap(), (2000, 2000));
+        assert_eq!(resolve_user(&root, "app:staff").unwrap(), (1000, 50));
+        assert_eq!(resolve_user(&root, "2000:7").unwrap(), (2000, 7));
+        assert!(resolve_user(&root, "nobody").is_err());
+        assert!(resolve_user(&root, "app:wheel").is_err());
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn does_not_follow_symlinks_out_of_the_container() {
+        let root = rootfs("symlink");
+        let host = root.with_extension("host");
+        create_dir_all(&host).unwrap();
+        write(host.join("passwd"), "app:x:1000:1000::/:/bin/sh\n").unwrap();
+        symlink(host.join("passwd"), root.join("etc/passwd")).unwrap();
+
+        // The link is resolved inside the container, where it leads nowhere.
+        assert!(resolve_user(&root, "app").is_err());
+        assert_eq!(resolve_user(&root, "root").unwrap(), (0, 0));
+        remove_dir_all(&root).unwrap();
+        remove_dir_all(&host).unwrap();
+    }
+}
@@ -2,11 +2,13 @@
 
 use anyhow::{Result, anyhow};
 use copy_dir::copy_dir;
-use nix::mount::{MsFlags, mount, umount};
+use nix::errno::Errno;
+use nix::mount::{MntFlags, MsFlags, mount, umount, umount2};
 use std::{
     collections::HashMap,
     fs::{create_dir_all, read_dir, remove_dir_all},
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
 pub trait StorageDriver: Send + Sync {
@@ -27,6 +29,7 @@ pub trait StorageDriver: Send + Sync {
 
 pub struct OverlayFSDriver {
     root: PathBuf,
+    #[allow(dead_code)]
     work_dir: PathBuf,
 }
 
@@ -65,11 +68,13 @@ impl StorageDriver for OverlayFSDriver {
         let work_dir = snapshot_dir.join("work");
         let merged_dir = snapshot_dir.join("merged");
 
-        self.create_dirs(&lower_dir, &upper_dir, &work_dir, &merged_dir)?;
-
+        // copy_dir creates the lower directory itself.
         if source.exists() {
-            copy_dir(source, &lower_dir).unwrap();
+            create_dir_all(&snapshot_dir)?;
+            copy_dir(source, &lower_dir)
+                .map_err(|e| anyhow!("Failed to copy {:?} into snapshot: {}", source, e))?;
         }
+        self.create_dirs(&lower_dir, &upper_dir, &work_dir, &merged_dir)?;
 
         let options = format!(
             "lowerdir={},upperdir={},workdir={}",
@@ -84,7 +89,8 @@ impl StorageDriver for OverlayFSDriver {
             Some("overlay"),
             MsFlags::empty(),
             Some(options.as_str()),
-        )?;
+        )
+        .map_err(|e| anyhow!("Failed to mount overlay for snapshot {}: {}", id, e))?;
 
         Ok(merged_dir)
     }
@@ -93,18 +99,23 @@ impl StorageDriver for OverlayFSDriver {
         let snapshot_dir = self.root.join("snapshots").join(id);
         let merged_dir = snapshot_dir.join("merged");
 
+        // Not mounted any more after a reboot.
         if merged_dir.exists() {
-            umount(&merged_dir).unwrap();
+            match umount2(&merged_dir, MntFlags::MNT_DETACH) {
+                Ok(()) | Err(Errno::EINVAL) => {}
+                Err(e) => return Err(anyhow!("Failed to unmount {:?}: {}", merged_dir, e)),
+            }
         }
 
         if snapshot_dir.exists() {
-            remove_dir_all(&snapshot_dir).unwrap();
+            remove_dir_all(&snapshot_dir)
+                .map_err(|e| anyhow!("Failed to remove snapshot {}: {}", id, e))?;
         }
 
         Ok(())
     }
 
-    fn mount(&self, source: &Path, target: &Path, option: &HashMap<String, String>) -> Result<()> {
+    fn mount(&self, source: &Path, target: &Path, _option: &HashMap<String, String>) -> Result<()> {
         if !target.exists() {
             create_dir_all(target)?;
         }
@@ -139,10 +150,10 @@ impl StorageDriver for OverlayFSDriver {
         let mut snapshots = Vec::new();
         for entry in read_dir(snapshot_dir).unwrap() {
             let entry = entry?;
-            if entry.file_type().unwrap().is_dir() {
-                if let Some(name) = entry.file_name().to_str() {
-                    snapshots.push(name.to_string());
-                }
+            if entry.file_type().unwrap().is_dir()
+                && let Some(name) = entry.file_name().to_str()
+            {
+                snapshots.push(name.to_string());
             }
         }
 
@@ -170,10 +181,12 @@ impl StorageDriver for AufsDriver {
         let snapshot_dir = self.root.join("snapshots").join(id);
         let merged_dir = snapshot_dir.join("merged");
 
-        create_dir_all(&merged_dir).unwrap();
-
+        create_dir_all(&snapshot_dir)?;
         if source.exists() {
-            copy_dir(source, &merged_dir).unwrap();
+            copy_dir(source, &merged_dir)
+                .map_err(|e| anyhow!("Failed to copy {:?} into snapshot: {}", source, e))?;
+        } else {
+            create_dir_all(&merged_dir)?;
         }
         Ok(merged_dir)
     }
@@ -187,7 +200,7 @@ impl StorageDriver for AufsDriver {
         Ok(())
     }
 
-    fn mount(&self, source: &Path, target: &Path, option: &HashMap<String, String>) -> Result<()> {
+    fn mount(&self, source: &Path, target: &Path, _option: &HashMap<String, String>) -> Result<()> {
         mount(
             Some(source),
             target,
@@ -218,10 +231,10 @@ impl StorageDriver for AufsDriver {
         let mut snapshots = Vec::new();
         for entry in read_dir(snapshot_dir).unwrap() {
             let entry = entry?;
-            if entry.file_type().unwrap().is_dir() {
-                if let Some(name) = entry.file_name().to_str() {
-                    snapshots.push(name.to_string());
-                }
+            if entry.file_type().unwrap().is_dir()
+                && let Some(name) = entry.file_name().to_str()
+            {
+                snapshots.push(name.to_string());
             }
         }
 
@@ -234,9 +247,24 @@ pub enum DriverType {
     Aufs,
 }
 
+impl FromStr for DriverType {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "overlayfs" | "overlay" => Ok(DriverType::OverlayFS),
+            "aufs" => Ok(DriverType::Aufs),
+            _ => Err(anyhow!(
+                "Unknown storage driver {:?}, expected overlayfs or aufs",
+                value
+            )),
+        }
+    }
+}
+
 pub fn create_driver(driver_type: DriverType, root: PathBuf) -> Result<Box<dyn StorageDriver>> {
     match driver_type {
-        DriverType::OverlayFS => Ok(Box::new(OverlayFSDriver::new(root).unwrap())),
-        DriverType::Aufs => Ok(Box::new(AufsDriver::new(root).unwrap())),
+        DriverType::OverlayFS => Ok(Box::new(OverlayFSDriver::new(root)?)),
+        DriverType::Aufs => Ok(Box::new(AufsDriver::new(root)?)),
     }
 }
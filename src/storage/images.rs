@@ -2,7 +2,7 @@
 
 use std::{
     collections::HashMap,
-    fs::{File, create_dir_all, read_to_string, remove_dir_all, write},
+    fs::{File, create_dir_all, read_dir, read_to_string, remove_dir_all, write},
     io::Read,
     path::{Path, PathBuf},
 };
@@ -35,6 +35,9 @@ pub struct ImageConfig {
     pub working_dir: String,
     pub user: String,
     pub labels: HashMap<String, String>,
+    /// Signal that stops containers of this image, e.g. SIGQUIT for nginx.
+    #[serde(default)]
+    pub stop_signal: Option<String>,
 }
 
 pub struct ImageManager {
@@ -47,10 +50,11 @@ impl Default for ImageConfig {
         Self {
             entrypoint: vec![],
             cmd: vec!["/bin/sh".to_string()],
-            env: vec!["PATH+/usr/bin:/bin".to_string()],
+            env: vec!["PATH=/usr/bin:/bin".to_string()],
             working_dir: "/".to_string(),
             user: "root".to_string(),
             labels: HashMap::new(),
+            stop_signal: None,
         }
     }
 }
@@ -121,14 +125,14 @@ impl ImageManager {
     }
 
     pub fn remove(&mut self, image_id: &str) -> Result<()> {
-        if let Some(image) = self.images.remove(image_id) {
+        if self.images.remove(image_id).is_some() {
             let image_dir = self.images_dir.join(image_id);
             if image_dir.exists() {
                 remove_dir_all(image_dir)?;
             }
             self.save_images()?;
         }
-        return Ok(());
+        Ok(())
     }
 
     pub fn get(&self, name: &str, tag: &str) -> Option<&Image> {
@@ -187,7 +191,17 @@ impl ImageManager {
     }
 
     fn calculate_directory_size(path: &Path) -> Result<u64> {
-        let total_size = 0;
+        let mut total_size = 0;
+
+        for entry in read_dir(path)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                total_size += Self::calculate_directory_size(&entry.path())?;
+            } else if file_type.is_file() {
+                total_size += entry.metadata()?.len();
+            }
+        }
 
         Ok(total_size)
     }
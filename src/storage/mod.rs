@@ -1,11 +1,11 @@
 // src/stroage/mod.rs
 
-pub mod storage;
 pub mod drivers;
-pub mod volumes;
 pub mod images;
 pub mod snapshot;
-
+#[allow(clippy::module_inception)]
+pub mod storage;
+pub mod volumes;
 
 use std::path::PathBuf;
 
@@ -21,7 +21,7 @@ pub struct StorageConfig {
 impl Default for StorageConfig {
     fn default() -> Self {
         Self {
-            driver:"overlayfs".to_string(),
+            driver: "overlayfs".to_string(),
             root: PROGRAM_ROOT.to_path_buf().clone(),
             volumes_dir: PROGRAM_ROOT.join("volumes"),
             images_dir: PROGRAM_ROOT.join("images"),
@@ -29,10 +29,10 @@ impl Default for StorageConfig {
     }
 }
 
-
+#[allow(dead_code)]
 pub struct StorageManager {
     config: StorageConfig,
     volume: volumes::VolumeManager,
     image_manager: images::ImageManager,
     driver: Box<dyn drivers::StorageDriver>,
-}
\ No newline at end of file
+}
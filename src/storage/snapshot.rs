@@ -4,7 +4,7 @@ use anyhow::Result;
 use copy_dir::copy_dir;
 use std::{
     collections::HashMap,
-    fs::{create_dir_all, remove_dir_all},
+    fs::{create_dir_all, read_dir, remove_dir_all},
     path::{Path, PathBuf},
 };
 use uuid::Uuid;
@@ -36,7 +36,24 @@ impl SnapshotManager {
     }
 
     fn load_snapshots(&mut self) -> Result<()> {
-        todo!();
+        for entry in read_dir(&self.snapshots_dir)? {
+            let entry = entry?;
+            let rootfs = entry.path().join("rootfs");
+
+            if rootfs.is_dir()
+                && let Some(id) = entry.file_name().to_str()
+            {
+                self.snapshots.insert(
+                    id.to_string(),
+                    Snapshot {
+                        id: id.to_string(),
+                        parent: None,
+                        rootfs,
+                        active: false,
+                    },
+                );
+            }
+        }
         Ok(())
     }
 
@@ -48,10 +65,10 @@ impl SnapshotManager {
         let rootfs = snapshot_dir.join("rootfs");
         create_dir_all(&rootfs)?;
 
-        if let Some(parent_id) = parent {
-            if let Some(parent_snapshot) = self.snapshots.get(parent_id) {
-                Self::copy_directory(&parent_snapshot.rootfs, &rootfs)?;
-            }
+        if let Some(parent_id) = parent
+            && let Some(parent_snapshot) = self.snapshots.get(parent_id)
+        {
+            Self::copy_directory(&parent_snapshot.rootfs, &rootfs)?;
         }
 
         let snapshot = Snapshot {
@@ -66,10 +83,10 @@ impl SnapshotManager {
     }
 
     pub fn remove(&mut self, snapshot_id: &str) -> Result<()> {
-        if let Some(snapshot) = self.snapshots.remove(snapshot_id) {
-            if snapshot.rootfs.exists() {
-                remove_dir_all(snapshot.rootfs)?;
-            }
+        if let Some(snapshot) = self.snapshots.remove(snapshot_id)
+            && snapshot.rootfs.exists()
+        {
+            remove_dir_all(snapshot.rootfs)?;
         }
         Ok(())
     }
@@ -79,7 +96,7 @@ impl SnapshotManager {
     }
 
     fn copy_directory(src: &Path, dst: &Path) -> Result<()> {
-        copy_dir(src, dst);
+        copy_dir(src, dst)?;
         Ok(())
     }
 }
@@ -1,15 +1,16 @@
 use anyhow::Ok;
 // src/storage.rs
 use anyhow::{Result, anyhow};
+use nix::fcntl::{Flock, FlockArg};
 use serde::{Deserialize, Serialize};
-use std::fs::{self, File};
+use std::fs::{self, File, OpenOptions};
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{OnceLock, RwLock};
 use sysinfo::System;
 
-use crate::PROGRAM_NAME;
 use crate::runtime::container::Container;
+use crate::{PROGRAM_CMD, PROGRAM_NAME};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SystemData {
@@ -23,6 +24,12 @@ fn get_datafile() -> String {
     format!("/var/lib/{}/metadata.json", *PROGRAM_NAME)
 }
 
+/// Directory holding the runtime state of a single container, such as its
+/// control socket.
+pub fn get_container_dir(id: &str) -> PathBuf {
+    PathBuf::from(format!("/var/lib/{}/containers/{}", *PROGRAM_NAME, id))
+}
+
 static SYSTEM_DATA_LOCK: OnceLock<RwLock<SystemData>> = OnceLock::new();
 
 pub fn init() -> Result<()> {
@@ -60,72 +67,181 @@ fn read_from_disk() -> Result<SystemData> {
     Ok(data)
 }
 
+/// Replaces the metadata file in one step, so that a reader never sees it
+/// half written.
 fn write_to_disk(data: &SystemData) -> Result<()> {
     let json = serde_json::to_string_pretty(data)?;
-    fs::write(get_datafile().as_str(), json)?;
+    let datafile = get_datafile();
+    let tmpfile = format!("{}.tmp", datafile);
+    fs::write(&tmpfile, json)?;
+    fs::rename(&tmpfile, &datafile)?;
     Ok(())
 }
 
-pub fn add_container(container: Container) -> Result<()> {
-    let lock = SYSTEM_DATA_LOCK
+/// Container supervisors update the metadata as well as the CLI, so every
+/// access holds an advisory lock and starts from what is on disk.
+fn load(arg: FlockArg) -> Result<(Flock<File>, SystemData)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(format!("/var/lib/{}/metadata.lock", *PROGRAM_NAME))?;
+    let lock = Flock::lock(file, arg)
+        .map_err(|(_, e)| anyhow!("Failed to lock container metadata: {}", e))?;
+
+    let data = read_from_disk()?;
+    *SYSTEM_DATA_LOCK
         .get()
         .expect("System not initialized")
         .write()
-        .unwrap();
+        .unwrap() = data.clone();
 
-    let mut data = lock.clone();
+    Ok((lock, data))
+}
 
-    data.containers.push(container);
+fn store(data: SystemData) -> Result<()> {
     write_to_disk(&data)?;
-    drop(lock);
+    *SYSTEM_DATA_LOCK
+        .get()
+        .expect("System not initialized")
+        .write()
+        .unwrap() = data;
+    Ok(())
+}
 
-    SYSTEM_DATA_LOCK.get().unwrap().write().unwrap().containers = data.containers.clone();
+pub fn add_container(container: Container) -> Result<()> {
+    let (_lock, mut data) = load(FlockArg::LockExclusive)?;
+
+    if !container.name.is_empty() && data.containers.iter().any(|c| c.name == container.name) {
+        return Err(anyhow!(
+            "Container name {:?} is already in use",
+            container.name
+        ));
+    }
+    let container_id = container.id.clone();
+    data.containers.push(container);
+    store(data)?;
+
+    log::info!("Container {} added", container_id);
+    Ok(())
+}
 
-    println!("Container added successfully.");
+pub fn update_container(container: &Container) -> Result<()> {
+    modify_container(&container.id, |existing| *existing = container.clone())?;
     Ok(())
 }
 
+/// Applies `change` to the stored container while holding the metadata lock
+/// and returns the updated container.
+pub fn modify_container<F>(id: &str, change: F) -> Result<Container>
+where
+    F: FnOnce(&mut Container),
+{
+    let (_lock, mut data) = load(FlockArg::LockExclusive)?;
+
+    let container = data
+        .containers
+        .iter_mut()
+        .find(|c| c.id == id)
+        .ok_or_else(|| anyhow!("Container {} does not exists", id))?;
+    change(container);
+    let updated = container.clone();
+
+    store(data)?;
+    Ok(updated)
+}
+
+/// Looks a container up by its exact name, its full id, or a prefix of the
+/// id that matches a single container. The `nb-` part of ids may be left out
+/// of the prefix.
+pub fn find_container(reference: &str) -> Result<Container> {
+    // Containers created without a name have an empty one.
+    if reference.is_empty() {
+        return Err(anyhow!("No container given"));
+    }
+    let (_lock, data) = load(FlockArg::LockShared)?;
+
+    if let Some(container) = data
+        .containers
+        .iter()
+        .find(|c| c.name == reference || c.id == reference)
+    {
+        return Ok(container.clone());
+    }
+
+    let prefix = format!("{}-", *PROGRAM_CMD);
+    let mut matches = data.containers.into_iter().filter(|c| {
+        c.id.starts_with(reference)
+            || c.id
+                .strip_prefix(&prefix)
+                .is_some_and(|id| id.starts_with(reference))
+    });
+    match (matches.next(), matches.next()) {
+        (Some(container), None) => Ok(container),
+        (Some(first), Some(second)) => {
+            let mut ids = vec![first.id, second.id];
+            ids.extend(matches.map(|c| c.id));
+            Err(anyhow!(
+                "Container {:?} is ambiguous, it matches {}",
+                reference,
+                ids.join(", ")
+            ))
+        }
+        _ => Err(anyhow!("No such container: {}", reference)),
+    }
+}
+
+/// Gives a container a new name that no other container has.
+pub fn rename_container(id: &str, name: &str) -> Result<Container> {
+    let (_lock, mut data) = load(FlockArg::LockExclusive)?;
+
+    if data.containers.iter().any(|c| c.name == name && c.id != id) {
+        return Err(anyhow!("Container name {:?} is already in use", name));
+    }
+    let container = data
+        .containers
+        .iter_mut()
+        .find(|c| c.id == id)
+        .ok_or_else(|| anyhow!("Container {} does not exists", id))?;
+    container.name = name.to_string();
+    let renamed = container.clone();
+
+    store(data)?;
+    Ok(renamed)
+}
+
 pub fn get_container_ids() -> Result<Vec<String>> {
-    let lock = SYSTEM_DATA_LOCK
-        .get()
-        .expect("System not initialized")
-        .read()
-        .unwrap();
-    let ids = lock.containers.iter().map(|c| c.id.clone()).collect();
+    let (_lock, data) = load(FlockArg::LockShared)?;
+    let ids = data.containers.iter().map(|c| c.id.clone()).collect();
     Ok(ids)
 }
 
+pub fn get_containers() -> Result<Vec<Container>> {
+    let (_lock, data) = load(FlockArg::LockShared)?;
+    Ok(data.containers)
+}
+
 pub fn delete_container(id: &str) -> Result<()> {
-    let mut lock = SYSTEM_DATA_LOCK
-        .get()
-        .expect("System not initialized")
-        .write()
-        .unwrap();
+    let (_lock, mut data) = load(FlockArg::LockExclusive)?;
 
-    let initial_len = lock.containers.len();
-    lock.containers.retain(|c| c.id != id);
+    let initial_len = data.containers.len();
+    data.containers.retain(|c| c.id != id);
 
-    if lock.containers.len() < initial_len {
-        write_to_disk(&lock)?;
-        println!("Container {} deleted successfully.", id);
+    if data.containers.len() < initial_len {
+        store(data)?;
+        log::info!("Removed container {}", id);
     } else {
-        println!("Container with ID {} not found.", id);
+        log::warn!("Container {} was already removed", id);
     }
 
     Ok(())
 }
 
 pub fn get_container(id: &str) -> Result<Container> {
-    let mut lock = SYSTEM_DATA_LOCK
-        .get()
-        .expect("System not initialized")
-        .write()
-        .unwrap();
-    lock.containers.retain(|e| e.id == id);
+    let (_lock, data) = load(FlockArg::LockShared)?;
 
-    if lock.containers.len() > 0 {
-        log::info!("The number of containers are {}", lock.containers.len());
-        return Ok(lock.containers.get(0).unwrap().clone());
-    }
-    Err(anyhow!("Container does not exists"))
+    data.containers
+        .into_iter()
+        .find(|e| e.id == id)
+        .ok_or_else(|| anyhow!("Container does not exists"))
 }
@@ -45,7 +45,7 @@ impl VolumeManager {
             volumes_dir,
             volumes: HashMap::new(),
         };
-        manager.load_volumes();
+        manager.load_volumes()?;
         Ok(manager)
     }
 
@@ -111,7 +111,7 @@ impl VolumeManager {
 
         let volume_dir = self.volumes_dir.join(&volume_id);
         if volume_dir.exists() {
-            remove_dir_all(volume_dir);
+            remove_dir_all(volume_dir)?;
         }
 
         self.volumes.remove(&volume_id);
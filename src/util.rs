@@ -0,0 +1,22 @@
+// src/util.rs
+use anyhow::{Result, anyhow};
+
+/// Parses sizes such as `512k`, `10m` or `1g`, plain numbers are bytes.
+pub fn parse_size(value: &str) -> Result<u64> {
+    let lower = value.trim().to_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        "g" | "gb" => 1024 * 1024 * 1024,
+        _ => return Err(anyhow!("Invalid size {:?}", value)),
+    };
+
+    match digits.parse::<u64>() {
+        Ok(size) if size > 0 => size
+            .checked_mul(multiplier)
+            .ok_or_else(|| anyhow!("Size {:?} is too large", value)),
+        _ => Err(anyhow!("Invalid size {:?}", value)),
+    }
+}